@@ -0,0 +1,93 @@
+/* Generated from capi/src/lib.rs by capi/tests/header.rs, do not edit. */
+#ifndef CHIP8_H
+#define CHIP8_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Bumped whenever a function changes in a way that breaks existing callers. */
+#define CHIP8_API_VERSION 1
+
+/* A machine, created by chip8_new. */
+typedef struct chip8 chip8_t;
+
+/* The version of the API the library implements. */
+uint32_t chip8_api_version(void);
+
+/* Creates a machine running the `len` bytes at `rom`. Free it with `chip8_free`. */
+chip8_t *chip8_new(const uint8_t *rom, size_t len);
+
+/* Frees a machine from `chip8_new`. Null is ignored. */
+void chip8_free(chip8_t *chip8);
+
+/*
+ * Runs one 60Hz frame of `instructions` instructions. Returns 1 if the
+ * screen changed, 0 if not and -1 if the program crashed the emulator.
+ */
+int32_t chip8_run_frame(chip8_t *chip8, uint32_t instructions);
+
+/* Presses or releases hex key `key`. */
+void chip8_set_key(chip8_t *chip8, uint8_t key, bool pressed);
+
+/* Sets the whole keypad, one bit per hex key. */
+void chip8_set_keys(chip8_t *chip8, uint16_t keys);
+
+/*
+ * Copies the 32 framebuffer rows to `rows`. The most significant bit of a
+ * row is its leftmost pixel.
+ */
+void chip8_framebuffer(const chip8_t *chip8, uint64_t *rows);
+
+/* Register VX. */
+uint8_t chip8_register(const chip8_t *chip8, uint8_t x);
+
+void chip8_set_register(chip8_t *chip8, uint8_t x, uint8_t value);
+
+/* The index register I. */
+uint16_t chip8_index(const chip8_t *chip8);
+
+void chip8_set_index(chip8_t *chip8, uint16_t i);
+
+uint16_t chip8_pc(const chip8_t *chip8);
+
+void chip8_set_pc(chip8_t *chip8, uint16_t pc);
+
+uint8_t chip8_delay_timer(const chip8_t *chip8);
+
+void chip8_set_delay_timer(chip8_t *chip8, uint8_t value);
+
+uint8_t chip8_sound_timer(const chip8_t *chip8);
+
+void chip8_set_sound_timer(chip8_t *chip8, uint8_t value);
+
+/* The byte at `addr`, or 0 outside of memory. */
+uint8_t chip8_read_memory(const chip8_t *chip8, uint16_t addr);
+
+/* Writes outside of memory are ignored. */
+void chip8_write_memory(chip8_t *chip8, uint16_t addr, uint8_t value);
+
+/* Seeds the random numbers of RND. */
+void chip8_seed(chip8_t *chip8, uint64_t seed);
+
+/*
+ * Writes a save state to `buf` if it fits in `len` bytes, and returns its
+ * size either way. Call with a null `buf` to get the size.
+ */
+size_t chip8_save_state(const chip8_t *chip8, uint8_t *buf, size_t len);
+
+/*
+ * Restores a save state from `chip8_save_state`. Returns 0 on success and
+ * -1 if the state can't be read, leaving the machine unchanged.
+ */
+int32_t chip8_load_state(chip8_t *chip8, const uint8_t *buf, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
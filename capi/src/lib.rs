@@ -0,0 +1,212 @@
+//! C API for embedding the emulator. `include/chip8.h` is generated from
+//! this file by `tests/header.rs`; keep to the types it knows.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+
+use chip8::emulator::{Chip8, HEIGHT};
+
+/// Bumped whenever a function changes in a way that breaks existing callers.
+pub const CHIP8_API_VERSION: u32 = 1;
+
+/// The version of the API the library implements.
+#[no_mangle]
+pub extern "C" fn chip8_api_version() -> u32 {
+    CHIP8_API_VERSION
+}
+
+/// Creates a machine running the `len` bytes at `rom`. Free it with `chip8_free`.
+///
+/// # Safety
+/// `rom` must point to `len` readable bytes, or be null with `len` 0.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_new(rom: *const u8, len: usize) -> *mut Chip8 {
+    let rom = if rom.is_null() { &[][..] } else { slice::from_raw_parts(rom, len) };
+    Box::into_raw(Box::new(Chip8::from_rom(rom)))
+}
+
+/// Frees a machine from `chip8_new`. Null is ignored.
+///
+/// # Safety
+/// `chip8` must come from `chip8_new` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_free(chip8: *mut Chip8) {
+    if !chip8.is_null() {
+        drop(Box::from_raw(chip8));
+    }
+}
+
+/// Runs one 60Hz frame of `instructions` instructions. Returns 1 if the
+/// screen changed, 0 if not and -1 if the program crashed the emulator.
+///
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_run_frame(chip8: *mut Chip8, instructions: u32) -> i32 {
+    let chip8 = &mut *chip8;
+    match panic::catch_unwind(AssertUnwindSafe(|| chip8.run_frame(instructions as usize))) {
+        Ok(_) if chip8.error().is_some() => -1,
+        Ok(redraw) => redraw as i32,
+        Err(_) => -1,
+    }
+}
+
+/// Presses or releases hex key `key`.
+///
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_key(chip8: *mut Chip8, key: u8, pressed: bool) {
+    (*chip8).set_key(key, pressed);
+}
+
+/// Sets the whole keypad, one bit per hex key.
+///
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_keys(chip8: *mut Chip8, keys: u16) {
+    (*chip8).set_keys(keys);
+}
+
+/// Copies the 32 framebuffer rows to `rows`. The most significant bit of a
+/// row is its leftmost pixel.
+///
+/// # Safety
+/// `chip8` must be a live machine and `rows` must have room for 32 values.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_framebuffer(chip8: *const Chip8, rows: *mut u64) {
+    let rows = slice::from_raw_parts_mut(rows, HEIGHT);
+    rows.copy_from_slice((*chip8).screen().rows());
+}
+
+/// Register VX.
+///
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_register(chip8: *const Chip8, x: u8) -> u8 {
+    (*chip8).registers()[(x & 0x0f) as usize]
+}
+
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_register(chip8: *mut Chip8, x: u8, value: u8) {
+    (*chip8).set_register(x, value);
+}
+
+/// The index register I.
+///
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_index(chip8: *const Chip8) -> u16 {
+    (*chip8).index()
+}
+
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_index(chip8: *mut Chip8, i: u16) {
+    (*chip8).set_index(i);
+}
+
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_pc(chip8: *const Chip8) -> u16 {
+    (*chip8).pc() as u16
+}
+
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_pc(chip8: *mut Chip8, pc: u16) {
+    (*chip8).set_pc(pc);
+}
+
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_delay_timer(chip8: *const Chip8) -> u8 {
+    (*chip8).delay_timer()
+}
+
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_delay_timer(chip8: *mut Chip8, value: u8) {
+    (*chip8).set_delay_timer(value);
+}
+
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_sound_timer(chip8: *const Chip8) -> u8 {
+    (*chip8).sound_timer()
+}
+
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_set_sound_timer(chip8: *mut Chip8, value: u8) {
+    (*chip8).set_sound_timer(value);
+}
+
+/// The byte at `addr`, or 0 outside of memory.
+///
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_read_memory(chip8: *const Chip8, addr: u16) -> u8 {
+    (*chip8).memory().get(addr as usize).copied().unwrap_or(0)
+}
+
+/// Writes outside of memory are ignored.
+///
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_write_memory(chip8: *mut Chip8, addr: u16, value: u8) {
+    (*chip8).write_memory(addr, value);
+}
+
+/// Seeds the random numbers of RND.
+///
+/// # Safety
+/// `chip8` must be a live machine from `chip8_new`.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_seed(chip8: *mut Chip8, seed: u64) {
+    (*chip8).seed_rng(seed);
+}
+
+/// Writes a save state to `buf` if it fits in `len` bytes, and returns its
+/// size either way. Call with a null `buf` to get the size.
+///
+/// # Safety
+/// `chip8` must be a live machine and `buf` must have room for `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_save_state(chip8: *const Chip8, buf: *mut u8, len: usize) -> usize {
+    let state = (*chip8).save_state();
+    if !buf.is_null() && state.len() <= len {
+        slice::from_raw_parts_mut(buf, state.len()).copy_from_slice(&state);
+    }
+    state.len()
+}
+
+/// Restores a save state from `chip8_save_state`. Returns 0 on success and
+/// -1 if the state can't be read, leaving the machine unchanged.
+///
+/// # Safety
+/// `chip8` must be a live machine and `buf` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chip8_load_state(chip8: *mut Chip8, buf: *const u8, len: usize) -> i32 {
+    if buf.is_null() {
+        return -1;
+    }
+    match (*chip8).load_state(slice::from_raw_parts(buf, len)) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    }
+}
@@ -0,0 +1,88 @@
+/* Exercises the C API. Run through `cargo test -p chip8-capi`. */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "chip8.h"
+
+static int failures = 0;
+
+#define CHECK(cond) do { \
+    if (!(cond)) { \
+        fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__, #cond); \
+        failures++; \
+    } \
+} while (0)
+
+int main(void) {
+    /*
+     * 200: ld v0, 5
+     * 202: ld f, v0      ; the font digit 5
+     * 204: drw v1, v1, 5 ; at 0, 0
+     * 206: skp v2        ; key 0
+     * 208: jp 0x206
+     * 20a: ld v3, 1
+     * 20c: jp 0x20c
+     */
+    const uint8_t rom[] = {
+        0x60, 0x05, 0xf0, 0x29, 0xd1, 0x15, 0xe2, 0x9e,
+        0x12, 0x06, 0x63, 0x01, 0x12, 0x0c,
+    };
+    uint64_t rows[32];
+    uint8_t *state;
+    size_t size;
+
+    CHECK(chip8_api_version() == CHIP8_API_VERSION);
+
+    chip8_t *chip8 = chip8_new(rom, sizeof rom);
+    CHECK(chip8 != NULL);
+    CHECK(chip8_run_frame(chip8, 10) == 1);
+    CHECK(chip8_register(chip8, 0) == 5);
+    CHECK(chip8_index(chip8) == 25);
+
+    chip8_framebuffer(chip8, rows);
+    CHECK(rows[0] >> 56 == 0xf0);
+    CHECK(rows[1] >> 56 == 0x80);
+    CHECK(rows[5] == 0);
+
+    /* Waiting for key 0 */
+    CHECK(chip8_run_frame(chip8, 10) == 0);
+    CHECK(chip8_register(chip8, 3) == 0);
+
+    size = chip8_save_state(chip8, NULL, 0);
+    state = malloc(size);
+    CHECK(chip8_save_state(chip8, state, size) == size);
+
+    chip8_set_key(chip8, 0, true);
+    chip8_run_frame(chip8, 10);
+    CHECK(chip8_register(chip8, 3) == 1);
+    CHECK(chip8_pc(chip8) == 0x20c);
+
+    CHECK(chip8_load_state(chip8, state, size) == 0);
+    CHECK(chip8_register(chip8, 3) == 0);
+    CHECK(chip8_load_state(chip8, state, size / 2) == -1);
+
+    chip8_set_register(chip8, 3, 0x42);
+    chip8_set_delay_timer(chip8, 2);
+    chip8_write_memory(chip8, 0x300, 0x99);
+    chip8_set_keys(chip8, 0);
+    chip8_run_frame(chip8, 10);
+    CHECK(chip8_register(chip8, 3) == 0x42);
+    CHECK(chip8_delay_timer(chip8) == 1);
+    CHECK(chip8_read_memory(chip8, 0x300) == 0x99);
+    CHECK(chip8_read_memory(chip8, 0xffff) == 0);
+
+    /* An unknown opcode is reported instead of aborting */
+    chip8_write_memory(chip8, 0x400, 0xff);
+    chip8_write_memory(chip8, 0x401, 0xff);
+    chip8_set_pc(chip8, 0x400);
+    CHECK(chip8_run_frame(chip8, 1) == -1);
+
+    free(state);
+    chip8_free(chip8);
+
+    if (failures == 0) {
+        printf("C API: all checks passed\n");
+    }
+    return failures == 0 ? EXIT_SUCCESS : EXIT_FAILURE;
+}
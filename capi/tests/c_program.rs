@@ -0,0 +1,46 @@
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const C_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/c");
+const INCLUDE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/include");
+
+// target/<profile>, two levels above this test binary in deps/
+fn target_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent().and_then(Path::parent).unwrap().to_owned()
+}
+
+// Builds tests/c/test_chip8.c against the shared library and runs it
+#[cfg(unix)]
+#[test]
+fn c_program() {
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_owned());
+    if Command::new(&compiler).arg("--version").output().is_err() {
+        eprintln!("No C compiler ({}), skipping", compiler);
+        return;
+    }
+    let dir = target_dir();
+    // `cargo test` only builds the rlib
+    let mut cargo = Command::new(env!("CARGO"));
+    cargo.args(["build", "-p", "chip8-capi", "--lib"]);
+    if dir.file_name().is_some_and(|name| name == "release") {
+        cargo.arg("--release");
+    }
+    assert!(cargo.status().unwrap().success(), "Could not build the library");
+
+    let program = dir.join("test_chip8");
+    let status = Command::new(&compiler)
+        .arg(Path::new(C_DIR).join("test_chip8.c"))
+        .arg("-I").arg(INCLUDE)
+        .arg("-L").arg(&dir)
+        .arg(format!("-Wl,-rpath,{}", dir.display()))
+        .arg("-lchip8_capi")
+        .arg("-o").arg(&program)
+        .status()
+        .unwrap();
+    assert!(status.success(), "Could not compile test_chip8.c");
+    let output = Command::new(&program).output().unwrap();
+    print!("{}", String::from_utf8_lossy(&output.stdout));
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
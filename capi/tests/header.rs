@@ -0,0 +1,111 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const HEADER: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/include/chip8.h");
+const SOURCE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs");
+
+fn c_type(rust: &str) -> String {
+    let c = match rust.trim() {
+        "*mut Chip8" => "chip8_t *",
+        "*const Chip8" => "const chip8_t *",
+        "*mut u8" => "uint8_t *",
+        "*const u8" => "const uint8_t *",
+        "*mut u64" => "uint64_t *",
+        "u8" => "uint8_t",
+        "u16" => "uint16_t",
+        "u32" => "uint32_t",
+        "u64" => "uint64_t",
+        "i32" => "int32_t",
+        "usize" => "size_t",
+        "bool" => "bool",
+        "" => "void",
+        other => panic!("No C type for {}", other),
+    };
+    c.to_owned()
+}
+
+// Renders `name: type` as a C parameter
+fn c_param(param: &str) -> String {
+    let (name, ty) = param.split_once(':').expect("Parameter without a type");
+    let ty = c_type(ty);
+    if ty.ends_with('*') {
+        format!("{}{}", ty, name.trim())
+    } else {
+        format!("{} {}", ty, name.trim())
+    }
+}
+
+fn generate(source: &str) -> String {
+    let mut out = String::new();
+    out.push_str("/* Generated from capi/src/lib.rs by capi/tests/header.rs, do not edit. */\n");
+    out.push_str("#ifndef CHIP8_H\n#define CHIP8_H\n\n");
+    out.push_str("#include <stdbool.h>\n#include <stddef.h>\n#include <stdint.h>\n\n");
+    out.push_str("#ifdef __cplusplus\nextern \"C\" {\n#endif\n\n");
+
+    let mut doc: Vec<&str> = Vec::new();
+    let mut in_safety = false;
+    for line in source.lines().map(str::trim) {
+        if let Some(text) = line.strip_prefix("///") {
+            let text = text.trim();
+            in_safety |= text == "# Safety";
+            if !in_safety {
+                doc.push(text);
+            }
+            continue;
+        }
+        if let Some(rest) = line.strip_prefix("pub const ") {
+            let (name, value) = rest.split_once(':').unwrap();
+            let value = value.split('=').nth(1).unwrap().trim().trim_end_matches(';');
+            write_doc(&mut out, &doc);
+            out.push_str(&format!("#define {} {}\n\n", name.trim(), value));
+            out.push_str("/* A machine, created by chip8_new. */\ntypedef struct chip8 chip8_t;\n\n");
+        } else if let Some(rest) = line.strip_prefix("pub extern \"C\" fn ")
+            .or_else(|| line.strip_prefix("pub unsafe extern \"C\" fn "))
+        {
+            let (name, rest) = rest.split_once('(').unwrap();
+            let (params, rest) = rest.split_once(')').unwrap();
+            let ret = rest.trim().trim_end_matches('{').trim().trim_start_matches("->");
+            let params: Vec<String> = params.split(',').filter(|p| !p.trim().is_empty()).map(c_param).collect();
+            let params = if params.is_empty() { "void".to_owned() } else { params.join(", ") };
+            write_doc(&mut out, &doc);
+            let ret = c_type(ret);
+            let space = if ret.ends_with('*') { "" } else { " " };
+            out.push_str(&format!("{}{}{}({});\n\n", ret, space, name, params));
+        }
+        if !line.starts_with('#') {
+            doc.clear();
+            in_safety = false;
+        }
+    }
+
+    out.push_str("#ifdef __cplusplus\n}\n#endif\n\n#endif\n");
+    out
+}
+
+fn write_doc(out: &mut String, doc: &[&str]) {
+    let lines: Vec<&str> = doc.iter().copied().filter(|line| !line.is_empty()).collect();
+    match lines.as_slice() {
+        [] => (),
+        [line] => out.push_str(&format!("/* {} */\n", line)),
+        lines => {
+            out.push_str("/*\n");
+            for line in lines {
+                out.push_str(&format!(" * {}\n", line));
+            }
+            out.push_str(" */\n");
+        }
+    }
+}
+
+// UPDATE_HEADER=1 writes the header instead of comparing
+#[test]
+fn header_is_up_to_date() {
+    let header = generate(&fs::read_to_string(SOURCE).unwrap());
+    if env::var_os("UPDATE_HEADER").is_some() {
+        fs::write(HEADER, &header).unwrap();
+    } else {
+        let current = fs::read_to_string(Path::new(HEADER)).unwrap_or_default();
+        assert!(current == header, "include/chip8.h is out of date, run UPDATE_HEADER=1 cargo test -p chip8-capi");
+    }
+}
@@ -0,0 +1,16 @@
+//! Loads arbitrary bytes as a save state and runs whatever they restore.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use chip8::emulator::Chip8;
+
+fuzz_target!(|data: &[u8]| {
+    let mut chip8 = Chip8::from_rom(&[]);
+    if chip8.load_state(data).is_ok() {
+        for _ in 0..16 {
+            chip8.run_frame(32);
+        }
+    }
+});
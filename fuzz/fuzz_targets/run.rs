@@ -0,0 +1,64 @@
+//! Runs an arbitrary rom from an arbitrary machine state, with the keypad
+//! changing every frame. The core must stop with an error, never panic.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use chip8::emulator::{Chip8, CpuError};
+use chip8::quirks::{LoadStore, Quirks};
+
+// Keeps each run short enough for the fuzzer to get through many
+const MAX_FRAMES: usize = 64;
+const MAX_INSTRUCTIONS: usize = 64;
+
+#[derive(Debug, Arbitrary)]
+struct Input {
+    rom: Vec<u8>,
+    registers: [u8; 16],
+    index: u16,
+    pc: u16,
+    delay_timer: u8,
+    sound_timer: u8,
+    seed: u64,
+    quirks: [bool; 5],
+    load_store: u8,
+    stack_depth: u8,
+    instructions: u8,
+    // The keypad during each frame
+    keys: Vec<u16>,
+}
+
+fuzz_target!(|input: Input| {
+    let mut chip8 = Chip8::from_rom(&input.rom);
+    for (x, &value) in input.registers.iter().enumerate() {
+        chip8.set_register(x as u8, value);
+    }
+    chip8.set_index(input.index);
+    chip8.set_pc(input.pc);
+    chip8.set_delay_timer(input.delay_timer);
+    chip8.set_sound_timer(input.sound_timer);
+    chip8.seed_rng(input.seed);
+    let [shift, wrap, jump, display_wait, logic] = input.quirks;
+    let load_store = match input.load_store % 3 {
+        0 => LoadStore::LeaveI,
+        1 => LoadStore::Increment,
+        _ => LoadStore::IncrementByX,
+    };
+    let stack_depth = input.stack_depth;
+    chip8.set_quirks(Quirks { shift, load_store, wrap, jump, display_wait, logic, stack_depth });
+
+    let instructions = input.instructions as usize % MAX_INSTRUCTIONS + 1;
+    for &keys in input.keys.iter().take(MAX_FRAMES) {
+        chip8.set_keys(keys);
+        chip8.run_frame(instructions);
+        if let Some(error) = chip8.error() {
+            // Every address in memory is usable
+            if let CpuError::Memory { addr, .. } = error {
+                assert!(*addr >= chip8.memory().len(), "{}", error);
+            }
+            break;
+        }
+    }
+});
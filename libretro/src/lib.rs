@@ -0,0 +1,391 @@
+//! A libretro core built on the `Chip8` core. Roms known to the rom database
+//! get their quirks, speed, colours and joypad mapping from it unless the
+//! core options say otherwise.
+
+mod retro;
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+
+use chip8::database::{Database, Entry};
+use chip8::display::Palette;
+use chip8::emulator::{Chip8, HEIGHT, INSTRUCTIONS_PER_FRAME, WIDTH};
+use chip8::keymap::Keymap;
+use chip8::quirks::{QuirkPreset, Quirks};
+
+use retro::*;
+
+const FPS: f64 = 60.0;
+const SAMPLE_RATE: f64 = 44100.0;
+const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / FPS) as usize;
+const TONE: f64 = 440.0;
+const VOLUME: i16 = 0x1000;
+
+const QUIRKS_KEY: &[u8] = b"chip8_quirks\0";
+const SPEED_KEY: &[u8] = b"chip8_speed\0";
+
+// Hex key for each joypad button, like WASD on the keyboard: 5 up, 8 down,
+// 7 left, 9 right, 6 for A and 4 for B
+const JOYPAD: [(&str, c_uint, u8); 6] = [
+    ("up", RETRO_DEVICE_ID_JOYPAD_UP, 0x5),
+    ("down", RETRO_DEVICE_ID_JOYPAD_DOWN, 0x8),
+    ("left", RETRO_DEVICE_ID_JOYPAD_LEFT, 0x7),
+    ("right", RETRO_DEVICE_ID_JOYPAD_RIGHT, 0x9),
+    ("a", RETRO_DEVICE_ID_JOYPAD_A, 0x6),
+    ("b", RETRO_DEVICE_ID_JOYPAD_B, 0x4),
+];
+
+struct Callbacks {
+    environment: Option<retro_environment_t>,
+    video: Option<retro_video_refresh_t>,
+    audio_batch: Option<retro_audio_sample_batch_t>,
+    input_poll: Option<retro_input_poll_t>,
+    input_state: Option<retro_input_state_t>,
+}
+
+struct Core {
+    chip8: Chip8,
+    rom: Vec<u8>,
+    entry: Option<Entry>,
+    instructions: usize,
+    palette: Palette,
+    joypad: [(c_uint, u8); 6],
+    keymap: Keymap,
+    frame: [u32; WIDTH * HEIGHT],
+    audio: Vec<i16>,
+    // Position in the square wave, in samples
+    phase: u64,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video: None,
+    audio_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    let environment = CALLBACKS.lock().unwrap().environment;
+    match environment {
+        Some(environment) => unsafe { environment(cmd, data) },
+        None => false,
+    }
+}
+
+// The value of a core option, if the frontend has one
+fn variable(key: &'static [u8]) -> Option<String> {
+    let mut variable = retro_variable { key: key.as_ptr() as *const c_char, value: ptr::null() };
+    if !environment(RETRO_ENVIRONMENT_GET_VARIABLE, &mut variable as *mut _ as *mut c_void) || variable.value.is_null() {
+        return None;
+    }
+    unsafe { CStr::from_ptr(variable.value) }.to_str().ok().map(str::to_owned)
+}
+
+impl Core {
+    fn new(rom: &[u8]) -> Self {
+        let entry = Database::bundled().lookup(rom);
+        let mut joypad = [(0, 0); 6];
+        for (button, &(action, id, hex)) in joypad.iter_mut().zip(JOYPAD.iter()) {
+            let hex = entry.as_ref()
+                .and_then(|entry| entry.keys.iter().find(|(other, _)| other == action))
+                .map_or(hex, |&(_, hex)| hex);
+            *button = (id, hex);
+        }
+        let mut core = Core {
+            chip8: Chip8::from_rom(rom),
+            rom: rom.to_vec(),
+            palette: entry.as_ref().and_then(|entry| entry.palette).unwrap_or_default(),
+            entry,
+            instructions: INSTRUCTIONS_PER_FRAME,
+            joypad,
+            keymap: Keymap::default(),
+            frame: [0; WIDTH * HEIGHT],
+            audio: vec![0; SAMPLES_PER_FRAME * 2],
+            phase: 0,
+        };
+        core.chip8.seed_rng(0);
+        core.apply_options();
+        core
+    }
+
+    // Reads the core options; "auto" takes the value from the rom database
+    fn apply_options(&mut self) {
+        let quirks = match variable(QUIRKS_KEY).and_then(|value| value.parse::<QuirkPreset>().ok()) {
+            Some(preset) => preset.quirks(),
+            None => self.entry.as_ref().map_or(Quirks::default(), |entry| entry.quirks),
+        };
+        self.chip8.set_quirks(quirks);
+        self.instructions = variable(SPEED_KEY)
+            .and_then(|value| value.parse().ok())
+            .or_else(|| self.entry.as_ref().and_then(|entry| entry.tickrate))
+            .unwrap_or(INSTRUCTIONS_PER_FRAME);
+    }
+
+    fn reset(&mut self) {
+        let quirks = self.chip8.quirks();
+        self.chip8 = Chip8::from_rom(&self.rom);
+        self.chip8.set_quirks(quirks);
+        self.chip8.seed_rng(0);
+        self.phase = 0;
+    }
+
+    fn poll_input(&mut self, input_state: retro_input_state_t) {
+        let mut keys = 0u16;
+        for &(id, hex) in &self.joypad {
+            if unsafe { input_state(0, RETRO_DEVICE_JOYPAD, 0, id) } != 0 {
+                keys |= 1 << hex;
+            }
+        }
+        // RETROK_ codes of digits and letters are their ASCII codes
+        for c in ('0'..='9').chain('a'..='z') {
+            if let Some(hex) = self.keymap.hex_key_for_char(c) {
+                if unsafe { input_state(0, RETRO_DEVICE_KEYBOARD, 0, c as c_uint) } != 0 {
+                    keys |= 1 << hex;
+                }
+            }
+        }
+        self.chip8.set_keys(keys);
+    }
+
+    // A square wave while the sound timer runs
+    fn fill_audio(&mut self) {
+        let period = (SAMPLE_RATE / TONE) as u64;
+        let active = self.chip8.sound_active();
+        for frame in self.audio.chunks_mut(2) {
+            let sample = match active {
+                true if self.phase % period < period / 2 => VOLUME,
+                true => -VOLUME,
+                false => 0,
+            };
+            frame[0] = sample;
+            frame[1] = sample;
+            self.phase = if active { self.phase + 1 } else { 0 };
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: retro_environment_t) {
+    CALLBACKS.lock().unwrap().environment = Some(callback);
+    let variables = [
+        retro_variable {
+            key: QUIRKS_KEY.as_ptr() as *const c_char,
+            value: b"Quirks; auto|cowgod|vip|schip\0".as_ptr() as *const c_char,
+        },
+        retro_variable {
+            key: SPEED_KEY.as_ptr() as *const c_char,
+            value: b"Instructions per frame; auto|5|7|10|15|20|30|50|100|200|500|1000\0".as_ptr() as *const c_char,
+        },
+        retro_variable { key: ptr::null(), value: ptr::null() },
+    ];
+    environment(RETRO_ENVIRONMENT_SET_VARIABLES, variables.as_ptr() as *mut c_void);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: retro_video_refresh_t) {
+    CALLBACKS.lock().unwrap().video = Some(callback);
+}
+
+// Sound goes through the batch callback
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: retro_audio_sample_t) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: retro_audio_sample_batch_t) {
+    CALLBACKS.lock().unwrap().audio_batch = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: retro_input_poll_t) {
+    CALLBACKS.lock().unwrap().input_poll = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: retro_input_state_t) {
+    CALLBACKS.lock().unwrap().input_state = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *CORE.lock().unwrap() = None;
+}
+
+/// # Safety
+/// `info` must point to a `retro_system_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut retro_system_info) {
+    *info = retro_system_info {
+        library_name: b"CHIP-8\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"ch8|c8|chip8\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+/// `info` must point to a `retro_system_av_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut retro_system_av_info) {
+    *info = retro_system_av_info {
+        geometry: retro_game_geometry {
+            base_width: WIDTH as c_uint,
+            base_height: HEIGHT as c_uint,
+            max_width: WIDTH as c_uint,
+            max_height: HEIGHT as c_uint,
+            aspect_ratio: (WIDTH / HEIGHT) as f32,
+        },
+        timing: retro_system_timing { fps: FPS, sample_rate: SAMPLE_RATE },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(core) = CORE.lock().unwrap().as_mut() {
+        core.reset();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let (video, audio_batch, input_poll, input_state) = {
+        let callbacks = CALLBACKS.lock().unwrap();
+        (callbacks.video, callbacks.audio_batch, callbacks.input_poll, callbacks.input_state)
+    };
+    let mut updated = false;
+    environment(RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE, &mut updated as *mut bool as *mut c_void);
+
+    let mut core = CORE.lock().unwrap();
+    let core = match core.as_mut() {
+        Some(core) => core,
+        None => return,
+    };
+    if updated {
+        core.apply_options();
+    }
+    if let Some(input_poll) = input_poll {
+        unsafe { input_poll() };
+    }
+    if let Some(input_state) = input_state {
+        core.poll_input(input_state);
+    }
+    core.chip8.run_frame(core.instructions);
+
+    core.chip8.screen().to_buffer(&mut core.frame, &core.palette);
+    if let Some(video) = video {
+        let pitch = WIDTH * std::mem::size_of::<u32>();
+        unsafe { video(core.frame.as_ptr() as *const c_void, WIDTH as c_uint, HEIGHT as c_uint, pitch) };
+    }
+    core.fill_audio();
+    if let Some(audio_batch) = audio_batch {
+        unsafe { audio_batch(core.audio.as_ptr(), SAMPLES_PER_FRAME) };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    match CORE.lock().unwrap().as_ref() {
+        Some(core) => core.chip8.save_state().len(),
+        None => Chip8::from_rom(&[]).save_state().len(),
+    }
+}
+
+/// # Safety
+/// `data` must have room for `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let core = CORE.lock().unwrap();
+    let state = match core.as_ref() {
+        Some(core) => core.chip8.save_state(),
+        None => return false,
+    };
+    if data.is_null() || size < state.len() {
+        return false;
+    }
+    slice::from_raw_parts_mut(data as *mut u8, state.len()).copy_from_slice(&state);
+    true
+}
+
+/// # Safety
+/// `data` must point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let mut core = CORE.lock().unwrap();
+    match core.as_mut() {
+        Some(core) if !data.is_null() => {
+            core.chip8.load_state(slice::from_raw_parts(data as *const u8, size)).is_ok()
+        }
+        _ => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+/// # Safety
+/// `game` must be null or point to a `retro_game_info` whose data is readable.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const retro_game_info) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+    let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+    if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+        return false;
+    }
+    let rom = slice::from_raw_parts((*game).data as *const u8, (*game).size);
+    let core = Core::new(rom);
+    *CORE.lock().unwrap() = Some(core);
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_type: c_uint, _info: *const retro_game_info, _num: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *CORE.lock().unwrap() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match (id, CORE.lock().unwrap().as_mut()) {
+        (RETRO_MEMORY_SYSTEM_RAM, Some(core)) => core.chip8.memory_mut().as_mut_ptr() as *mut c_void,
+        _ => ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match (id, CORE.lock().unwrap().as_ref()) {
+        (RETRO_MEMORY_SYSTEM_RAM, Some(core)) => core.chip8.memory().len(),
+        _ => 0,
+    }
+}
@@ -0,0 +1,211 @@
+//! A minimal libretro frontend: loads the core as a shared library, feeds it
+//! a rom and runs frames. The core is one global instance per process, so
+//! everything runs in a single test.
+
+use std::env;
+use std::ffi::CStr;
+use std::fs;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::ptr;
+use std::sync::Mutex;
+
+use libloading::{Library, Symbol};
+
+const ROOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/..");
+
+#[repr(C)]
+struct GameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[repr(C)]
+struct Variable {
+    key: *const c_char,
+    value: *const c_char,
+}
+
+struct Frontend {
+    video: Vec<u32>,
+    samples: usize,
+    loud: bool,
+    buttons: u16,
+    speed: &'static [u8],
+    options: Vec<String>,
+}
+
+static FRONTEND: Mutex<Frontend> = Mutex::new(Frontend {
+    video: Vec::new(),
+    samples: 0,
+    loud: false,
+    buttons: 0,
+    speed: b"auto\0",
+    options: Vec::new(),
+});
+
+unsafe extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    let mut frontend = FRONTEND.lock().unwrap();
+    match cmd {
+        // SET_PIXEL_FORMAT
+        10 => *(data as *const c_uint) == 1,
+        // GET_VARIABLE
+        15 => {
+            let variable = &mut *(data as *mut Variable);
+            if CStr::from_ptr(variable.key).to_bytes() == b"chip8_speed" {
+                variable.value = frontend.speed.as_ptr() as *const c_char;
+                true
+            } else {
+                false
+            }
+        }
+        // SET_VARIABLES
+        16 => {
+            let mut variable = data as *const Variable;
+            while !(*variable).key.is_null() {
+                frontend.options.push(CStr::from_ptr((*variable).key).to_string_lossy().into_owned());
+                variable = variable.add(1);
+            }
+            true
+        }
+        // GET_VARIABLE_UPDATE
+        17 => {
+            *(data as *mut bool) = true;
+            true
+        }
+        _ => false,
+    }
+}
+
+unsafe extern "C" fn video(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+    assert_eq!((width, height, pitch), (64, 32, 256));
+    let pixels = std::slice::from_raw_parts(data as *const u32, 64 * 32);
+    FRONTEND.lock().unwrap().video = pixels.to_vec();
+}
+
+unsafe extern "C" fn audio_sample(_left: i16, _right: i16) {}
+
+unsafe extern "C" fn audio_batch(data: *const i16, frames: usize) -> usize {
+    let samples = std::slice::from_raw_parts(data, frames * 2);
+    let mut frontend = FRONTEND.lock().unwrap();
+    frontend.samples += frames;
+    frontend.loud |= samples.iter().any(|&sample| sample != 0);
+    frames
+}
+
+unsafe extern "C" fn input_poll() {}
+
+unsafe extern "C" fn input_state(port: c_uint, device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+    let buttons = FRONTEND.lock().unwrap().buttons;
+    (port == 0 && device == 1 && id < 16 && buttons & (1 << id) != 0) as i16
+}
+
+// target/<profile>, two levels above this test binary in deps/
+fn target_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent().and_then(Path::parent).unwrap().to_owned()
+}
+
+fn load_core() -> Library {
+    let dir = target_dir();
+    // `cargo test` only builds the rlib
+    let mut cargo = Command::new(env!("CARGO"));
+    cargo.args(["build", "-p", "chip8-libretro", "--lib"]);
+    if dir.file_name().is_some_and(|name| name == "release") {
+        cargo.arg("--release");
+    }
+    assert!(cargo.status().unwrap().success(), "Could not build the core");
+    let path = dir.join(libloading::library_filename("chip8_libretro"));
+    unsafe { Library::new(path) }.unwrap()
+}
+
+fn video_frame() -> Vec<u32> {
+    FRONTEND.lock().unwrap().video.clone()
+}
+
+#[test]
+fn run_core() {
+    let core = load_core();
+    unsafe {
+        let api_version: Symbol<unsafe extern "C" fn() -> c_uint> = core.get(b"retro_api_version").unwrap();
+        assert_eq!(api_version(), 1);
+
+        let set_environment: Symbol<unsafe extern "C" fn(unsafe extern "C" fn(c_uint, *mut c_void) -> bool)> =
+            core.get(b"retro_set_environment").unwrap();
+        set_environment(environment);
+        assert_eq!(FRONTEND.lock().unwrap().options, ["chip8_quirks", "chip8_speed"]);
+        let set_video: Symbol<unsafe extern "C" fn(unsafe extern "C" fn(*const c_void, c_uint, c_uint, usize))> =
+            core.get(b"retro_set_video_refresh").unwrap();
+        set_video(video);
+        let set_audio: Symbol<unsafe extern "C" fn(unsafe extern "C" fn(i16, i16))> =
+            core.get(b"retro_set_audio_sample").unwrap();
+        set_audio(audio_sample);
+        let set_audio_batch: Symbol<unsafe extern "C" fn(unsafe extern "C" fn(*const i16, usize) -> usize)> =
+            core.get(b"retro_set_audio_sample_batch").unwrap();
+        set_audio_batch(audio_batch);
+        let set_input_poll: Symbol<unsafe extern "C" fn(unsafe extern "C" fn())> =
+            core.get(b"retro_set_input_poll").unwrap();
+        set_input_poll(input_poll);
+        let set_input_state: Symbol<unsafe extern "C" fn(unsafe extern "C" fn(c_uint, c_uint, c_uint, c_uint) -> i16)> =
+            core.get(b"retro_set_input_state").unwrap();
+        set_input_state(input_state);
+
+        let init: Symbol<unsafe extern "C" fn()> = core.get(b"retro_init").unwrap();
+        let load_game: Symbol<unsafe extern "C" fn(*const GameInfo) -> bool> = core.get(b"retro_load_game").unwrap();
+        let run: Symbol<unsafe extern "C" fn()> = core.get(b"retro_run").unwrap();
+        let serialize_size: Symbol<unsafe extern "C" fn() -> usize> = core.get(b"retro_serialize_size").unwrap();
+        let serialize: Symbol<unsafe extern "C" fn(*mut c_void, usize) -> bool> = core.get(b"retro_serialize").unwrap();
+        let unserialize: Symbol<unsafe extern "C" fn(*const c_void, usize) -> bool> =
+            core.get(b"retro_unserialize").unwrap();
+        let unload_game: Symbol<unsafe extern "C" fn()> = core.get(b"retro_unload_game").unwrap();
+        let deinit: Symbol<unsafe extern "C" fn()> = core.get(b"retro_deinit").unwrap();
+
+        init();
+        assert!(!load_game(ptr::null()));
+
+        // The draw self-test matches its golden screen
+        let rom = fs::read(Path::new(ROOT).join("tests/roms/draw.ch8")).unwrap();
+        let game = GameInfo { path: ptr::null(), data: rom.as_ptr() as *const c_void, size: rom.len(), meta: ptr::null() };
+        assert!(load_game(&game));
+        for _ in 0..20 {
+            run();
+        }
+        let golden = fs::read_to_string(Path::new(ROOT).join("tests/roms/draw.golden")).unwrap();
+        let screen: String = video_frame()
+            .chunks(64)
+            .map(|row| row.iter().map(|&pixel| if pixel == 0 { '.' } else { '#' }).collect::<String>() + "\n")
+            .collect();
+        assert!(golden.ends_with(&screen), "Core screen:\n{}", screen);
+        assert_eq!(FRONTEND.lock().unwrap().samples, 20 * 735);
+        unload_game();
+
+        // The doodle rom stamps random dots while 5 (joypad up) is held,
+        // and serialize/unserialize bring it back to the same frames
+        let rom = fs::read(Path::new(ROOT).join("tests/movies/doodle.ch8")).unwrap();
+        let game = GameInfo { path: ptr::null(), data: rom.as_ptr() as *const c_void, size: rom.len(), meta: ptr::null() };
+        assert!(load_game(&game));
+        FRONTEND.lock().unwrap().speed = b"20\0";
+        run();
+        let first = video_frame();
+        FRONTEND.lock().unwrap().buttons = 1 << 4;
+        let mut state = vec![0u8; serialize_size()];
+        assert!(serialize(state.as_mut_ptr() as *mut c_void, state.len()));
+        for _ in 0..30 {
+            run();
+        }
+        let stamped = video_frame();
+        assert_ne!(stamped, first);
+        assert!(unserialize(state.as_ptr() as *const c_void, state.len()));
+        for _ in 0..30 {
+            run();
+        }
+        assert_eq!(video_frame(), stamped);
+        assert!(!unserialize(state.as_ptr() as *const c_void, 10));
+
+        unload_game();
+        deinit();
+    }
+}
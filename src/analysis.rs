@@ -0,0 +1,330 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write as _};
+use std::ops::RangeInclusive;
+
+use crate::disasm;
+use crate::emulator::MEMORY_SIZE;
+
+/// Where roms are loaded and start running.
+pub const START: u16 = 0x200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EdgeKind {
+    /// On to the next instruction.
+    Next,
+    Jump,
+    Call,
+    /// Past the next instruction, when a skip is taken.
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Edge {
+    pub to: u16,
+    pub kind: EdgeKind,
+}
+
+/// Instructions that run one after the other, only entered at the top.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    /// Address and opcode of each instruction.
+    pub instructions: Vec<(u16, u16)>,
+    pub edges: Vec<Edge>,
+}
+
+impl Block {
+    pub fn start(&self) -> u16 {
+        self.instructions[0].0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Region {
+    Code(RangeInclusive<u16>),
+    Data(RangeInclusive<u16>),
+}
+
+/// What static analysis finds in a rom by following the control flow from
+/// 0x200: which bytes are code and which data, the subroutines, and the
+/// basic blocks with the edges between them.
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    rom_len: usize,
+    // Whether each byte of the rom is part of a reachable instruction
+    code: Vec<bool>,
+    instructions: BTreeMap<u16, u16>,
+    subroutines: BTreeSet<u16>,
+    unresolved: BTreeSet<u16>,
+    outside: BTreeSet<u16>,
+    blocks: Vec<Block>,
+}
+
+// Where control goes after the instruction `op` at `addr`
+fn successors(addr: u16, op: u16) -> Vec<Edge> {
+    let next = addr.wrapping_add(2);
+    let target = op & 0x0fff;
+    let edge = |to, kind| Edge { to, kind };
+    match disasm::kind(op) {
+        "RET" => vec![],
+        // The emulator runs SYS as a jump
+        "SYS" | "JP" if op & 0xf000 != 0xb000 => vec![edge(target, EdgeKind::Jump)],
+        // JP V0 depends on a register
+        "JP" => vec![],
+        "CALL" => vec![edge(target, EdgeKind::Call), edge(next, EdgeKind::Next)],
+        "SE" | "SNE" | "SKP" | "SKNP" => vec![edge(next, EdgeKind::Next), edge(next.wrapping_add(2), EdgeKind::Skip)],
+        // Not an instruction; whatever ran into it is probably wrong
+        "DW" => vec![],
+        _ => vec![edge(next, EdgeKind::Next)],
+    }
+}
+
+impl Analysis {
+    pub fn analyze(rom: &[u8]) -> Self {
+        // The loader drops the part of a rom past the end of memory
+        let rom = &rom[..rom.len().min(MEMORY_SIZE - START as usize)];
+        let end = START as usize + rom.len();
+        let op_at = |addr: u16| {
+            let byte = |addr: usize| rom.get(addr.wrapping_sub(START as usize)).copied().unwrap_or(0) as u16;
+            (byte(addr as usize) << 8) | byte(addr as usize + 1)
+        };
+        let in_rom = |addr: u16| (START as usize..end).contains(&(addr as usize));
+
+        let mut analysis = Analysis {
+            rom_len: rom.len(),
+            code: vec![false; rom.len()],
+            instructions: BTreeMap::new(),
+            subroutines: BTreeSet::new(),
+            unresolved: BTreeSet::new(),
+            outside: BTreeSet::new(),
+            blocks: Vec::new(),
+        };
+        let mut leaders = BTreeSet::new();
+        let mut work = vec![START];
+        leaders.insert(START);
+        while let Some(addr) = work.pop() {
+            if analysis.instructions.contains_key(&addr) {
+                continue;
+            }
+            if !in_rom(addr) {
+                analysis.outside.insert(addr);
+                continue;
+            }
+            let op = op_at(addr);
+            analysis.instructions.insert(addr, op);
+            for byte in [addr, addr + 1] {
+                if let Some(code) = analysis.code.get_mut((byte - START) as usize) {
+                    *code = true;
+                }
+            }
+            let edges = successors(addr, op);
+            if op & 0xf000 == 0xb000 {
+                analysis.unresolved.insert(addr);
+            }
+            for edge in &edges {
+                if edge.kind == EdgeKind::Call {
+                    analysis.subroutines.insert(edge.to);
+                }
+                // Anything but running on starts a new block
+                if edge.kind != EdgeKind::Next || edges.len() > 1 {
+                    leaders.insert(edge.to);
+                }
+                work.push(edge.to);
+            }
+        }
+        analysis.blocks = analysis.build_blocks(&leaders);
+        analysis
+    }
+
+    fn build_blocks(&self, leaders: &BTreeSet<u16>) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        for &leader in leaders {
+            let mut addr = leader;
+            let mut instructions = Vec::new();
+            let edges = loop {
+                let op = match self.instructions.get(&addr) {
+                    Some(&op) => op,
+                    None => break vec![],
+                };
+                instructions.push((addr, op));
+                let edges = successors(addr, op);
+                match edges.as_slice() {
+                    [Edge { to, kind: EdgeKind::Next }] if !leaders.contains(to) => addr = *to,
+                    _ => break edges,
+                }
+            };
+            if !instructions.is_empty() {
+                blocks.push(Block { instructions, edges });
+            }
+        }
+        blocks
+    }
+
+    pub fn is_code(&self, addr: u16) -> bool {
+        addr.checked_sub(START).and_then(|offset| self.code.get(offset as usize)).copied().unwrap_or(false)
+    }
+
+    /// Reachable instructions by address.
+    pub fn instructions(&self) -> &BTreeMap<u16, u16> {
+        &self.instructions
+    }
+
+    /// Entry points of the subroutines called.
+    pub fn subroutines(&self) -> &BTreeSet<u16> {
+        &self.subroutines
+    }
+
+    /// `JP V0, addr` instructions, whose targets depend on V0 at run time.
+    pub fn unresolved(&self) -> &BTreeSet<u16> {
+        &self.unresolved
+    }
+
+    /// Jump and call targets outside the rom.
+    pub fn outside(&self) -> &BTreeSet<u16> {
+        &self.outside
+    }
+
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    /// The rom split into runs of code and data, in order.
+    pub fn regions(&self) -> Vec<Region> {
+        let mut regions: Vec<Region> = Vec::new();
+        for (offset, &code) in self.code.iter().enumerate() {
+            let addr = START + offset as u16;
+            match regions.last_mut() {
+                Some(Region::Code(range)) if code => *range = *range.start()..=addr,
+                Some(Region::Data(range)) if !code => *range = *range.start()..=addr,
+                _ if code => regions.push(Region::Code(addr..=addr)),
+                _ => regions.push(Region::Data(addr..=addr)),
+            }
+        }
+        regions
+    }
+
+    /// The control flow graph in Graphviz DOT, one node per block. Calls are
+    /// dashed, taken skips dotted, and unresolved jumps red.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph rom {\n    node [shape=box, fontname=monospace];\n");
+        for block in &self.blocks {
+            let label: String = block.instructions
+                .iter()
+                .map(|&(addr, op)| format!("{:03x}: {}\\l", addr, disasm::disassemble(op)))
+                .collect();
+            let (addr, _) = *block.instructions.last().unwrap();
+            let color = if self.unresolved.contains(&addr) { ", color=red" } else { "" };
+            writeln!(dot, "    b{:03x} [label=\"{}\"{}];", block.start(), label, color).unwrap();
+        }
+        for &addr in &self.outside {
+            writeln!(dot, "    b{:03x} [label=\"{:03x} (outside the rom)\", style=dashed];", addr, addr).unwrap();
+        }
+        for block in &self.blocks {
+            for edge in &block.edges {
+                let style = match edge.kind {
+                    EdgeKind::Next | EdgeKind::Jump => "",
+                    EdgeKind::Call => " [style=dashed]",
+                    EdgeKind::Skip => " [style=dotted]",
+                };
+                writeln!(dot, "    b{:03x} -> b{:03x}{};", block.start(), edge.to, style).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for region in self.regions() {
+            let (kind, range) = match &region {
+                Region::Code(range) => ("code", range),
+                Region::Data(range) => ("data", range),
+            };
+            let size = range.end() - range.start() + 1;
+            writeln!(f, "{} {:03x}-{:03x} ({} bytes)", kind, range.start(), range.end(), size)?;
+        }
+        let code = self.code.iter().filter(|&&code| code).count();
+        writeln!(f, "{} of {} bytes are code, in {} blocks", code, self.rom_len, self.blocks.len())?;
+        for addr in &self.subroutines {
+            writeln!(f, "subroutine {:03x}", addr)?;
+        }
+        for addr in &self.unresolved {
+            writeln!(f, "unresolved jump at {:03x}: {}", addr, disasm::disassemble(self.instructions[addr]))?;
+        }
+        for addr in &self.outside {
+            writeln!(f, "target {:03x} is outside the rom", addr)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 22] = [
+        0x22, 0x0c, // 200: CALL 0x20c
+        0x30, 0x01, // 202: SE V0, 0x01
+        0x12, 0x02, // 204: JP 0x202
+        0xb2, 0x10, // 206: JP V0, 0x210
+        0xff, 0xff, // 208: data
+        0xff, 0xff, // 20a: data
+        0xa2, 0x08, // 20c: LD I, 0x208
+        0xd0, 0x12, // 20e: DRW V0, V1, 2
+        0x00, 0xee, // 210: RET
+        0x13, 0x00, // 212: data
+        0x13, 0x00, // 214: data
+    ];
+
+    #[test]
+    fn code_and_data() {
+        let analysis = Analysis::analyze(&ROM);
+        assert_eq!(
+            analysis.regions(),
+            vec![
+                Region::Code(0x200..=0x207),
+                Region::Data(0x208..=0x20b),
+                Region::Code(0x20c..=0x211),
+                Region::Data(0x212..=0x215),
+            ]
+        );
+        assert!(analysis.is_code(0x20c));
+        assert!(!analysis.is_code(0x208));
+        assert!(!analysis.is_code(0x1fe));
+        assert_eq!(analysis.subroutines().iter().copied().collect::<Vec<_>>(), [0x20c]);
+        assert_eq!(analysis.unresolved().iter().copied().collect::<Vec<_>>(), [0x206]);
+    }
+
+    #[test]
+    fn blocks() {
+        let analysis = Analysis::analyze(&ROM);
+        let starts: Vec<u16> = analysis.blocks().iter().map(Block::start).collect();
+        assert_eq!(starts, [0x200, 0x202, 0x204, 0x206, 0x20c]);
+        let skip = &analysis.blocks()[1];
+        assert_eq!(skip.edges, [Edge { to: 0x204, kind: EdgeKind::Next }, Edge { to: 0x206, kind: EdgeKind::Skip }]);
+        assert_eq!(analysis.blocks()[4].instructions.len(), 3);
+
+        let dot = analysis.to_dot();
+        assert!(dot.starts_with("digraph rom {"));
+        assert!(dot.contains("b200 -> b20c [style=dashed];"));
+        assert!(dot.contains("b204 -> b202;"));
+        assert!(dot.contains("206: JP V0, 0x210\\l\", color=red"));
+    }
+
+    #[test]
+    fn outside_the_rom() {
+        // JP 0x300
+        let analysis = Analysis::analyze(&[0x13, 0x00]);
+        assert_eq!(analysis.outside().iter().copied().collect::<Vec<_>>(), [0x300]);
+        assert!(analysis.to_string().contains("target 300 is outside the rom"));
+    }
+
+    #[test]
+    fn only_what_fits_in_memory() {
+        // Runs on through all of memory; ADD V0, 1 everywhere
+        let rom = [0x70, 0x01].repeat(0x8000);
+        let analysis = Analysis::analyze(&rom);
+        assert_eq!(analysis.regions(), [Region::Code(0x200..=0xfff)]);
+        assert_eq!(analysis.outside().iter().copied().collect::<Vec<_>>(), [0x1000]);
+    }
+}
@@ -0,0 +1,153 @@
+use std::thread;
+
+use crate::display::Screen;
+use crate::emulator::Chip8;
+
+/// What one machine did during a `Batch::run`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub screen: Screen,
+    /// Whether the screen changed during the frames that ran.
+    pub redraw: bool,
+    pub state_hash: u64,
+}
+
+/// Independent machines stepped together on worker threads, e.g. for
+/// compatibility sweeps or training agents.
+#[derive(Clone)]
+pub struct Batch {
+    machines: Vec<Chip8>,
+    threads: usize,
+}
+
+impl Batch {
+    pub fn new(machines: Vec<Chip8>) -> Self {
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        Batch { machines, threads }
+    }
+
+    /// `count` copies of `chip8`, each with RND seeded from `seed` plus its index.
+    pub fn clones(chip8: &Chip8, count: usize, seed: u64) -> Self {
+        let machines = (0..count)
+            .map(|i| {
+                let mut chip8 = chip8.clone();
+                chip8.seed_rng(seed.wrapping_add(i as u64));
+                chip8
+            })
+            .collect();
+        Batch::new(machines)
+    }
+
+    // At most this many threads run at once
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    pub fn len(&self) -> usize {
+        self.machines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.machines.is_empty()
+    }
+
+    pub fn machines(&self) -> &[Chip8] {
+        &self.machines
+    }
+
+    pub fn machines_mut(&mut self) -> &mut [Chip8] {
+        &mut self.machines
+    }
+
+    pub fn into_machines(self) -> Vec<Chip8> {
+        self.machines
+    }
+
+    /// Sets the keypad of each machine; machines past the end of `keys` keep theirs.
+    pub fn set_keys(&mut self, keys: &[u16]) {
+        for (chip8, &keys) in self.machines.iter_mut().zip(keys) {
+            chip8.set_keys(keys);
+        }
+    }
+
+    /// Runs `frames` frames on every machine and returns where each one ended up.
+    pub fn run(&mut self, frames: u32, instructions: usize) -> Vec<Frame> {
+        self.map(|_, chip8| {
+            let mut redraw = false;
+            for _ in 0..frames {
+                redraw |= chip8.run_frame(instructions);
+            }
+            Frame { screen: *chip8.screen(), redraw, state_hash: chip8.state_hash() }
+        })
+    }
+
+    /// Calls `f` with the index of each machine and the machine, in parallel,
+    /// and returns the results in machine order.
+    pub fn map<F, R>(&mut self, f: F) -> Vec<R>
+    where
+        F: Fn(usize, &mut Chip8) -> R + Sync,
+        R: Send,
+    {
+        let chunk = self.machines.len().div_ceil(self.threads).max(1);
+        let f = &f;
+        thread::scope(|scope| {
+            let workers: Vec<_> = self.machines
+                .chunks_mut(chunk)
+                .enumerate()
+                .map(|(n, machines)| {
+                    scope.spawn(move || {
+                        machines
+                            .iter_mut()
+                            .enumerate()
+                            .map(|(i, chip8)| f(n * chunk + i, chip8))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Draws a dot at a random column on every pass
+    const ROM: [u8; 12] = [0xa2, 0x0a, 0x61, 0x00, 0xc0, 0x3f, 0xd0, 0x11, 0x12, 0x04, 0x80, 0x00];
+
+    fn assert_send<T: Send + Sync>() {}
+
+    #[test]
+    fn machine_is_send() {
+        assert_send::<Chip8>();
+        assert_send::<Batch>();
+    }
+
+    #[test]
+    fn matches_sequential_runs() {
+        let chip8 = Chip8::from_rom(&ROM);
+        let mut batch = Batch::clones(&chip8, 7, 100);
+        batch.set_threads(3);
+        batch.set_keys(&[0x1, 0x2]);
+        let frames = batch.run(10, 8);
+        assert_eq!(frames.len(), 7);
+        assert!(batch.machines()[1].is_key_down(1));
+
+        for (i, frame) in frames.iter().enumerate() {
+            let mut expected = chip8.clone();
+            expected.seed_rng(100 + i as u64);
+            if i < 2 {
+                expected.set_keys(1 << i);
+            }
+            for _ in 0..10 {
+                expected.run_frame(8);
+            }
+            assert!(frame.redraw);
+            assert_eq!(frame.screen, *expected.screen());
+            assert_eq!(frame.state_hash, expected.state_hash());
+        }
+        // Different seeds draw different dots
+        assert_ne!(frames[0].screen, frames[1].screen);
+    }
+}
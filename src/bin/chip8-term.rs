@@ -0,0 +1,296 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossterm::{cursor, execute, queue, terminal};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+    KeyboardEnhancementFlags, PushKeyboardEnhancementFlags, PopKeyboardEnhancementFlags};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+
+use clap::Parser;
+
+use chip8::cheats::{Compare, SearchCommand, SearchControls};
+use chip8::cli::{CoverageArgs, MovieArgs, Options, ProfileArgs, TraceArgs};
+use chip8::control::{Controls, Speed};
+use chip8::display::{Screen, OFF};
+use chip8::emulator::{WIDTH, HEIGHT};
+use chip8::keymap::Keymap;
+use chip8::movie::Session;
+use chip8::patch::PatchedRom;
+use chip8::phosphor::Phosphor;
+
+const FRAME: Duration = Duration::from_micros(16600);
+// Most terminals never report key releases, so a press is held this many frames
+// (long enough to bridge the gap until the terminal's key repeat kicks in).
+const HOLD_FRAMES: u8 = 30;
+
+/// Run a rom in the terminal
+#[derive(Debug, Parser)]
+#[command(name = "chip8-term", version)]
+struct Args {
+    /// The rom to run
+    rom: PathBuf,
+    #[command(flatten)]
+    options: Options,
+    #[command(flatten)]
+    movie: MovieArgs,
+    #[command(flatten)]
+    trace: TraceArgs,
+    #[command(flatten)]
+    profile: ProfileArgs,
+    #[command(flatten)]
+    coverage: CoverageArgs,
+}
+
+// Puts the terminal in raw mode and restores it when dropped, even on panic.
+struct Terminal {
+    enhanced: bool,
+}
+
+impl Terminal {
+    fn enter() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide,
+            terminal::Clear(terminal::ClearType::All))?;
+        let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced {
+            execute!(stdout, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+        Ok(Terminal { enhanced })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        if self.enhanced {
+            let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(stdout, ResetColor, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn main() -> io::Result<()> {
+    let args = Args::parse();
+    let PatchedRom { original, patched: rom } = args.options.read_rom(&args.rom).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let (settings, keymap) = args.options.load_rom(&args.rom, &original).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let (mut chip8, mut session) = args.movie.start(&rom, &settings).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    session.set_cheats(args.options.cheats(&args.rom).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    }));
+    let trace = args.trace.open().unwrap_or_else(|e| {
+        eprintln!("Could not create the trace log: {}", e);
+        process::exit(1);
+    });
+    let mut observers = (trace, (args.profile.profiler(), args.coverage.coverage()));
+    let palette = settings.palette();
+    let mut phosphor = settings.phosphor.map(|mode| {
+        let mut phosphor = Phosphor::new(mode);
+        phosphor.set_palette(palette);
+        phosphor
+    });
+
+    let term = Terminal::enter()?;
+    let mut stdout = io::stdout();
+    // Frames left before each key counts as released
+    let mut held = [0u8; 16];
+    let mut buffer = [OFF; WIDTH * HEIGHT];
+    let mut shown: Option<Screen> = None;
+    let mut controls = Controls::new();
+    controls.set_mute(settings.mute());
+    let mut search = SearchControls::new();
+    let cheats_path = args.options.cheats_path(&args.rom);
+    let mut beeping = false;
+    // How the last replay ended
+    let mut note = String::new();
+    let mut deadline = Instant::now();
+    'running: loop {
+        while event::poll(Duration::ZERO)? {
+            match event::read()? {
+                Event::Key(KeyEvent { code: KeyCode::Esc, .. }) => break 'running,
+                Event::Key(KeyEvent { code: KeyCode::Char('c'), modifiers, .. })
+                    if modifiers.contains(KeyModifiers::CONTROL) => break 'running,
+                Event::Key(KeyEvent { kind: KeyEventKind::Press, code, .. })
+                    if is_hotkey(&keymap, code) => match code {
+                        KeyCode::Char('p') => controls.toggle_pause(),
+                        KeyCode::Char('n') => controls.advance(),
+                        KeyCode::Tab => controls.toggle_speed(settings.fast_forward()),
+                        _ => controls.toggle_speed(settings.slow_motion()),
+                    },
+                Event::Key(KeyEvent { kind: KeyEventKind::Press, code: KeyCode::F(n), .. }) => {
+                    if let Some(command) = search_command(n) {
+                        note = search.run_and_save(command, &chip8, session.cheats_mut(), &cheats_path);
+                    }
+                }
+                Event::Key(KeyEvent { code, kind, .. }) => {
+                    if let Some(key) = to_valid_key(&keymap, code) {
+                        held[key as usize] = match kind {
+                            KeyEventKind::Release => 0,
+                            _ if term.enhanced => u8::MAX,
+                            _ => HOLD_FRAMES,
+                        };
+                    }
+                }
+                Event::Resize(..) => {
+                    queue!(stdout, terminal::Clear(terminal::ClearType::All))?;
+                    shown = None;
+                }
+                _ => (),
+            }
+        }
+        let keys = held.iter().enumerate()
+            .filter(|(_, &frames)| frames > 0)
+            .fold(0, |keys, (key, _)| keys | (1 << key));
+        if !term.enhanced {
+            for frames in held.iter_mut() {
+                *frames = frames.saturating_sub(1);
+            }
+        }
+
+        let frames = controls.frames();
+        match session.run_observed(&mut chip8, frames, keys, &mut observers) {
+            Some(Ok(())) => note = "replay finished in the recorded state".to_owned(),
+            Some(Err(e)) => note = e.to_string(),
+            None => (),
+        }
+        if let Some(e) = chip8.error() {
+            note = e.to_string();
+        }
+        if controls.sound_active(&chip8) && !beeping {
+            queue!(stdout, Print('\x07'))?;
+        }
+        beeping = controls.sound_active(&chip8);
+        match phosphor.as_mut() {
+            Some(phosphor) if frames > 0 => draw(&mut stdout, phosphor.apply(chip8.screen()))?,
+            None if shown != Some(*chip8.screen()) => {
+                chip8.screen().to_buffer(&mut buffer, &palette);
+                draw(&mut stdout, &buffer)?;
+                shown = Some(*chip8.screen());
+            }
+            _ => (),
+        }
+        queue!(stdout,
+            cursor::MoveTo(0, (HEIGHT / 2) as u16),
+            terminal::Clear(terminal::ClearType::CurrentLine),
+            Print(status(&controls, &session, &note)))?;
+        stdout.flush()?;
+
+        deadline += FRAME;
+        match deadline.checked_duration_since(Instant::now()) {
+            Some(wait) => thread::sleep(wait),
+            None => deadline = Instant::now(),
+        }
+    }
+    drop(term);
+    if let Err(e) = session.finish(&chip8) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    let (trace, (profiler, coverage)) = observers;
+    if let Some(trace) = trace {
+        trace.finish()?;
+    }
+    if let Some(profiler) = profiler {
+        args.profile.write(&profiler)?;
+    }
+    if let Some(coverage) = coverage {
+        args.coverage.write(&coverage, &rom)?;
+    }
+    Ok(())
+}
+
+// Draws two pixel rows per terminal row: the upper half block takes the top
+// pixel as foreground and the bottom pixel as background.
+fn draw(out: &mut impl Write, screen: &[u32; WIDTH * HEIGHT]) -> io::Result<()> {
+    for row in 0..HEIGHT / 2 {
+        queue!(out, cursor::MoveTo(0, row as u16))?;
+        let mut colors = None;
+        for x in 0..WIDTH {
+            let top = to_color(screen[x + row * 2 * WIDTH]);
+            let bottom = to_color(screen[x + (row * 2 + 1) * WIDTH]);
+            if colors != Some((top, bottom)) {
+                queue!(out, SetForegroundColor(top), SetBackgroundColor(bottom))?;
+                colors = Some((top, bottom));
+            }
+            queue!(out, Print('\u{2580}'))?;
+        }
+        queue!(out, ResetColor)?;
+    }
+    Ok(())
+}
+
+fn search_command(key: u8) -> Option<SearchCommand> {
+    match key {
+        1 => Some(SearchCommand::Start),
+        2 => Some(SearchCommand::Narrow(Compare::Equal)),
+        3 => Some(SearchCommand::Narrow(Compare::Changed)),
+        4 => Some(SearchCommand::Narrow(Compare::Increased)),
+        5 => Some(SearchCommand::Narrow(Compare::Decreased)),
+        6 => Some(SearchCommand::Freeze),
+        _ => None,
+    }
+}
+
+// Keys the keymap binds stay CHIP-8 keys
+fn is_hotkey(keymap: &Keymap, code: KeyCode) -> bool {
+    matches!(code, KeyCode::Char('p') | KeyCode::Char('n') | KeyCode::Char('m') | KeyCode::Tab)
+        && to_valid_key(keymap, code).is_none()
+}
+
+fn status(controls: &Controls, session: &Session, note: &str) -> String {
+    let speed = match (controls.is_paused(), controls.speed()) {
+        (true, _) => "paused (p: resume, n: next frame)".to_owned(),
+        (false, Speed::Normal) => String::new(),
+        (false, Speed::FastForward(frames)) => format!("fast forward x{}", frames),
+        (false, Speed::SlowMotion(ticks)) => format!("slow motion 1/{}", ticks),
+    };
+    let movie = if session.is_recording() {
+        "recording"
+    } else if session.is_replaying() {
+        "replay"
+    } else {
+        note
+    };
+    match (movie.is_empty(), speed.is_empty()) {
+        (true, _) => speed,
+        (false, true) => movie.to_owned(),
+        (false, false) => format!("{} - {}", movie, speed),
+    }
+}
+
+fn to_color(pixel: u32) -> Color {
+    Color::Rgb {
+        r: (pixel >> 16) as u8,
+        g: (pixel >> 8) as u8,
+        b: pixel as u8,
+    }
+}
+
+fn to_valid_key(keymap: &Keymap, code: KeyCode) -> Option<u8> {
+    match code {
+        KeyCode::Up    => keymap.hex_key("ArrowUp"),
+        KeyCode::Down  => keymap.hex_key("ArrowDown"),
+        KeyCode::Left  => keymap.hex_key("ArrowLeft"),
+        KeyCode::Right => keymap.hex_key("ArrowRight"),
+        KeyCode::Enter => keymap.hex_key("Enter"),
+        KeyCode::Char(' ') => keymap.hex_key("Space"),
+        // Terminals only send the typed character, so its position comes
+        // from the keyboard layout
+        KeyCode::Char(c) => keymap.hex_key_for_char(c),
+        _ => None,
+    }
+}
@@ -0,0 +1,378 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::emulator::{Chip8, MEMORY_SIZE};
+
+// Most candidates a search lists or freezes at once
+const SHOWN_CANDIDATES: usize = 8;
+
+#[derive(Debug)]
+pub enum CheatError {
+    Io(io::Error),
+    /// A line of a cheat file that could not be read (1-based).
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::Io(e) => write!(f, "Could not access cheats: {}", e),
+            CheatError::Parse { line, message } => write!(f, "Invalid cheat on line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+impl From<io::Error> for CheatError {
+    fn from(e: io::Error) -> Self {
+        CheatError::Io(e)
+    }
+}
+
+/// What a cheat holds at a fixed value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Register(u8),
+    Memory(u16),
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let register = s.strip_prefix('V').or_else(|| s.strip_prefix('v'));
+        match register {
+            Some(x) if x.len() == 1 => u8::from_str_radix(x, 16).map(Target::Register).map_err(|e| e.to_string()),
+            _ => u16::from_str_radix(s.trim_start_matches("0x"), 16)
+                .ok()
+                .filter(|&addr| (addr as usize) < MEMORY_SIZE)
+                .map(Target::Memory)
+                .ok_or_else(|| format!("Expected V0-VF or an address, not {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Register(x) => write!(f, "V{:X}", x),
+            Target::Memory(addr) => write!(f, "{:03x}", addr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub target: Target,
+    pub value: u8,
+    pub name: String,
+}
+
+/// Registers and memory frozen to a value every frame. Saved per rom, next
+/// to it with the extension `.cht`, one cheat per line:
+///
+/// ```text
+/// # target = value (hex), then an optional name
+/// 2f4 = 09 lives
+/// VE = 00 no enemies
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Self {
+        Cheats::default()
+    }
+
+    /// Where the cheats of `rom` are kept.
+    pub fn path_for(rom: &Path) -> PathBuf {
+        rom.with_extension("cht")
+    }
+
+    pub fn load(path: &Path) -> Result<Self, CheatError> {
+        Cheats::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, CheatError> {
+        let mut cheats = Cheats::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| CheatError::Parse { line: i + 1, message };
+            let (target, rest) = line.split_once('=').ok_or_else(|| error("Expected <target> = <value>".to_owned()))?;
+            let target = target.trim().parse().map_err(error)?;
+            let rest = rest.trim();
+            let (value, name) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            let value = u8::from_str_radix(value.trim_start_matches("0x"), 16)
+                .map_err(|_| error(format!("Expected a hex byte, not {}", value)))?;
+            cheats.add(Cheat { target, value, name: name.trim().to_owned() });
+        }
+        Ok(cheats)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), CheatError> {
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    /// Adds a cheat, replacing the one on the same target.
+    pub fn add(&mut self, cheat: Cheat) {
+        self.remove(cheat.target);
+        self.cheats.push(cheat);
+    }
+
+    pub fn remove(&mut self, target: Target) {
+        self.cheats.retain(|cheat| cheat.target != target);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> {
+        self.cheats.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    /// Sets every target to its value; frontends call it before each frame.
+    pub fn apply(&self, chip8: &mut Chip8) {
+        for cheat in &self.cheats {
+            match cheat.target {
+                Target::Register(x) => chip8.set_register(x, cheat.value),
+                Target::Memory(addr) => chip8.write_memory(addr, cheat.value),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Cheats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for cheat in &self.cheats {
+            if cheat.name.is_empty() {
+                writeln!(f, "{} = {:02x}", cheat.target, cheat.value)?;
+            } else {
+                writeln!(f, "{} = {:02x} {}", cheat.target, cheat.value, cheat.name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a byte compares with its value in the last snapshot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compare {
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    /// Now holds exactly this value.
+    Value(u8),
+}
+
+impl Compare {
+    fn matches(self, before: u8, now: u8) -> bool {
+        match self {
+            Compare::Equal => now == before,
+            Compare::Changed => now != before,
+            Compare::Increased => now > before,
+            Compare::Decreased => now < before,
+            Compare::Value(value) => now == value,
+        }
+    }
+}
+
+/// Finds where a rom keeps a value, like the number of lives: take a
+/// snapshot, play until the value changes, and narrow the candidates down
+/// with how it changed.
+#[derive(Debug, Clone)]
+pub struct Search {
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl Search {
+    /// Starts with every address as a candidate.
+    pub fn new(chip8: &Chip8) -> Self {
+        let snapshot = chip8.memory().to_vec();
+        Search { candidates: (0..snapshot.len() as u16).collect(), snapshot }
+    }
+
+    /// Keeps the candidates whose byte compares to the last snapshot as
+    /// asked, then takes a new snapshot. Returns how many are left.
+    pub fn narrow(&mut self, chip8: &Chip8, compare: Compare) -> usize {
+        let memory = chip8.memory();
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&addr| compare.matches(snapshot[addr as usize], memory[addr as usize]));
+        self.snapshot = memory.to_vec();
+        self.candidates.len()
+    }
+
+    /// Addresses still matching, with their value in the last snapshot.
+    pub fn candidates(&self) -> impl Iterator<Item = (u16, u8)> + '_ {
+        self.candidates.iter().map(move |&addr| (addr, self.snapshot[addr as usize]))
+    }
+}
+
+/// A step of a memory search, from a frontend hotkey.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchCommand {
+    /// Start over with a snapshot of all of memory.
+    Start,
+    Narrow(Compare),
+    /// Freeze the candidates left at their current value.
+    Freeze,
+}
+
+/// Runs a memory search for a frontend, one hotkey at a time.
+#[derive(Debug, Clone, Default)]
+pub struct SearchControls {
+    search: Option<Search>,
+}
+
+impl SearchControls {
+    pub fn new() -> Self {
+        SearchControls::default()
+    }
+
+    /// Carries out `command`, adding to `cheats` on a freeze. Returns a line
+    /// telling the player how the search stands.
+    pub fn run(&mut self, command: SearchCommand, chip8: &Chip8, cheats: &mut Cheats) -> String {
+        let search = match (command, self.search.as_mut()) {
+            (SearchCommand::Start, _) | (_, None) => {
+                self.search = Some(Search::new(chip8));
+                return format!("search: {} candidates", chip8.memory().len());
+            }
+            (_, Some(search)) => search,
+        };
+        match command {
+            SearchCommand::Freeze if search.candidates.len() > SHOWN_CANDIDATES => {
+                format!("search: narrow down to {} candidates to freeze them", SHOWN_CANDIDATES)
+            }
+            SearchCommand::Freeze => {
+                let frozen: Vec<String> = search.candidates().map(|(addr, _)| {
+                    let value = chip8.memory()[addr as usize];
+                    cheats.add(Cheat { target: Target::Memory(addr), value, name: "found".to_owned() });
+                    format!("{:03x} = {:02x}", addr, value)
+                }).collect();
+                format!("search: froze {}", frozen.join(", "))
+            }
+            _ => {
+                if let SearchCommand::Narrow(compare) = command {
+                    search.narrow(chip8, compare);
+                }
+                let left = search.candidates.len();
+                if (1..=SHOWN_CANDIDATES).contains(&left) {
+                    let candidates: Vec<String> =
+                        search.candidates().map(|(addr, value)| format!("{:03x}={:02x}", addr, value)).collect();
+                    format!("search: {} candidates ({})", left, candidates.join(" "))
+                } else {
+                    format!("search: {} candidates", left)
+                }
+            }
+        }
+    }
+
+    /// Like `run`, and saves the cheats to `path` once a freeze adds to them.
+    pub fn run_and_save(&mut self, command: SearchCommand, chip8: &Chip8, cheats: &mut Cheats, path: &Path) -> String {
+        let before = cheats.clone();
+        let line = self.run(command, chip8, cheats);
+        if *cheats == before {
+            return line;
+        }
+        match cheats.save(path) {
+            Ok(()) => format!("{}, saved to {}", line, path.display()),
+            Err(e) => e.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts V0 down into 0x300 every pass while 0 is not held
+    const ROM: [u8; 14] = [
+        0x60, 0x09, // 200: LD V0, 9
+        0xa3, 0x00, // 202: LD I, 0x300
+        0xf0, 0x55, // 204: LD [I], V0
+        0x61, 0x00, // 206: LD V1, 0
+        0xe1, 0x9e, // 208: SKP V1
+        0x70, 0xff, // 20a: ADD V0, 0xff
+        0x12, 0x04, // 20c: JP 0x204
+    ];
+
+    #[test]
+    fn search_and_freeze() {
+        let mut chip8 = Chip8::from_rom(&ROM);
+        chip8.run_frame(3);
+        let mut search = Search::new(&chip8);
+        chip8.run_frame(6);
+        search.narrow(&chip8, Compare::Decreased);
+        chip8.set_keys(1);
+        chip8.run_frame(6);
+        search.narrow(&chip8, Compare::Equal);
+        chip8.set_keys(0);
+        chip8.run_frame(6);
+        assert_eq!(search.narrow(&chip8, Compare::Changed), 1);
+        assert_eq!(search.candidates().collect::<Vec<_>>(), [(0x300, 7)]);
+
+        let mut cheats = Cheats::new();
+        cheats.add(Cheat { target: Target::Memory(0x300), value: 9, name: "lives".to_owned() });
+        cheats.add(Cheat { target: Target::Register(0), value: 9, name: String::new() });
+        for _ in 0..5 {
+            cheats.apply(&mut chip8);
+            chip8.run_frame(6);
+        }
+        // Without the cheats it would be down to 2
+        assert!(chip8.memory()[0x300] >= 8);
+    }
+
+    #[test]
+    fn search_controls() {
+        let mut chip8 = Chip8::from_rom(&ROM);
+        let mut controls = SearchControls::new();
+        let mut cheats = Cheats::new();
+        chip8.run_frame(3);
+        // Any command starts a search
+        assert_eq!(controls.run(SearchCommand::Freeze, &chip8, &mut cheats), "search: 4096 candidates");
+        assert!(controls.run(SearchCommand::Freeze, &chip8, &mut cheats).contains("narrow down"));
+        chip8.run_frame(6);
+        controls.run(SearchCommand::Narrow(Compare::Decreased), &chip8, &mut cheats);
+        chip8.set_keys(1);
+        chip8.run_frame(6);
+        controls.run(SearchCommand::Narrow(Compare::Equal), &chip8, &mut cheats);
+        chip8.set_keys(0);
+        chip8.run_frame(6);
+        let line = controls.run(SearchCommand::Narrow(Compare::Changed), &chip8, &mut cheats);
+        assert_eq!(line, "search: 1 candidates (300=07)");
+        assert!(cheats.is_empty());
+        let path = std::env::temp_dir().join(format!("chip8-cheats-{}.cht", std::process::id()));
+        let line = controls.run_and_save(SearchCommand::Freeze, &chip8, &mut cheats, &path);
+        assert_eq!(line, format!("search: froze 300 = 07, saved to {}", path.display()));
+        assert_eq!(Cheats::load(&path).unwrap().to_string(), "300 = 07 found\n");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn text_format() {
+        let cheats = Cheats::parse("# infinite lives\n2f4 = 09 lives\n\nVe = 0x00\n").unwrap();
+        let parsed: Vec<(Target, u8, &str)> =
+            cheats.iter().map(|cheat| (cheat.target, cheat.value, cheat.name.as_str())).collect();
+        assert_eq!(parsed, [(Target::Memory(0x2f4), 9, "lives"), (Target::Register(0xe), 0, "")]);
+        assert_eq!(cheats.to_string(), "2f4 = 09 lives\nVE = 00\n");
+        assert_eq!(Cheats::parse(&cheats.to_string()).unwrap(), cheats);
+
+        assert!(matches!(Cheats::parse("2f4 09"), Err(CheatError::Parse { line: 1, .. })));
+        assert!(matches!(Cheats::parse("\nVG = 1"), Err(CheatError::Parse { line: 2, .. })));
+        assert!(matches!(Cheats::parse("1000 = 1"), Err(CheatError::Parse { .. })));
+        assert_eq!(Cheats::parse("fff = 1").unwrap().iter().next().unwrap().target, Target::Memory(0xfff));
+        assert_eq!(Cheats::path_for(Path::new("roms/pong.ch8")), PathBuf::from("roms/pong.cht"));
+    }
+}
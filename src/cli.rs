@@ -0,0 +1,445 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::cheats::{CheatError, Cheats};
+use crate::config::{self, ConfigError, Settings};
+use crate::coverage::Coverage;
+use crate::emulator::Chip8;
+use crate::display::Palette;
+use crate::keymap::Keymap;
+use crate::movie::{Movie, MovieError, Session};
+use crate::patch::{self, PatchError, PatchedRom};
+use crate::phosphor::PhosphorMode;
+use crate::profile::Profiler;
+use crate::quirks::{QuirkPreset, Variant};
+use crate::trace::{self, Trace, TraceFilter, TraceFormat};
+
+#[derive(Debug, Parser)]
+#[command(name = "chip8", version, about = "A CHIP-8 emulator")]
+#[command(after_help = "Settings not given on the command line are read from the config file \
+    ($XDG_CONFIG_HOME/chip8/config.toml, or ~/.config/chip8/config.toml), which also holds the keymap.")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run a rom in a window
+    Run(RunArgs),
+    /// Show which config file is used and the settings it results in
+    Config(Options),
+    /// Replay a movie without a window and check that it ends like the recording
+    Replay {
+        /// The movie to replay
+        movie: PathBuf,
+        /// The rom it was recorded with
+        rom: PathBuf,
+        /// Apply the IPS or BPS patch FILE to the rom [default: the rom's .ips or .bps file, if any]
+        #[arg(long, value_name = "FILE")]
+        patch: Option<PathBuf>,
+        #[command(flatten)]
+        trace: TraceArgs,
+        #[command(flatten)]
+        profile: ProfileArgs,
+        #[command(flatten)]
+        coverage: CoverageArgs,
+    },
+    /// Find the code, data and subroutines of a rom without running it
+    Analyze {
+        /// The rom to analyze
+        rom: PathBuf,
+        /// Apply the IPS or BPS patch FILE to the rom [default: the rom's .ips or .bps file, if any]
+        #[arg(long, value_name = "FILE")]
+        patch: Option<PathBuf>,
+        /// Write the control flow graph to FILE in Graphviz DOT format
+        #[arg(long, value_name = "FILE")]
+        dot: Option<PathBuf>,
+    },
+    /// Find the sprites of a rom, statically and optionally by running it
+    Sprites {
+        /// The rom to search
+        rom: PathBuf,
+        /// Also record what gets drawn while running the rom for N frames without input
+        #[arg(long, value_name = "N", conflicts_with = "movie")]
+        frames: Option<u32>,
+        /// Also record what gets drawn while replaying a movie of the rom
+        #[arg(long, value_name = "FILE")]
+        movie: Option<PathBuf>,
+        /// Write a PNG sprite sheet to FILE instead of printing the sprites
+        #[arg(long, value_name = "FILE")]
+        png: Option<PathBuf>,
+        #[command(flatten)]
+        options: Options,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct RunArgs {
+    /// The rom to run
+    pub rom: PathBuf,
+    #[command(flatten)]
+    pub options: Options,
+    #[command(flatten)]
+    pub movie: MovieArgs,
+    #[command(flatten)]
+    pub trace: TraceArgs,
+    #[command(flatten)]
+    pub profile: ProfileArgs,
+    #[command(flatten)]
+    pub coverage: CoverageArgs,
+}
+
+// Shared by every frontend, so they all take the same flags
+#[derive(Debug, Default, Args)]
+pub struct Options {
+    /// Instructions executed per 60Hz frame [default: 10]
+    #[arg(long, value_name = "N")]
+    pub ipf: Option<usize>,
+    /// Quirk preset: cowgod, vip or schip [default: the variant's preset]
+    #[arg(long, value_name = "PRESET")]
+    pub quirks: Option<QuirkPreset>,
+    /// Machine variant: chip8 or schip [default: chip8]
+    #[arg(long)]
+    pub variant: Option<Variant>,
+    /// Window scale: 1, 2, 4, 8, 16 or 32 [default: 8]
+    #[arg(long)]
+    pub scale: Option<usize>,
+    /// Colours: classic, amber, green, lcd or <on>:<off> as hex RGB [default: classic]
+    #[arg(long)]
+    pub palette: Option<Palette>,
+    /// Seed for RND, to make runs repeatable [default: random]
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Turn off the sound
+    #[arg(long)]
+    pub mute: bool,
+    /// How many times faster fast forward runs, and slower slow motion [default: 4]
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub speed: Option<u32>,
+    /// Filter against sprite flicker: blend, blend:<decay>, persist or hold
+    #[arg(long, value_name = "MODE")]
+    pub phosphor: Option<PhosphorMode>,
+    /// Read the keymap from FILE instead of the config file
+    #[arg(long, value_name = "FILE")]
+    pub keymap: Option<PathBuf>,
+    /// Use FILE as the config file
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Don't take settings for the rom from the rom database
+    #[arg(long)]
+    pub no_database: bool,
+    /// Freeze the values in the cheat file FILE every frame [default: the rom's .cht file, if any]
+    #[arg(long, value_name = "FILE")]
+    pub cheats: Option<PathBuf>,
+    /// Apply the IPS or BPS patch FILE to the rom [default: the rom's .ips or .bps file, if any]
+    #[arg(long, value_name = "FILE")]
+    pub patch: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Args)]
+pub struct MovieArgs {
+    /// Record the keys of every frame to FILE, to replay the session later
+    #[arg(long, value_name = "FILE", conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+    /// Replay a recorded session from FILE, with the settings it was recorded with
+    #[arg(long, value_name = "FILE")]
+    pub replay: Option<PathBuf>,
+}
+
+impl MovieArgs {
+    /// The machine to run and the session to run it in. A replay sets the
+    /// machine up the way it was recorded, otherwise `settings` apply.
+    pub fn start(&self, rom: &[u8], settings: &Settings) -> Result<(Chip8, Session), MovieError> {
+        if let Some(path) = &self.replay {
+            let movie = Movie::load(path)?;
+            return Ok((movie.start(rom)?, Session::replay(movie)));
+        }
+        let mut chip8 = Chip8::from_rom(rom);
+        settings.apply(&mut chip8);
+        let instructions = settings.instructions_per_frame();
+        match &self.record {
+            Some(path) => {
+                // A recording needs to know its seed
+                let seed = settings.seed.unwrap_or_else(rand::random);
+                chip8.seed_rng(seed);
+                let movie = Movie::new(rom, seed, instructions, chip8.variant(), chip8.quirks());
+                Ok((chip8, Session::record(movie, path.clone())))
+            }
+            None => Ok((chip8, Session::live(instructions))),
+        }
+    }
+}
+
+#[derive(Debug, Default, Args)]
+pub struct TraceArgs {
+    /// Log every executed instruction to FILE
+    #[arg(long, value_name = "FILE")]
+    pub trace: Option<PathBuf>,
+    /// Trace log format: text or json (JSON Lines) [default: text]
+    #[arg(long, value_name = "FORMAT", requires = "trace")]
+    pub trace_format: Option<TraceFormat>,
+    /// Only log instructions at these addresses, e.g. 200-2ff
+    #[arg(long, value_name = "RANGE", requires = "trace", value_parser = trace::parse_pc_range)]
+    pub trace_pc: Option<RangeInclusive<u16>>,
+    /// Only log these instructions, e.g. DRW,CALL
+    #[arg(long, value_name = "NAMES", requires = "trace", value_delimiter = ',')]
+    pub trace_kind: Vec<String>,
+    /// Only log during these frames, e.g. 100-200
+    #[arg(long, value_name = "RANGE", requires = "trace", value_parser = trace::parse_frame_range)]
+    pub trace_frames: Option<RangeInclusive<u64>>,
+}
+
+impl TraceArgs {
+    pub fn filter(&self) -> TraceFilter {
+        TraceFilter { pc: self.trace_pc.clone(), kinds: self.trace_kind.clone(), frames: self.trace_frames.clone() }
+    }
+
+    /// The trace log, if one was asked for.
+    pub fn open(&self) -> io::Result<Option<Trace<BufWriter<File>>>> {
+        self.trace
+            .as_deref()
+            .map(|path| Trace::create(path, self.trace_format.unwrap_or_default(), self.filter()))
+            .transpose()
+    }
+}
+
+#[derive(Debug, Default, Args)]
+pub struct ProfileArgs {
+    /// Profile the run and write a hotspot report to FILE at exit
+    #[arg(long, value_name = "FILE")]
+    pub profile: Option<PathBuf>,
+    /// Profile the run and write its frames and subroutine calls to FILE as Chrome trace events
+    #[arg(long, value_name = "FILE")]
+    pub profile_trace: Option<PathBuf>,
+}
+
+impl ProfileArgs {
+    /// The profiler, if a report or trace was asked for.
+    pub fn profiler(&self) -> Option<Profiler> {
+        (self.profile.is_some() || self.profile_trace.is_some()).then(Profiler::new)
+    }
+
+    pub fn write(&self, profiler: &Profiler) -> io::Result<()> {
+        if let Some(path) = &self.profile {
+            std::fs::write(path, profiler.to_string())?;
+        }
+        if let Some(path) = &self.profile_trace {
+            profiler.write_chrome_trace(BufWriter::new(File::create(path)?))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Args)]
+pub struct CoverageArgs {
+    /// Write the addresses the run executed, read and wrote to FILE as JSON at exit
+    #[arg(long, value_name = "FILE")]
+    pub coverage: Option<PathBuf>,
+    /// Write a disassembly of the rom marked with what the run covered to FILE at exit
+    #[arg(long, value_name = "FILE")]
+    pub coverage_listing: Option<PathBuf>,
+}
+
+impl CoverageArgs {
+    /// The coverage recorder, if a coverage file was asked for.
+    pub fn coverage(&self) -> Option<Coverage> {
+        (self.coverage.is_some() || self.coverage_listing.is_some()).then(Coverage::new)
+    }
+
+    pub fn write(&self, coverage: &Coverage, rom: &[u8]) -> io::Result<()> {
+        if let Some(path) = &self.coverage {
+            std::fs::write(path, coverage.to_json())?;
+        }
+        if let Some(path) = &self.coverage_listing {
+            std::fs::write(path, coverage.listing(rom))?;
+        }
+        Ok(())
+    }
+}
+
+impl Options {
+    // Only what was given on the command line
+    pub fn settings(&self) -> Settings {
+        Settings {
+            instructions_per_frame: self.ipf,
+            quirks: self.quirks,
+            variant: self.variant,
+            scale: self.scale,
+            palette: self.palette,
+            seed: self.seed,
+            mute: if self.mute { Some(true) } else { None },
+            speed: self.speed,
+            phosphor: self.phosphor,
+            rom_quirks: None,
+        }
+    }
+
+    /// The command line layered over the config file, and the keymap for `rom`.
+    pub fn load(&self, rom: Option<&Path>) -> Result<(Settings, Keymap), ConfigError> {
+        let (file, keymap) = self.load_file(rom)?;
+        Ok((self.settings().or(file), keymap))
+    }
+
+    /// Like `load`, with the rom database entry for `data` between the command
+    /// line and the config file. `data` is the rom before any patch, since the
+    /// database only knows released roms. A variant or preset picked on the
+    /// command line or in the config file replaces the quirks from the database.
+    pub fn load_rom(&self, rom: &Path, data: &[u8]) -> Result<(Settings, Keymap), ConfigError> {
+        let (file, mut keymap) = self.load_file(Some(rom))?;
+        let entry = if self.no_database { None } else { config::database()?.lookup(data) };
+        let settings = match entry {
+            Some(entry) => {
+                entry.bind_keys(&mut keymap);
+                let mut rom = entry.settings();
+                if file.variant.is_some() || file.quirks.is_some() {
+                    rom.variant = None;
+                    rom.rom_quirks = None;
+                }
+                self.settings().or(rom).or(file)
+            }
+            None => self.settings().or(file),
+        };
+        Ok((settings, keymap))
+    }
+
+    fn load_file(&self, rom: Option<&Path>) -> Result<(Settings, Keymap), ConfigError> {
+        let rom = rom.and_then(|rom| rom.file_stem()).and_then(|name| name.to_str());
+        let (file, keymap) = config::load(self.config.as_deref(), rom)?;
+        let keymap = match &self.keymap {
+            Some(path) => Keymap::load(path, rom).map_err(|e| ConfigError::Keymap(path.clone(), e))?,
+            None => keymap,
+        };
+        Ok((file, keymap))
+    }
+
+    /// The rom at `path`, before and after its patch.
+    pub fn read_rom(&self, path: &Path) -> Result<PatchedRom, PatchError> {
+        patch::read_rom(path, self.patch.as_deref())
+    }
+
+    /// The cheats for `rom`: from `--cheats`, or the file next to the rom.
+    pub fn cheats(&self, rom: &Path) -> Result<Cheats, CheatError> {
+        match &self.cheats {
+            Some(path) => Cheats::load(path),
+            None => {
+                let path = Cheats::path_for(rom);
+                if path.exists() { Cheats::load(&path) } else { Ok(Cheats::new()) }
+            }
+        }
+    }
+
+    /// Where cheats found while playing `rom` are saved.
+    pub fn cheats_path(&self, rom: &Path) -> PathBuf {
+        self.cheats.clone().unwrap_or_else(|| Cheats::path_for(rom))
+    }
+
+    pub fn config_path(&self) -> Option<PathBuf> {
+        self.config.clone().or_else(config::config_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::{LoadStore, Quirks};
+    use clap::CommandFactory;
+
+    #[test]
+    fn cli_is_valid() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn run_flags() {
+        let cli = Cli::try_parse_from([
+            "chip8", "run", "pong.ch8", "--ipf", "20", "--quirks", "vip", "--seed", "7", "--mute",
+        ]).unwrap();
+        let args = match cli.command {
+            Command::Run(args) => args,
+            command => panic!("Parsed as {:?}", command),
+        };
+        assert_eq!(args.rom, PathBuf::from("pong.ch8"));
+        let settings = args.options.settings();
+        assert_eq!(settings.instructions_per_frame, Some(20));
+        assert_eq!(settings.quirks, Some(QuirkPreset::Vip));
+        assert_eq!(settings.seed, Some(7));
+        assert_eq!(settings.mute, Some(true));
+        assert!(Cli::try_parse_from(["chip8", "run", "pong.ch8", "--variant", "xochip"]).is_err());
+    }
+
+    #[test]
+    fn trace_flags() {
+        let cli = Cli::try_parse_from([
+            "chip8", "replay", "pong.movie", "pong.ch8", "--trace", "pong.log", "--trace-format", "json",
+            "--trace-pc", "200-2ff", "--trace-kind", "DRW,CALL", "--trace-frames", "60-120",
+        ]).unwrap();
+        let trace = match cli.command {
+            Command::Replay { trace, .. } => trace,
+            command => panic!("Parsed as {:?}", command),
+        };
+        assert_eq!(trace.trace_format, Some(TraceFormat::Json));
+        let filter = trace.filter();
+        assert_eq!(filter.pc, Some(0x200..=0x2ff));
+        assert_eq!(filter.kinds, ["DRW", "CALL"]);
+        assert_eq!(filter.frames, Some(60..=120));
+        // Filters without a log to filter
+        assert!(Cli::try_parse_from(["chip8", "run", "pong.ch8", "--trace-kind", "DRW"]).is_err());
+    }
+
+    #[test]
+    fn database_under_flags() {
+        let rom = std::fs::read("tests/roms/timers.ch8").unwrap();
+        let options = Options { config: Some("/dev/null".into()), ..Options::default() };
+        let (settings, _) = options.load_rom("timers.ch8".as_ref(), &rom).unwrap();
+        assert_eq!(settings.quirks(), Quirks { load_store: LoadStore::Increment, display_wait: false, logic: false, stack_depth: 16, ..Quirks::VIP });
+
+        let options = Options { quirks: Some(QuirkPreset::Vip), ..options };
+        let (settings, _) = options.load_rom("timers.ch8".as_ref(), &rom).unwrap();
+        assert_eq!(settings.quirks(), Quirks::VIP);
+
+        let options = Options { quirks: None, variant: Some(Variant::Schip), ..options };
+        let (settings, _) = options.load_rom("timers.ch8".as_ref(), &rom).unwrap();
+        assert_eq!(settings.quirks(), Quirks::SCHIP);
+    }
+
+    #[test]
+    fn config_variant_over_database() {
+        let dir = std::env::temp_dir().join(format!("chip8-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config.toml");
+        std::fs::write(&config, "variant = \"schip\"\ninstructions-per-frame = 30\n").unwrap();
+        let rom = std::fs::read("tests/roms/draw.ch8").unwrap();
+        let options = Options { config: Some(config.clone()), ..Options::default() };
+        let (settings, _) = options.load_rom("draw.ch8".as_ref(), &rom).unwrap();
+        assert_eq!(settings.variant(), Variant::Schip);
+        assert_eq!(settings.quirks(), Quirks::SCHIP);
+        // The rest of the entry still comes before the config file
+        assert_eq!(settings.palette(), "ffffff:000000".parse().unwrap());
+
+        let options = Options { quirks: Some(QuirkPreset::Vip), ..options };
+        let (settings, _) = options.load_rom("draw.ch8".as_ref(), &rom).unwrap();
+        assert_eq!(settings.quirks(), Quirks::VIP);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn database_for_patched_roms() {
+        let dir = std::env::temp_dir().join(format!("chip8-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("timers.ch8");
+        std::fs::copy("tests/roms/timers.ch8", &path).unwrap();
+        // Changes the first byte
+        std::fs::write(dir.join("timers.ips"), b"PATCH\0\0\0\0\x01\xffEOF").unwrap();
+        let options = Options { config: Some("/dev/null".into()), ..Options::default() };
+        let rom = options.read_rom(&path).unwrap();
+        assert_eq!(rom.patched[0], 0xff);
+        let (settings, _) = options.load_rom(&path, &rom.original).unwrap();
+        assert_eq!(settings.quirks().load_store, LoadStore::Increment);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::control::Speed;
+use crate::database::Database;
+use crate::display::Palette;
+use crate::emulator::{Chip8, INSTRUCTIONS_PER_FRAME};
+use crate::keymap::{Keymap, KeymapError};
+use crate::phosphor::PhosphorMode;
+use crate::quirks::{QuirkPreset, Quirks, Variant};
+
+pub const DEFAULT_SCALE: usize = 8;
+/// How many times faster fast forward runs, and slower slow motion.
+pub const DEFAULT_SPEED: u32 = 4;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Toml(PathBuf, toml::de::Error),
+    Keymap(PathBuf, KeymapError),
+    Database(PathBuf, serde_json::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            ConfigError::Toml(path, e) => write!(f, "Invalid config {}: {}", path.display(), e),
+            ConfigError::Keymap(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Database(path, e) => write!(f, "Invalid rom database {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Settings from one layer of configuration (config file or command line).
+/// Unset values fall through to the layer below and finally to the defaults.
+///
+/// ```toml
+/// instructions-per-frame = 15
+/// quirks = "vip"
+/// variant = "chip8"
+/// scale = 8
+/// palette = "amber"
+/// seed = 1234
+/// mute = true
+/// speed = 4
+/// phosphor = "blend:0.4"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Settings {
+    pub instructions_per_frame: Option<usize>,
+    #[serde(default, deserialize_with = "parse")]
+    pub quirks: Option<QuirkPreset>,
+    #[serde(default, deserialize_with = "parse")]
+    pub variant: Option<Variant>,
+    pub scale: Option<usize>,
+    #[serde(default, deserialize_with = "parse")]
+    pub palette: Option<Palette>,
+    pub seed: Option<u64>,
+    pub mute: Option<bool>,
+    pub speed: Option<u32>,
+    #[serde(default, deserialize_with = "parse")]
+    pub phosphor: Option<PhosphorMode>,
+    /// Quirks of a rom from the rom database, used when no preset is given.
+    #[serde(skip)]
+    pub rom_quirks: Option<Quirks>,
+}
+
+// Reads the value through its FromStr, so the config accepts the same strings as the command line
+fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+impl Settings {
+    pub fn from_toml(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    // Fills in everything this layer leaves unset from `below`
+    pub fn or(self, below: Settings) -> Settings {
+        // A preset and rom quirks replace each other, and a variant on its own
+        // replaces the rom quirks below it with the variant's preset
+        let (quirks, rom_quirks) = match (self.quirks, self.rom_quirks) {
+            (None, None) if self.variant.is_some() => (below.quirks, None),
+            (None, None) => (below.quirks, below.rom_quirks),
+            quirks => quirks,
+        };
+        Settings {
+            instructions_per_frame: self.instructions_per_frame.or(below.instructions_per_frame),
+            quirks,
+            variant: self.variant.or(below.variant),
+            scale: self.scale.or(below.scale),
+            palette: self.palette.or(below.palette),
+            seed: self.seed.or(below.seed),
+            mute: self.mute.or(below.mute),
+            speed: self.speed.or(below.speed),
+            phosphor: self.phosphor.or(below.phosphor),
+            rom_quirks,
+        }
+    }
+
+    pub fn instructions_per_frame(&self) -> usize {
+        self.instructions_per_frame.unwrap_or(INSTRUCTIONS_PER_FRAME)
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant.unwrap_or_default()
+    }
+
+    // The explicit preset, or the one that goes with the variant
+    pub fn preset(&self) -> QuirkPreset {
+        self.quirks.unwrap_or_else(|| self.variant().preset())
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        match (self.quirks, self.rom_quirks) {
+            (None, Some(quirks)) => quirks,
+            _ => self.preset().quirks(),
+        }
+    }
+
+    pub fn scale(&self) -> usize {
+        self.scale.unwrap_or(DEFAULT_SCALE)
+    }
+
+    pub fn palette(&self) -> Palette {
+        self.palette.unwrap_or_default()
+    }
+
+    pub fn mute(&self) -> bool {
+        self.mute.unwrap_or(false)
+    }
+
+    // A speed of 0 would stop fast forward altogether, so it counts as 1
+    pub fn speed(&self) -> u32 {
+        self.speed.unwrap_or(DEFAULT_SPEED).max(1)
+    }
+
+    pub fn fast_forward(&self) -> Speed {
+        Speed::FastForward(self.speed())
+    }
+
+    pub fn slow_motion(&self) -> Speed {
+        Speed::SlowMotion(self.speed())
+    }
+
+    pub fn apply(&self, chip8: &mut Chip8) {
+        chip8.set_variant(self.variant());
+        chip8.set_quirks(self.quirks());
+        if let Some(seed) = self.seed {
+            chip8.seed_rng(seed);
+        }
+    }
+}
+
+// Every setting with the defaults filled in, in config file syntax
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let palette = self.palette();
+        writeln!(f, "instructions-per-frame = {}", self.instructions_per_frame())?;
+        match (self.quirks, self.rom_quirks) {
+            (None, Some(quirks)) => writeln!(f, "# quirks from the rom database: {:?}", quirks)?,
+            _ => writeln!(f, "quirks = \"{}\"", self.preset())?,
+        }
+        writeln!(f, "variant = \"{}\"", self.variant())?;
+        writeln!(f, "scale = {}", self.scale())?;
+        writeln!(f, "palette = \"{:06x}:{:06x}\"", palette.on, palette.off)?;
+        match self.seed {
+            Some(seed) => writeln!(f, "seed = {}", seed)?,
+            None => writeln!(f, "# seed is random")?,
+        }
+        writeln!(f, "mute = {}", self.mute())?;
+        writeln!(f, "speed = {}", self.speed())?;
+        match self.phosphor {
+            Some(PhosphorMode::Blend { decay }) => writeln!(f, "phosphor = \"blend:{}\"", decay),
+            Some(PhosphorMode::Persist) => writeln!(f, "phosphor = \"persist\""),
+            Some(PhosphorMode::Hold) => writeln!(f, "phosphor = \"hold\""),
+            None => writeln!(f, "# phosphor is off"),
+        }
+    }
+}
+
+/// `$XDG_CONFIG_HOME/chip8`, or `~/.config/chip8` when that is not set.
+pub fn config_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("chip8"))
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("config.toml"))
+}
+
+/// The bundled rom database, with the copy of the community database in
+/// `database/` next to the config file on top when there is one.
+pub fn database() -> Result<Database, ConfigError> {
+    let dir = match config_dir() {
+        Some(dir) => dir.join("database"),
+        None => return Ok(Database::bundled()),
+    };
+    if !dir.join("programs.json").exists() {
+        return Ok(Database::bundled());
+    }
+    let read = |name: &str| {
+        let path = dir.join(name);
+        fs::read_to_string(&path).map_err(|e| ConfigError::Io(path, e))
+    };
+    let (programs, hashes, platforms) = (read("programs.json")?, read("sha1-hashes.json")?, read("platforms.json")?);
+    let database = Database::from_json(&programs, &hashes, &platforms)
+        .map_err(|e| ConfigError::Database(dir, e))?;
+    Ok(Database::bundled().merge(database))
+}
+
+/// The settings and keymap for `rom` from a config file. A missing file at
+/// the default location is the same as an empty one.
+pub fn load(path: Option<&Path>, rom: Option<&str>) -> Result<(Settings, Keymap), ConfigError> {
+    let (path, required) = match path {
+        Some(path) => (path.to_owned(), true),
+        None => match config_path() {
+            Some(path) => (path, false),
+            None => return Ok((Settings::default(), Keymap::default())),
+        },
+    };
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !required => String::new(),
+        Err(e) => return Err(ConfigError::Io(path, e)),
+    };
+    let settings = Settings::from_toml(&text).map_err(|e| ConfigError::Toml(path.clone(), e))?;
+    let keymap = Keymap::from_toml(&text, rom).map_err(|e| ConfigError::Keymap(path, e))?;
+    Ok((settings, keymap))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_settings() {
+        let settings = Settings::from_toml(r#"
+            instructions-per-frame = 15
+            quirks = "vip"
+            palette = "amber"
+            speed = 8
+            phosphor = "persist"
+
+            [keymap]
+            1 = ["KeyW"]
+        "#).unwrap();
+        assert_eq!(settings.instructions_per_frame(), 15);
+        assert_eq!(settings.quirks(), Quirks::VIP);
+        assert_eq!(settings.palette(), "amber".parse().unwrap());
+        assert_eq!(settings.phosphor, Some(PhosphorMode::Persist));
+        assert_eq!(settings.scale(), DEFAULT_SCALE);
+        assert_eq!(settings.fast_forward(), Speed::FastForward(8));
+        assert_eq!(settings.slow_motion(), Speed::SlowMotion(8));
+        assert!(Settings::from_toml("quirks = \"fast\"").is_err());
+    }
+
+    #[test]
+    fn layers() {
+        let file = Settings {
+            instructions_per_frame: Some(15),
+            variant: Some(Variant::Schip),
+            mute: Some(true),
+            ..Settings::default()
+        };
+        let cli = Settings {
+            instructions_per_frame: Some(30),
+            ..Settings::default()
+        };
+        let settings = cli.or(file);
+        assert_eq!(settings.instructions_per_frame(), 30);
+        assert_eq!(settings.quirks(), Quirks::SCHIP);
+        assert!(settings.mute());
+    }
+
+    #[test]
+    fn variant_replaces_rom_quirks() {
+        let rom = Settings {
+            variant: Some(Variant::Chip8),
+            rom_quirks: Some(Quirks { wrap: false, ..Quirks::SCHIP }),
+            ..Settings::default()
+        };
+        let variant = Settings { variant: Some(Variant::Schip), ..Settings::default() };
+        assert_eq!(variant.clone().or(rom.clone()).quirks(), Quirks::SCHIP);
+        // An explicit preset still beats the variant's
+        let file = Settings { quirks: Some(QuirkPreset::Vip), ..Settings::default() };
+        assert_eq!(variant.or(rom.clone()).or(file.clone()).quirks(), Quirks::VIP);
+        assert_eq!(rom.clone().or(file).quirks(), rom.rom_quirks.unwrap());
+    }
+}
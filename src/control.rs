@@ -0,0 +1,166 @@
+use crate::emulator::Chip8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    Normal,
+    /// Run this many emulated frames per displayed frame, with sound muted.
+    FastForward(u32),
+    /// Run one emulated frame every this many displayed frames.
+    SlowMotion(u32),
+}
+
+/// Decides how many frames to emulate each time the frontend displays one.
+/// Timers tick once per emulated frame, so they follow the emulated frame
+/// count rather than wall-clock time at every speed.
+#[derive(Debug, Clone)]
+pub struct Controls {
+    speed: Speed,
+    paused: bool,
+    advance: bool,
+    ticks: u32,
+    mute: bool,
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Controls {
+            speed: Speed::Normal,
+            paused: false,
+            advance: false,
+            ticks: 0,
+            mute: false,
+        }
+    }
+}
+
+impl Controls {
+    pub fn new() -> Self {
+        Controls::default()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.advance = false;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.set_paused(!self.paused);
+    }
+
+    // Runs exactly one frame on the next call to `frames`, pausing first if needed
+    pub fn advance(&mut self) {
+        self.paused = true;
+        self.advance = true;
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+        self.ticks = 0;
+    }
+
+    // Switches to `speed`, or back to normal if already running at it
+    pub fn toggle_speed(&mut self, speed: Speed) {
+        if self.speed == speed {
+            self.set_speed(Speed::Normal);
+        } else {
+            self.set_speed(speed);
+        }
+    }
+
+    pub fn set_mute(&mut self, mute: bool) {
+        self.mute = mute;
+    }
+
+    pub fn muted(&self) -> bool {
+        self.mute || self.paused || matches!(self.speed, Speed::FastForward(_))
+    }
+
+    pub fn sound_active(&self, chip8: &Chip8) -> bool {
+        chip8.sound_active() && !self.muted()
+    }
+
+    // Number of frames to emulate for the current displayed frame
+    pub fn frames(&mut self) -> u32 {
+        if self.paused {
+            let advance = self.advance;
+            self.advance = false;
+            return advance as u32;
+        }
+        match self.speed {
+            Speed::Normal => 1,
+            Speed::FastForward(frames) => frames,
+            Speed::SlowMotion(ticks) => {
+                self.ticks += 1;
+                if self.ticks >= ticks {
+                    self.ticks = 0;
+                    1
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    // Emulates the frames for one displayed frame and returns how many ran
+    pub fn run(&mut self, chip8: &mut Chip8, instructions: usize) -> u32 {
+        let frames = self.frames();
+        for _ in 0..frames {
+            chip8.run_frame(instructions);
+        }
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_and_advance() {
+        let mut controls = Controls::new();
+        assert_eq!(controls.frames(), 1);
+        controls.toggle_pause();
+        assert_eq!(controls.frames(), 0);
+        controls.advance();
+        assert_eq!(controls.frames(), 1);
+        assert_eq!(controls.frames(), 0);
+        controls.toggle_pause();
+        assert_eq!(controls.frames(), 1);
+    }
+
+    #[test]
+    fn speeds() {
+        let mut controls = Controls::new();
+        controls.toggle_speed(Speed::FastForward(4));
+        assert_eq!(controls.frames(), 4);
+        assert!(controls.muted());
+        controls.toggle_speed(Speed::SlowMotion(3));
+        let frames: Vec<u32> = (0..6).map(|_| controls.frames()).collect();
+        assert_eq!(frames, [0, 0, 1, 0, 0, 1]);
+        controls.toggle_speed(Speed::SlowMotion(3));
+        assert_eq!(controls.speed(), Speed::Normal);
+    }
+
+    #[test]
+    fn timers_follow_emulated_frames() {
+        // LD V0, 0x3c; LD DT, V0; JP 0x204
+        let mut chip8 = Chip8::from_rom(&[0x60, 0x3c, 0xf0, 0x15, 0x12, 0x04]);
+        let mut controls = Controls::new();
+        controls.run(&mut chip8, 10);
+        assert_eq!(chip8.delay_timer(), 0x3b);
+        controls.set_speed(Speed::FastForward(8));
+        controls.run(&mut chip8, 10);
+        assert_eq!(chip8.delay_timer(), 0x33);
+        controls.set_speed(Speed::SlowMotion(2));
+        assert_eq!(controls.run(&mut chip8, 10), 0);
+        assert_eq!(chip8.delay_timer(), 0x33);
+    }
+}
@@ -0,0 +1,220 @@
+use std::fmt::Write as _;
+use std::ops::{Range, RangeInclusive};
+
+use crate::analysis::{Analysis, START};
+use crate::disasm;
+use crate::emulator::{Chip8, Observer, MEMORY_SIZE};
+
+const FETCHED: u8 = 1;
+const READ: u8 = 2;
+const WRITTEN: u8 = 4;
+// First byte of a fetched opcode
+const OPCODE: u8 = 8;
+
+/// Marks every address a run fetches as an opcode, and every address it
+/// reads or writes as data. Instructions that fail mark nothing.
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    marks: Vec<u8>,
+    // The instruction about to run and the data it accesses, marked once it succeeds
+    pending: Option<(usize, Range<usize>, u8)>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage { marks: vec![0; MEMORY_SIZE], pending: None }
+    }
+
+    fn mark(&mut self, addr: usize, mark: u8) {
+        if let Some(marks) = self.marks.get_mut(addr) {
+            *marks |= mark;
+        }
+    }
+
+    fn has(&self, addr: u16, mark: u8) -> bool {
+        self.marks.get(addr as usize).is_some_and(|marks| marks & mark != 0)
+    }
+
+    /// Whether an instruction started at `addr`.
+    pub fn executed(&self, addr: u16) -> bool {
+        self.has(addr, OPCODE)
+    }
+
+    /// Whether `addr` was fetched as part of an opcode.
+    pub fn fetched(&self, addr: u16) -> bool {
+        self.has(addr, FETCHED)
+    }
+
+    pub fn read(&self, addr: u16) -> bool {
+        self.has(addr, READ)
+    }
+
+    pub fn written(&self, addr: u16) -> bool {
+        self.has(addr, WRITTEN)
+    }
+
+    // Runs of addresses with `mark`
+    fn ranges(&self, mark: u8) -> Vec<RangeInclusive<u16>> {
+        let mut ranges: Vec<RangeInclusive<u16>> = Vec::new();
+        for addr in (0..self.marks.len() as u16).filter(|&addr| self.has(addr, mark)) {
+            match ranges.last_mut() {
+                Some(range) if *range.end() + 1 == addr => *range = *range.start()..=addr,
+                _ => ranges.push(addr..=addr),
+            }
+        }
+        ranges
+    }
+
+    /// The covered addresses as inclusive `[first, last]` ranges:
+    ///
+    /// ```json
+    /// {"fetched": [[512, 565]], "read": [[576, 576]], "written": []}
+    /// ```
+    pub fn to_json(&self) -> String {
+        let ranges = |mark| -> Vec<[u16; 2]> {
+            self.ranges(mark).into_iter().map(|range| [*range.start(), *range.end()]).collect()
+        };
+        serde_json::json!({
+            "fetched": ranges(FETCHED),
+            "read": ranges(READ),
+            "written": ranges(WRITTEN),
+        })
+        .to_string()
+    }
+
+    /// A disassembly of `rom` with the coverage of each line in front: `X`
+    /// for executed, `R` for read and `W` for written. Code the run never
+    /// reached is found by static analysis.
+    ///
+    /// ```text
+    /// X-- 22e: e4a1  SKNP V4
+    /// --- 236: 0000  SYS 0x000
+    /// -R- 240: 80
+    /// ```
+    pub fn listing(&self, rom: &[u8]) -> String {
+        let analysis = Analysis::analyze(rom);
+        // The loader drops the part of a rom past the end of memory
+        let rom = &rom[..rom.len().min(MEMORY_SIZE - START as usize)];
+        let end = START as usize + rom.len();
+        let executed = (START..end as u16).filter(|&addr| self.executed(addr)).count();
+        let instructions = analysis.instructions().len();
+        let mut listing = format!("; {} of {} reachable instructions executed\n", executed, instructions);
+        let flags = |addr: u16| {
+            [(self.executed(addr), 'X'), (self.read(addr), 'R'), (self.written(addr), 'W')]
+                .iter()
+                .map(|&(set, flag)| if set { flag } else { '-' })
+                .collect::<String>()
+        };
+        let mut addr = START as usize;
+        while addr < end {
+            let byte = rom[addr - START as usize];
+            let is_code = self.executed(addr as u16) || analysis.instructions().contains_key(&(addr as u16));
+            if is_code && addr + 1 < end {
+                let op = ((byte as u16) << 8) | rom[addr + 1 - START as usize] as u16;
+                let line = format!("{} {:03x}: {:04x}  {}", flags(addr as u16), addr, op, disasm::disassemble(op));
+                writeln!(listing, "{}", line).unwrap();
+                addr += 2;
+            } else {
+                writeln!(listing, "{} {:03x}: {:02x}", flags(addr as u16), addr, byte).unwrap();
+                addr += 1;
+            }
+        }
+        listing
+    }
+}
+
+impl Observer for Coverage {
+    fn before(&mut self, chip8: &Chip8) {
+        let (pc, op) = (chip8.pc(), chip8.opcode());
+        let (i, x) = (chip8.index() as usize, ((op >> 8) & 0x0f) as usize);
+        let (range, mark) = match (op & 0xf000, op & 0x00ff) {
+            (0xd000, _) => (i..i + (op & 0x000f) as usize, READ),
+            (0xf000, 0x33) => (i..i + 3, WRITTEN),
+            (0xf000, 0x55) => (i..i + x + 1, WRITTEN),
+            (0xf000, 0x65) => (i..i + x + 1, READ),
+            _ => (0..0, 0),
+        };
+        self.pending = Some((pc, range, mark));
+    }
+
+    fn after(&mut self, chip8: &Chip8) {
+        let (pc, range, mark) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return,
+        };
+        // A failed instruction accessed nothing
+        if chip8.error().is_some() {
+            return;
+        }
+        self.mark(pc, FETCHED | OPCODE);
+        self.mark(pc + 1, FETCHED);
+        for addr in range {
+            self.mark(addr, mark);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 12] = [
+        0xa2, 0x0a, // 200: LD I, 0x20a
+        0xd0, 0x11, // 202: DRW V0, V1, 1
+        0xf0, 0x33, // 204: LD B, V0
+        0x12, 0x06, // 206: JP 0x206
+        0x12, 0x00, // 208: JP 0x200 (never runs)
+        0x80, 0x00, // 20a: sprite
+    ];
+
+    #[test]
+    fn marks() {
+        let mut chip8 = Chip8::from_rom(&ROM);
+        let mut coverage = Coverage::new();
+        chip8.run_frame_observed(10, &mut coverage);
+        assert!(coverage.executed(0x206));
+        assert!(coverage.fetched(0x207) && !coverage.executed(0x207));
+        assert!(!coverage.fetched(0x208));
+        assert!(coverage.read(0x20a) && !coverage.read(0x20b));
+        assert!(coverage.written(0x20c));
+        assert_eq!(coverage.to_json(), r#"{"fetched":[[512,519]],"read":[[522,522]],"written":[[522,524]]}"#);
+    }
+
+    #[test]
+    fn listing() {
+        let mut chip8 = Chip8::from_rom(&ROM);
+        let mut coverage = Coverage::new();
+        chip8.run_frame_observed(10, &mut coverage);
+        let listing = coverage.listing(&ROM);
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[0], "; 4 of 4 reachable instructions executed");
+        assert_eq!(lines[1], "X-- 200: a20a  LD I, 0x20a");
+        assert_eq!(lines[5], "--- 208: 12");
+        assert_eq!(lines[7], "-RW 20a: 80");
+    }
+
+    #[test]
+    fn listing_stops_at_the_end_of_memory() {
+        let rom = [0x70, 0x01].repeat(0x1000);
+        let listing = Coverage::new().listing(&rom);
+        assert!(listing.starts_with("; 0 of 1792 reachable instructions executed\n"));
+        assert!(listing.ends_with("--- ffe: 7001  ADD V0, 0x01\n"));
+    }
+
+    #[test]
+    fn failed_instructions_mark_nothing() {
+        // LD [I], VF past the end of memory
+        let mut chip8 = Chip8::builder().program(&[0xff, 0x55]).index(0xff8).build();
+        let mut coverage = Coverage::new();
+        chip8.run_frame_observed(1, &mut coverage);
+        assert!(chip8.error().is_some());
+        assert!(!coverage.executed(0x200));
+        assert!(!(0xff8..0x1000).any(|addr| coverage.written(addr)));
+    }
+}
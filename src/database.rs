@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::config::Settings;
+use crate::display::Palette;
+use crate::emulator::Chip8;
+use crate::keymap::Keymap;
+use crate::quirks::{LoadStore, Quirks, Variant};
+
+const PROGRAMS: &str = include_str!("../database/programs.json");
+const HASHES: &str = include_str!("../database/sha1-hashes.json");
+const PLATFORMS: &str = include_str!("../database/platforms.json");
+
+#[derive(Debug, Deserialize)]
+struct Program {
+    title: String,
+    roms: HashMap<String, Rom>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rom {
+    #[serde(default)]
+    platforms: Vec<String>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, PlatformQuirks>,
+    tickrate: Option<usize>,
+    #[serde(default)]
+    keys: HashMap<String, u8>,
+    colors: Option<Colors>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Platform {
+    id: String,
+    quirks: PlatformQuirks,
+}
+
+// Unset quirks keep the value of the platform
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatformQuirks {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    vblank: Option<bool>,
+    logic: Option<bool>,
+}
+
+impl PlatformQuirks {
+    fn apply(&self, quirks: &mut Quirks) {
+        if let Some(shift) = self.shift {
+            quirks.shift = shift;
+        }
+        match (self.memory_leave_i_unchanged, self.memory_increment_by_x) {
+            (Some(true), _) => quirks.load_store = LoadStore::LeaveI,
+            (_, Some(true)) => quirks.load_store = LoadStore::IncrementByX,
+            // Turning off the current one goes back to the VIP behaviour
+            (Some(false), _) if quirks.load_store == LoadStore::LeaveI => quirks.load_store = LoadStore::Increment,
+            (_, Some(false)) if quirks.load_store == LoadStore::IncrementByX => quirks.load_store = LoadStore::Increment,
+            _ => (),
+        }
+        if let Some(wrap) = self.wrap {
+            quirks.wrap = wrap;
+        }
+        if let Some(jump) = self.jump {
+            quirks.jump = jump;
+        }
+        if let Some(vblank) = self.vblank {
+            quirks.display_wait = vblank;
+        }
+        if let Some(logic) = self.logic {
+            quirks.logic = logic;
+        }
+    }
+}
+
+// Game actions in the database, and the keys that play them on top of the keymap
+const ACTIONS: [(&str, &str); 6] = [
+    ("up", "ArrowUp"),
+    ("down", "ArrowDown"),
+    ("left", "ArrowLeft"),
+    ("right", "ArrowRight"),
+    ("a", "Space"),
+    ("b", "Enter"),
+];
+
+/// What the database knows about one rom.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub title: String,
+    pub platform: String,
+    pub quirks: Quirks,
+    /// Instructions per frame.
+    pub tickrate: Option<usize>,
+    /// Hex key for each game action (`up`, `a`, ...).
+    pub keys: Vec<(String, u8)>,
+    pub palette: Option<Palette>,
+}
+
+impl Entry {
+    pub fn variant(&self) -> Variant {
+        match self.platform.as_str() {
+            "chip48" | "superchip1" | "superchip" | "megachip8" => Variant::Schip,
+            _ => Variant::Chip8,
+        }
+    }
+
+    // A settings layer to put between the command line and the config file
+    pub fn settings(&self) -> Settings {
+        Settings {
+            instructions_per_frame: self.tickrate,
+            variant: Some(self.variant()),
+            palette: self.palette,
+            rom_quirks: Some(self.quirks),
+            ..Settings::default()
+        }
+    }
+
+    // Binds the arrow keys, space and enter to the game's actions, unless the keymap already uses them
+    pub fn bind_keys(&self, keymap: &mut Keymap) {
+        for (action, hex) in &self.keys {
+            if let Some(&(_, code)) = ACTIONS.iter().find(|(name, _)| name == action) {
+                if keymap.hex_key(code).is_none() {
+                    keymap.bind(*hex, code);
+                }
+            }
+        }
+    }
+
+    pub fn apply(&self, chip8: &mut Chip8) {
+        chip8.set_variant(self.variant());
+        chip8.set_quirks(self.quirks);
+    }
+}
+
+/// Rom settings keyed by SHA-1, in the format of the community CHIP-8
+/// database (https://github.com/chip-8/chip-8-database).
+#[derive(Debug)]
+pub struct Database {
+    programs: Vec<Program>,
+    hashes: HashMap<String, usize>,
+    platforms: Vec<Platform>,
+}
+
+impl Database {
+    /// The database built into the emulator, from `database/`.
+    pub fn bundled() -> Self {
+        Database::from_json(PROGRAMS, HASHES, PLATFORMS).expect("Invalid bundled rom database")
+    }
+
+    pub fn from_json(programs: &str, hashes: &str, platforms: &str) -> serde_json::Result<Self> {
+        Ok(Database {
+            programs: serde_json::from_str(programs)?,
+            hashes: serde_json::from_str(hashes)?,
+            platforms: serde_json::from_str(platforms)?,
+        })
+    }
+
+    /// This database with the entries of `other` on top, e.g. the bundled
+    /// database with a newer copy of the community one.
+    pub fn merge(mut self, other: Database) -> Self {
+        let offset = self.programs.len();
+        self.programs.extend(other.programs);
+        self.hashes.extend(other.hashes.into_iter().map(|(hash, index)| (hash, index + offset)));
+        // Lookups take the first platform with an id
+        self.platforms = other.platforms.into_iter().chain(self.platforms).collect();
+        self
+    }
+
+    pub fn sha1(rom: &[u8]) -> String {
+        sha1_smol::Sha1::from(rom).digest().to_string()
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<Entry> {
+        let hash = Database::sha1(rom);
+        let program = self.programs.get(*self.hashes.get(&hash)?)?;
+        let rom = program.roms.get(&hash)?;
+        // The first platform is the one the rom was made for
+        let platform = rom.platforms.first()?;
+        let mut quirks = Quirks::default();
+        if let Some(platform) = self.platforms.iter().find(|other| &other.id == platform) {
+            platform.quirks.apply(&mut quirks);
+        }
+        if let Some(overrides) = rom.quirky_platforms.get(platform) {
+            overrides.apply(&mut quirks);
+        }
+        // The database has no stack sizes; the interpreters on the VIP hold 12 calls
+        if matches!(platform.as_str(), "originalChip8" | "hybridVIP" | "chip8x") {
+            quirks.stack_depth = 12;
+        }
+        let mut keys: Vec<(String, u8)> = rom.keys
+            .iter()
+            .filter(|&(_, &hex)| hex < 16)
+            .map(|(action, &hex)| (action.clone(), hex))
+            .collect();
+        keys.sort();
+        let palette = rom.colors.as_ref().and_then(|colors| match colors.pixels.as_slice() {
+            [off, on, ..] => format!("{}:{}", on.trim_start_matches('#'), off.trim_start_matches('#')).parse().ok(),
+            _ => None,
+        });
+        Some(Entry {
+            title: program.title.clone(),
+            platform: platform.clone(),
+            quirks,
+            tickrate: rom.tickrate,
+            keys,
+            palette,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAMS: &str = r##"[{
+        "title": "Pong",
+        "roms": {
+            "HASH": {
+                "platforms": ["originalChip8", "modernChip8"],
+                "quirkyPlatforms": { "originalChip8": { "vblank": false } },
+                "tickrate": 15,
+                "keys": { "up": 1, "down": 4, "player2Up": 12 },
+                "colors": { "pixels": ["#0f380f", "#9bbc0f"] }
+            }
+        }
+    }]"##;
+
+    #[test]
+    fn bundled() {
+        let rom = std::fs::read("tests/roms/draw.ch8").unwrap();
+        let entry = Database::bundled().lookup(&rom).unwrap();
+        assert_eq!(entry.title, "Drawing self-test");
+        assert_eq!(entry.quirks, Quirks::COWGOD);
+        assert_eq!(Database::bundled().lookup(&[0x12, 0x00]), None);
+    }
+
+    #[test]
+    fn lookup() {
+        let hash = Database::sha1(b"pong");
+        let hashes = format!("{{\"{}\": 0}}", hash);
+        let database = Database::from_json(&PROGRAMS.replace("HASH", &hash), &hashes, PLATFORMS).unwrap();
+        let entry = database.lookup(b"pong").unwrap();
+        assert_eq!(entry.platform, "originalChip8");
+        assert_eq!(entry.variant(), Variant::Chip8);
+        assert_eq!(entry.quirks, Quirks { display_wait: false, ..Quirks::VIP });
+        assert_eq!(entry.tickrate, Some(15));
+        assert_eq!(entry.palette, Some("9bbc0f:0f380f".parse().unwrap()));
+
+        let mut keymap = Keymap::default();
+        entry.bind_keys(&mut keymap);
+        assert_eq!(keymap.hex_key("ArrowUp"), Some(0x1));
+        assert_eq!(keymap.hex_key("ArrowDown"), Some(0x4));
+        assert_eq!(keymap.hex_key("Digit1"), Some(0x1));
+    }
+
+    #[test]
+    fn merge() {
+        let hash = Database::sha1(b"pong");
+        let hashes = format!("{{\"{}\": 0}}", hash);
+        let platforms = r#"[{ "id": "originalChip8", "quirks": { "vblank": false } }]"#;
+        let other = Database::from_json(&PROGRAMS.replace("HASH", &hash), &hashes, platforms).unwrap();
+        let database = Database::bundled().merge(other);
+        assert_eq!(database.lookup(b"pong").unwrap().quirks, Quirks { stack_depth: 12, ..Quirks::COWGOD });
+        let rom = std::fs::read("tests/roms/draw.ch8").unwrap();
+        assert_eq!(database.lookup(&rom).unwrap().title, "Drawing self-test");
+    }
+}
@@ -0,0 +1,99 @@
+/// The mnemonic of an opcode, in the syntax of Cowgod's reference. Opcodes
+/// the emulator doesn't run come out as data (`DW 0x....`).
+pub fn disassemble(op: u16) -> String {
+    let x = (op >> 8) & 0x0f;
+    let y = (op >> 4) & 0x0f;
+    let n = op & 0x000f;
+    let kk = op & 0x00ff;
+    let addr = op & 0x0fff;
+    match (op >> 12, x, y, n) {
+        (0x0, 0x0, 0xe, 0x0) => "CLS".to_owned(),
+        (0x0, 0x0, 0xe, 0xe) => "RET".to_owned(),
+        (0x0, ..) => format!("SYS 0x{:03x}", addr),
+        (0x1, ..) => format!("JP 0x{:03x}", addr),
+        (0x2, ..) => format!("CALL 0x{:03x}", addr),
+        (0x3, ..) => format!("SE V{:X}, 0x{:02x}", x, kk),
+        (0x4, ..) => format!("SNE V{:X}, 0x{:02x}", x, kk),
+        (0x5, _, _, 0x0) => format!("SE V{:X}, V{:X}", x, y),
+        (0x6, ..) => format!("LD V{:X}, 0x{:02x}", x, kk),
+        (0x7, ..) => format!("ADD V{:X}, 0x{:02x}", x, kk),
+        (0x8, _, _, 0x0) => format!("LD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x1) => format!("OR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x2) => format!("AND V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x3) => format!("XOR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x4) => format!("ADD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x5) => format!("SUB V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x6) => format!("SHR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x7) => format!("SUBN V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0xe) => format!("SHL V{:X}, V{:X}", x, y),
+        (0x9, _, _, 0x0) => format!("SNE V{:X}, V{:X}", x, y),
+        (0xa, ..) => format!("LD I, 0x{:03x}", addr),
+        (0xb, ..) => format!("JP V0, 0x{:03x}", addr),
+        (0xc, ..) => format!("RND V{:X}, 0x{:02x}", x, kk),
+        (0xd, ..) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        (0xe, _, 0x9, 0xe) => format!("SKP V{:X}", x),
+        (0xe, _, 0xa, 0x1) => format!("SKNP V{:X}", x),
+        (0xf, _, 0x0, 0x7) => format!("LD V{:X}, DT", x),
+        (0xf, _, 0x0, 0xa) => format!("LD V{:X}, K", x),
+        (0xf, _, 0x1, 0x5) => format!("LD DT, V{:X}", x),
+        (0xf, _, 0x1, 0x8) => format!("LD ST, V{:X}", x),
+        (0xf, _, 0x1, 0xe) => format!("ADD I, V{:X}", x),
+        (0xf, _, 0x2, 0x9) => format!("LD F, V{:X}", x),
+        (0xf, _, 0x3, 0x3) => format!("LD B, V{:X}", x),
+        (0xf, _, 0x5, 0x5) => format!("LD [I], V{:X}", x),
+        (0xf, _, 0x6, 0x5) => format!("LD V{:X}, [I]", x),
+        _ => format!("DW 0x{:04x}", op),
+    }
+}
+
+/// The instruction name alone, e.g. `DRW` or `LD`.
+pub fn kind(op: u16) -> &'static str {
+    match (op >> 12, op & 0x000f, op & 0x00ff) {
+        (0x0, _, 0xe0) if op & 0x0f00 == 0 => "CLS",
+        (0x0, _, 0xee) if op & 0x0f00 == 0 => "RET",
+        (0x0, ..) => "SYS",
+        (0x1, ..) | (0xb, ..) => "JP",
+        (0x2, ..) => "CALL",
+        (0x3, ..) | (0x5, 0x0, _) => "SE",
+        (0x4, ..) | (0x9, 0x0, _) => "SNE",
+        (0x6, ..) | (0x8, 0x0, _) | (0xa, ..) => "LD",
+        (0x7, ..) | (0x8, 0x4, _) => "ADD",
+        (0x8, 0x1, _) => "OR",
+        (0x8, 0x2, _) => "AND",
+        (0x8, 0x3, _) => "XOR",
+        (0x8, 0x5, _) => "SUB",
+        (0x8, 0x6, _) => "SHR",
+        (0x8, 0x7, _) => "SUBN",
+        (0x8, 0xe, _) => "SHL",
+        (0xc, ..) => "RND",
+        (0xd, ..) => "DRW",
+        (0xe, _, 0x9e) => "SKP",
+        (0xe, _, 0xa1) => "SKNP",
+        (0xf, _, 0x1e) => "ADD",
+        (0xf, _, 0x07) | (0xf, _, 0x0a) | (0xf, _, 0x15) | (0xf, _, 0x18) | (0xf, _, 0x29) | (0xf, _, 0x33)
+        | (0xf, _, 0x55) | (0xf, _, 0x65) => "LD",
+        _ => "DW",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(disassemble(0x00e0), "CLS");
+        assert_eq!(disassemble(0x22f6), "CALL 0x2f6");
+        assert_eq!(disassemble(0x6a02), "LD VA, 0x02");
+        assert_eq!(disassemble(0x8ab4), "ADD VA, VB");
+        assert_eq!(disassemble(0xd015), "DRW V0, V1, 5");
+        assert_eq!(disassemble(0xf265), "LD V2, [I]");
+        assert_eq!(disassemble(0x5121), "DW 0x5121");
+        assert_eq!(kind(0xf265), "LD");
+        assert_eq!(kind(0xffff), "DW");
+        // Every opcode is named after its instruction
+        for op in 0..=0xffff {
+            assert_eq!(disassemble(op).split(' ').next(), Some(kind(op)), "{:04x}", op);
+        }
+    }
+}
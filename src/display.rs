@@ -0,0 +1,185 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::emulator::{WIDTH, HEIGHT};
+
+pub const ON: u32 = 0x00FFFFFF;
+pub const OFF: u32 = 0x00000000;
+
+/// Colours for lit and dark pixels as 0x00RRGGBB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub on: u32,
+    pub off: u32,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette { on: ON, off: OFF }
+    }
+}
+
+impl Palette {
+    // Colour of a pixel `intensity` (0.0 - 1.0) of the way from off to on
+    pub fn mix(&self, intensity: f32) -> u32 {
+        let intensity = intensity.clamp(0.0, 1.0);
+        [16, 8, 0].iter().fold(0, |color, &shift| {
+            let off = ((self.off >> shift) & 0xff) as f32;
+            let on = ((self.on >> shift) & 0xff) as f32;
+            color | (((off + (on - off) * intensity).round() as u32) << shift)
+        })
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    // A named palette or "<on>:<off>" as hex colours, e.g. "ffb000:2a1b00"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (on, off) = match s {
+            "classic" => (ON, OFF),
+            "amber" => (0xffb000, 0x2a1b00),
+            "green" => (0x33ff66, 0x0a1f0f),
+            "lcd" => (0x0f380f, 0x9bbc0f),
+            _ => {
+                let color = |hex: &str| match u32::from_str_radix(hex.trim_start_matches('#'), 16) {
+                    Ok(color) if color <= 0xffffff => Ok(color),
+                    _ => Err(format!("Not a colour: {}", hex)),
+                };
+                let mut colors = s.splitn(2, ':');
+                match (colors.next(), colors.next()) {
+                    (Some(on), Some(off)) => (color(on)?, color(off)?),
+                    _ => return Err(format!("Unknown palette: {} (expected classic, amber, green, lcd or <on>:<off>)", s)),
+                }
+            }
+        };
+        Ok(Palette { on, off })
+    }
+}
+
+/// The 64x32 monochrome display, one `u64` per row with the leftmost pixel
+/// in the most significant bit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Screen {
+    rows: [u64; HEIGHT],
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Screen { rows: [0; HEIGHT] }
+    }
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Screen::default()
+    }
+
+    pub fn clear(&mut self) {
+        self.rows = [0; HEIGHT];
+    }
+
+    pub fn from_rows(rows: [u64; HEIGHT]) -> Self {
+        Screen { rows }
+    }
+
+    pub fn rows(&self) -> &[u64; HEIGHT] {
+        &self.rows
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        (self.rows[y % HEIGHT] >> (WIDTH - 1 - x % WIDTH)) & 0x1 == 1
+    }
+
+    // XORs one 8 pixel sprite row onto the screen, wrapping around the edges.
+    // Returns true if a lit pixel was turned off.
+    pub fn draw_row(&mut self, x: usize, y: usize, byte: u8) -> bool {
+        let sprite = ((byte as u64) << (WIDTH - 8)).rotate_right((x % WIDTH) as u32);
+        let row = &mut self.rows[y % HEIGHT];
+        let collision = *row & sprite != 0;
+        *row ^= sprite;
+        collision
+    }
+
+    // Like `draw_row`, but pixels past the right edge are dropped
+    pub fn clip_row(&mut self, x: usize, y: usize, byte: u8) -> bool {
+        let sprite = ((byte as u64) << (WIDTH - 8)) >> (x % WIDTH);
+        let row = &mut self.rows[y % HEIGHT];
+        let collision = *row & sprite != 0;
+        *row ^= sprite;
+        collision
+    }
+
+    pub fn to_buffer(&self, buffer: &mut [u32; WIDTH * HEIGHT], palette: &Palette) {
+        for (y, row) in self.rows.iter().enumerate() {
+            for x in 0..WIDTH {
+                let lit = (row >> (WIDTH - 1 - x)) & 0x1 == 1;
+                buffer[x + y * WIDTH] = if lit { palette.on } else { palette.off };
+            }
+        }
+    }
+}
+
+// One line per row, '#' for lit and '.' for dark pixels
+impl fmt::Display for Screen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                write!(f, "{}", if self.pixel(x, y) { '#' } else { '.' })?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draw_row_wraps() {
+        let mut screen = Screen::new();
+        assert!(!screen.draw_row(62, 33, 0xf0));
+        assert!(screen.pixel(62, 1));
+        assert!(screen.pixel(63, 1));
+        assert!(screen.pixel(0, 1));
+        assert!(screen.pixel(1, 1));
+        assert!(!screen.pixel(2, 1));
+    }
+
+    #[test]
+    fn draw_row_collision() {
+        let mut screen = Screen::new();
+        screen.draw_row(0, 0, 0x80);
+        assert!(!screen.draw_row(1, 0, 0x80));
+        assert!(screen.draw_row(0, 0, 0x80));
+        assert!(!screen.pixel(0, 0));
+        assert!(screen.pixel(1, 0));
+    }
+
+    #[test]
+    fn clip_row() {
+        let mut screen = Screen::new();
+        assert!(!screen.clip_row(62, 1, 0xff));
+        assert_eq!(screen.rows()[1], 0x3);
+    }
+
+    #[test]
+    fn to_buffer() {
+        let mut screen = Screen::new();
+        screen.draw_row(4, 2, 0x80);
+        let mut buffer = [OFF; WIDTH * HEIGHT];
+        screen.to_buffer(&mut buffer, &"lcd".parse().unwrap());
+        assert_eq!(buffer[4 + 2 * WIDTH], 0x0f380f);
+        assert_eq!(buffer.iter().filter(|&&pixel| pixel == 0x9bbc0f).count(), WIDTH * HEIGHT - 1);
+    }
+
+    #[test]
+    fn palette() {
+        assert_eq!("102030:405060".parse(), Ok(Palette { on: 0x102030, off: 0x405060 }));
+        assert!("1020304:405060".parse::<Palette>().is_err());
+        assert!("purple".parse::<Palette>().is_err());
+        assert_eq!(Palette::default().mix(0.5), 0x808080);
+    }
+}
@@ -1,7 +1,9 @@
 use std::io::prelude::*;
+use std::fmt;
 use std::fs::File;
-use rand::rngs::ThreadRng;
-use rand::Rng;
+
+use crate::display::Screen;
+use crate::quirks::{LoadStore, Quirks, Variant};
 
 pub static FONT_SET: [u8; 80] = [
     0xf0, 0x90, 0x90, 0x90, 0xf0,
@@ -24,114 +26,652 @@ pub static FONT_SET: [u8; 80] = [
 
 #[derive(Debug)]
 pub enum Action<'a> { 
-    DisplayScreen(&'a [u32; WIDTH * HEIGHT]),
+    DisplayScreen(&'a Screen),
     WaitForKeyPress,
 }
 
-#[derive(Debug)]
-pub enum Event {
-    KeyPress(u8),
-    WaitingKeyPress(u8),
+/// Sees the machine before and after every instruction run by
+/// `Chip8::run_frame_observed`, e.g. to trace or profile a rom.
+pub trait Observer {
+    fn before(&mut self, _chip8: &Chip8) {}
+    fn after(&mut self, _chip8: &Chip8) {}
+    /// Called once the timers have ticked at the end of a frame.
+    fn end_frame(&mut self, _chip8: &Chip8) {}
+}
+
+impl Observer for () {}
+
+impl<T: Observer> Observer for Option<T> {
+    fn before(&mut self, chip8: &Chip8) {
+        if let Some(observer) = self {
+            observer.before(chip8);
+        }
+    }
+
+    fn after(&mut self, chip8: &Chip8) {
+        if let Some(observer) = self {
+            observer.after(chip8);
+        }
+    }
+
+    fn end_frame(&mut self, chip8: &Chip8) {
+        if let Some(observer) = self {
+            observer.end_frame(chip8);
+        }
+    }
+}
+
+// Several observers at once, e.g. `(trace, (profiler, coverage))`
+impl<A: Observer, B: Observer> Observer for (A, B) {
+    fn before(&mut self, chip8: &Chip8) {
+        self.0.before(chip8);
+        self.1.before(chip8);
+    }
+
+    fn after(&mut self, chip8: &Chip8) {
+        self.0.after(chip8);
+        self.1.after(chip8);
+    }
+
+    fn end_frame(&mut self, chip8: &Chip8) {
+        self.0.end_frame(chip8);
+        self.1.end_frame(chip8);
+    }
+}
+
+// SplitMix64. Its whole state is one number, so save states can hold it.
+#[derive(Debug, Clone)]
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// Not a save state of this emulator.
+    Format,
+    /// Saved by a version of the emulator this one can't read.
+    Version(u8),
+    /// Shorter than a save state.
+    Truncated,
+    /// A saved value is out of range.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::Format => write!(f, "Not a save state"),
+            StateError::Version(version) => write!(f, "Unsupported save state version {}", version),
+            StateError::Truncated => write!(f, "Save state is cut short"),
+            StateError::Invalid(what) => write!(f, "Save state has an invalid {}", what),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Why the machine stopped: a program did something the hardware can't.
+/// `pc` is the address of the instruction at fault.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CpuError {
+    UnknownOpcode { pc: u16, opcode: u16 },
+    /// A `CALL` nested deeper than the quirks' `stack_depth`.
+    StackOverflow { pc: u16, depth: u8 },
+    /// A `RET` with no call to return from.
+    StackUnderflow { pc: u16 },
+    /// An access to `addr`, outside of memory.
+    Memory { pc: u16, addr: usize },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuError::UnknownOpcode { pc, opcode } => write!(f, "Unknown opcode {:04x} at {:03x}", opcode, pc),
+            CpuError::StackOverflow { pc, depth } => write!(f, "Stack overflow at {:03x}: more than {} nested calls", pc, depth),
+            CpuError::StackUnderflow { pc } => write!(f, "Return without a call at {:03x}", pc),
+            CpuError::Memory { pc, addr } => write!(f, "Access outside of memory at {:03x}: {:#x}", pc, addr),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+const STATE_MAGIC: &[u8; 4] = b"C8ST";
+const STATE_VERSION: u8 = 1;
+
+// The most calls any variant nests; `Quirks::stack_depth` can only lower it
+const MAX_STACK_DEPTH: usize = 16;
+
+/// One call on the stack, as the debugger shows it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Call {
+    /// Address of the `CALL` instruction.
+    pub from: u16,
+    /// Address it called.
+    pub to: u16,
+    /// Where the matching `RET` goes.
+    pub return_to: u16,
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:03x} called {:03x}", self.from, self.to)
+    }
+}
+
+// Reads a save state front to back
+struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("flag")),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
 }
 
 pub const WIDTH: usize = 64;
 pub const HEIGHT: usize = 32;
+pub const INSTRUCTIONS_PER_FRAME: usize = 10;
+/// Bytes of memory, so addresses run from 0x000 to 0xfff.
+pub const MEMORY_SIZE: usize = 0x1000;
 
 #[allow(non_snake_case)]
+#[derive(Clone)]
 pub struct Chip8 { 
-    screen: [u32; WIDTH * HEIGHT],
+    screen: Screen,
     v: [u8; 16],
     I: u16,
     pc: usize,
-    sp: usize,
-    memory: [u8; 0xfff], // 4k memory
-    stack: [usize; 16],
+    sp: usize, // calls on the stack
+    memory: [u8; MEMORY_SIZE], // 4k memory
+    stack: [u16; MAX_STACK_DEPTH],
     DT: u8, 
     ST: u8, 
-    rng: ThreadRng,
+    keys: u16, // one bit per hex key
+    waiting_key: Option<u8>,
+    quirks: Quirks,
+    variant: Variant,
+    rng: Rng,
+    // Set once an instruction fails; the machine stays stopped
+    error: Option<CpuError>,
 }
 
 impl Chip8 {
     pub fn new(path: &str) -> Self {
-        let mut memory = [0; 0xfff];
+        let mut rom = Vec::new();
         let mut f = File::open(path).unwrap();
-        f.read_exact(&mut memory[0x200..]);
-        for i in 0..80 {
-            memory[i] = FONT_SET[i];
-        }
+        f.read_to_end(&mut rom).unwrap();
+        Chip8::from_rom(&rom)
+    }
+
+    /// A machine set up piece by piece, for tests and tools.
+    pub fn builder() -> Chip8Builder {
+        Chip8Builder::new()
+    }
+
+    pub fn from_rom(rom: &[u8]) -> Self {
+        let mut memory = [0; MEMORY_SIZE];
+        let len = rom.len().min(memory.len() - 0x200);
+        memory[0x200..0x200 + len].copy_from_slice(&rom[..len]);
+        memory[..80].copy_from_slice(&FONT_SET);
         Chip8 {
-            screen: [0; WIDTH * HEIGHT],
+            screen: Screen::new(),
             v: [0; 16],
             I: 0,
             pc: 0x200, // Programs start at 0x200 (512)
             sp: 0,
-            memory: memory,
-            stack: [0; 16],
+            memory,
+            stack: [0; MAX_STACK_DEPTH],
             DT: 0,
             ST: 0,
-            rng: rand::thread_rng(),
+            keys: 0,
+            waiting_key: None,
+            quirks: Quirks::default(),
+            variant: Variant::default(),
+            rng: Rng { state: rand::random() },
+            error: None,
         }
     }
 
+    // Like the VIP, only the low digit of VX picks a character
     fn get_font_location(&self, x: usize) -> usize {
-        if x > 0x0f {
-            panic!("Not a location for fonts")
-        }
-        (x * 5).into()
+        (x & 0x0f) * 5
     }
 
     fn jump(&mut self, nnn: u16) {
         self.pc = nnn.into();
     }
 
-    fn push_stack(&mut self, addr: usize) {
+    // `pc` is the address of the call
+    fn push_stack(&mut self, pc: usize, addr: usize) -> Result<(), CpuError> {
+        if self.sp >= self.stack_depth() {
+            return Err(CpuError::StackOverflow { pc: pc as u16, depth: self.stack_depth() as u8 });
+        }
+        self.stack[self.sp] = addr as u16;
         self.sp += 1;
-        self.stack[self.sp] = addr;
+        Ok(())
+    }
+
+    fn pop_stack(&mut self, pc: usize) -> Result<usize, CpuError> {
+        if self.sp == 0 {
+            return Err(CpuError::StackUnderflow { pc: pc as u16 });
+        }
+        self.sp -= 1;
+        Ok(self.stack[self.sp].into())
+    }
+
+    fn stack_depth(&self) -> usize {
+        (self.quirks.stack_depth as usize).min(MAX_STACK_DEPTH)
+    }
+
+    /// The return addresses on the stack, oldest first.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp]
     }
 
-    fn get_screen_pos(&self, x: usize, y: usize) -> usize {
-        (x % WIDTH) + ((y % HEIGHT) * WIDTH)
-        // x + y * WIDTH
+    /// The calls that led to the current instruction, innermost first.
+    pub fn backtrace(&self) -> Vec<Call> {
+        self.stack().iter().rev().map(|&return_to| {
+            let from = return_to.wrapping_sub(2);
+            let to = match self.memory.get(from as usize..from as usize + 2) {
+                Some(&[hi, lo]) => u16::from_be_bytes([hi, lo]) & 0x0fff,
+                _ => 0,
+            };
+            Call { from, to, return_to }
+        }).collect()
+    }
+
+    // The `len` bytes at I, or the error for the first one outside of memory
+    fn memory_at_i(&mut self, pc: usize, len: usize) -> Result<&mut [u8], CpuError> {
+        // Reading no bytes never fails, wherever I points
+        if len == 0 {
+            return Ok(&mut []);
+        }
+        let (start, memory_len) = (self.I as usize, self.memory.len());
+        self.memory
+            .get_mut(start..start + len)
+            .ok_or(CpuError::Memory { pc: pc as u16, addr: start.max(memory_len) })
+    }
+
+    // I after FX55 and FX65 with registers V0 to VX
+    fn increment_i(&mut self, x: usize) {
+        match self.quirks.load_store {
+            LoadStore::LeaveI => (),
+            LoadStore::Increment => self.I = self.I.wrapping_add(x as u16 + 1),
+            LoadStore::IncrementByX => self.I = self.I.wrapping_add(x as u16),
+        }
     }
 
     pub fn decreament_timer(&mut self) {
-        self.DT = self.DT.wrapping_sub(1);
+        self.DT = self.DT.saturating_sub(1);
+        self.ST = self.ST.saturating_sub(1);
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn variant(&self) -> Variant {
+        self.variant
+    }
+
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+    }
+
+    // Makes RND repeatable
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = Rng { state: seed };
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    // The opcode the next instruction runs
+    pub fn opcode(&self) -> u16 {
+        let byte = |addr: usize| self.memory.get(addr).copied().unwrap_or(0) as u16;
+        (byte(self.pc) << 8) | byte(self.pc + 1)
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.v
+    }
+
+    pub fn index(&self) -> u16 {
+        self.I
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    pub fn set_register(&mut self, x: u8, value: u8) {
+        self.v[(x & 0x0f) as usize] = value;
+    }
+
+    pub fn set_index(&mut self, i: u16) {
+        self.I = i;
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc as usize % self.memory.len();
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.DT = value;
     }
 
-    pub fn emulate_op(&mut self, event: Option<Event>) -> Option<Action> {
-        let hi = self.memory[self.pc];
-        let lo = self.memory[self.pc.wrapping_add(1)];
-        let op: u16 = ((hi as u16) << 8) | (lo as u16);
-        match event {
-            Some(Event::WaitingKeyPress(_)) => (),
-            _ => self.pc = self.pc.wrapping_add(2),
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.ST = value;
+    }
+
+    // Writes outside of memory are dropped
+    pub fn write_memory(&mut self, addr: u16, value: u8) {
+        if let Some(byte) = self.memory.get_mut(addr as usize) {
+            *byte = value;
         }
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.DT
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.ST
+    }
+
+    pub fn sound_active(&self) -> bool {
+        self.ST > 0
+    }
+
+    /// FNV-1a hash of the machine state, stable across builds so it can be
+    /// stored in files.
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        let mut add = |bytes: &[u8]| {
+            for &byte in bytes {
+                hash = (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        };
+        for row in self.screen.rows() {
+            add(&row.to_le_bytes());
+        }
+        add(&self.v);
+        add(&self.I.to_le_bytes());
+        add(&(self.pc as u16).to_le_bytes());
+        add(&[self.sp as u8]);
+        add(&self.memory);
+        for addr in self.stack() {
+            add(&addr.to_le_bytes());
+        }
+        add(&[self.DT, self.ST]);
+        add(&self.keys.to_le_bytes());
+        add(&[self.waiting_key.map_or(0xff, |key| key)]);
+        add(&self.rng.state.to_le_bytes());
+        hash
+    }
+
+    /// The whole machine, including quirks and the RNG, as bytes that
+    /// `load_state` restores.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(0x1200);
+        out.extend_from_slice(STATE_MAGIC);
+        out.push(STATE_VERSION);
+        for row in self.screen.rows() {
+            out.extend_from_slice(&row.to_le_bytes());
+        }
+        out.extend_from_slice(&self.v);
+        out.extend_from_slice(&self.I.to_le_bytes());
+        out.extend_from_slice(&(self.pc as u16).to_le_bytes());
+        out.push(self.sp as u8);
+        out.extend_from_slice(&self.memory);
+        for addr in &self.stack {
+            out.extend_from_slice(&addr.to_le_bytes());
+        }
+        out.extend_from_slice(&[self.DT, self.ST]);
+        out.extend_from_slice(&self.keys.to_le_bytes());
+        out.push(self.waiting_key.map_or(0xff, |key| key));
+        let quirks = self.quirks;
+        out.extend_from_slice(&[
+            quirks.shift as u8,
+            match quirks.load_store {
+                LoadStore::LeaveI => 0,
+                LoadStore::Increment => 1,
+                LoadStore::IncrementByX => 2,
+            },
+            quirks.wrap as u8,
+            quirks.jump as u8,
+            quirks.display_wait as u8,
+            quirks.logic as u8,
+            quirks.stack_depth,
+            match self.variant {
+                Variant::Chip8 => 0,
+                Variant::Schip => 1,
+            },
+        ]);
+        out.extend_from_slice(&self.rng.state.to_le_bytes());
+        out
+    }
+
+    /// Restores a state from `save_state`. The machine is left as it was if
+    /// the state can't be read.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader { data };
+        if r.take(4).map_err(|_| StateError::Format)? != STATE_MAGIC {
+            return Err(StateError::Format);
+        }
+        match r.u8()? {
+            STATE_VERSION => (),
+            version => return Err(StateError::Version(version)),
+        }
+        let mut state = self.clone();
+        let mut rows = [0; HEIGHT];
+        for row in rows.iter_mut() {
+            *row = r.u64()?;
+        }
+        state.screen = Screen::from_rows(rows);
+        state.v.copy_from_slice(r.take(16)?);
+        state.I = r.u16()?;
+        state.pc = r.u16()? as usize;
+        state.sp = r.u8()? as usize;
+        if state.pc >= state.memory.len() {
+            return Err(StateError::Invalid("program counter"));
+        }
+        if state.sp > MAX_STACK_DEPTH {
+            return Err(StateError::Invalid("stack pointer"));
+        }
+        let memory = r.take(state.memory.len())?;
+        state.memory.copy_from_slice(memory);
+        for addr in state.stack.iter_mut() {
+            *addr = r.u16()?;
+        }
+        state.DT = r.u8()?;
+        state.ST = r.u8()?;
+        state.keys = r.u16()?;
+        state.waiting_key = match r.u8()? {
+            0xff => None,
+            key if key < 16 => Some(key),
+            _ => return Err(StateError::Invalid("key")),
+        };
+        state.quirks = Quirks {
+            shift: r.bool()?,
+            load_store: match r.u8()? {
+                0 => LoadStore::LeaveI,
+                1 => LoadStore::Increment,
+                2 => LoadStore::IncrementByX,
+                _ => return Err(StateError::Invalid("quirk")),
+            },
+            wrap: r.bool()?,
+            jump: r.bool()?,
+            display_wait: r.bool()?,
+            logic: r.bool()?,
+            stack_depth: r.u8()?,
+        };
+        if state.sp > state.stack_depth() {
+            return Err(StateError::Invalid("stack depth"));
+        }
+        state.variant = match r.u8()? {
+            0 => Variant::Chip8,
+            1 => Variant::Schip,
+            _ => return Err(StateError::Invalid("variant")),
+        };
+        state.rng.state = r.u64()?;
+        state.error = None;
+        *self = state;
+        Ok(())
+    }
+
+    pub fn set_key(&mut self, key: u8, pressed: bool) {
+        let bit = 1 << (key & 0x0f);
+        if pressed {
+            self.keys |= bit;
+        } else {
+            self.keys &= !bit;
+        }
+    }
+
+    pub fn set_keys(&mut self, keys: u16) {
+        self.keys = keys;
+    }
+
+    /// What stopped the machine, if anything did. A stopped machine runs no
+    /// more instructions until a state is loaded.
+    pub fn error(&self) -> Option<&CpuError> {
+        self.error.as_ref()
+    }
+
+    pub fn is_key_down(&self, key: u8) -> bool {
+        self.keys & (1 << (key & 0x0f)) != 0
+    }
+
+    // Runs one 60Hz frame worth of instructions and ticks the timers.
+    // Returns true if the screen changed during the frame.
+    pub fn run_frame(&mut self, instructions: usize) -> bool {
+        self.run_frame_observed(instructions, &mut ())
+    }
+
+    /// `run_frame`, showing `observer` the machine around every instruction.
+    pub fn run_frame_observed<O: Observer + ?Sized>(&mut self, instructions: usize, observer: &mut O) -> bool {
+        let mut redraw = false;
+        for _ in 0..instructions {
+            if self.error.is_some() {
+                break;
+            }
+            let drawing = self.opcode() & 0xf000 == 0xd000;
+            observer.before(self);
+            let action = match self.emulate_op() {
+                Ok(action) => action,
+                Err(e) => {
+                    self.error = Some(e);
+                    None
+                }
+            };
+            let redrawn = matches!(action, Some(Action::DisplayScreen(_)));
+            let waiting = matches!(action, Some(Action::WaitForKeyPress));
+            observer.after(self);
+            match (redrawn, waiting) {
+                (true, _) => {
+                    redraw = true;
+                    if drawing && self.quirks.display_wait {
+                        break;
+                    }
+                }
+                (_, true) => break,
+                _ => (),
+            }
+        }
+        self.decreament_timer();
+        observer.end_frame(self);
+        redraw
+    }
+
+    /// Runs the instruction at pc. On an error pc has already moved on and
+    /// nothing else has changed.
+    pub fn emulate_op(&mut self) -> Result<Option<Action<'_>>, CpuError> {
+        let pc = self.pc;
+        if pc + 1 >= self.memory.len() {
+            return Err(CpuError::Memory { pc: pc as u16, addr: pc.max(self.memory.len()) });
+        }
+        let op = self.opcode();
+        self.pc = self.pc.wrapping_add(2);
         match op {
             0x00e0 => { // CLS
-                self.screen = [0; WIDTH * HEIGHT];
-                Some(Action::DisplayScreen(&self.screen))
+                self.screen.clear();
+                Ok(Some(Action::DisplayScreen(&self.screen)))
             },
             0x00ee => { // RET
-                self.pc = self.stack[self.sp];
-                self.sp = self.sp.wrapping_sub(1);
-                None
+                self.pc = self.pop_stack(pc)?;
+                Ok(None)
             },
             n if (n & 0xf000) == 0x0000 => { // 0nnn - SYS addr
                 let addr = n & 0x0fff;
                 self.jump(addr);
-                None
+                Ok(None)
             },
             n if (n & 0xf000) == 0x1000 => { // JP addr
                 let addr = n & 0x0fff;
                 self.jump(addr);
-                None
+                Ok(None)
             },
             n if (n & 0xf000) == 0x2000 => { // CALL addr
-                self.push_stack(self.pc);
+                self.push_stack(pc, self.pc)?;
 
                 let addr = n & 0x0fff;
                 self.jump(addr);
-                None
+                Ok(None)
             },
             n if (n & 0xf000) == 0x3000 => { // SE Vx, kk
                 let x: usize = ((n & 0x0f00) >> 8).into();
@@ -139,7 +679,7 @@ impl Chip8 {
                 if self.v[x] == kk {
                     self.pc = self.pc.wrapping_add(2);
                 }
-                None
+                Ok(None)
             },
             n if (n & 0xf000) == 0x4000 => { // SNE Vx, kk
                 let x: usize =  ((n & 0x0f00) >> 8).into();
@@ -147,7 +687,7 @@ impl Chip8 {
                 if self.v[x] != kk {
                     self.pc = self.pc.wrapping_add(2);
                 }
-                None
+                Ok(None)
             },
             n if (n & 0xf00f) == 0x5000 => { // SE Vx, Vy
                 let x: usize = ((n & 0x0f00) >> 8).into();
@@ -156,106 +696,110 @@ impl Chip8 {
                     // self.pc += 2;
                     self.pc = self.pc.wrapping_add(2);
                 }
-                None
+                Ok(None)
             },
             n if (n & 0xf000) == 0x6000 => { // LD Vx, Byte
                 let x: usize = ((n & 0x0f00) >> 8).into();
                 let byte = (n & 0x00ff) as u8;
                 self.v[x] = byte;
-                None
+                Ok(None)
             },
             n if (n & 0xf000) == 0x7000 => { // ADD Vx, Byte
                 let x: usize = ((n & 0x0f00) >> 8).into();
                 let byte: u8 = (n & 0x00ff) as u8;
                 // self.v[x] = self.v[x] + byte;
                 self.v[x] = self.v[x].wrapping_add(byte);
-                None
+                Ok(None)
             },
             n if (n & 0xf00f) == 0x8000 => { // LD Vx, Vy
                 let x: usize = ((n & 0x0f00) >> 8).into();
                 let y: usize = ((n & 0x00f0) >> 4).into();
 
                 self.v[x] = self.v[y];
-                None
+                Ok(None)
             },
             n if (n & 0xf00f) == 0x8001 => { // OR Vx, Vy
                 let x: usize = ((n & 0x0f00) >> 8).into();
                 let y: usize = ((n & 0x00f0) >> 4).into();
 
                 self.v[x] |= self.v[y];
-                None
+                if self.quirks.logic {
+                    self.v[0xf] = 0;
+                }
+                Ok(None)
             },
             n if (n & 0xf00f) == 0x8002 => { // AND Vx, Vy
                 let x: usize = ((n & 0x0f00) >> 8).into();
                 let y: usize = ((n & 0x00f0) >> 4).into();
 
                 self.v[x] &= self.v[y];
-                None
+                if self.quirks.logic {
+                    self.v[0xf] = 0;
+                }
+                Ok(None)
             },
             n if (n & 0xf00f) == 0x8003 => { // XOR Vx, Vy
                 let x: usize = ((n & 0x0f00) >> 8).into();
                 let y: usize = ((n & 0x00f0) >> 4).into();
 
                 self.v[x] ^= self.v[y];
-                None
+                if self.quirks.logic {
+                    self.v[0xf] = 0;
+                }
+                Ok(None)
             },
             n if (n & 0xf00f) == 0x8004 => { // ADD Vx, Vy
                 let x: usize = ((n & 0x0f00) >> 8).into();
                 let y: usize = ((n & 0x00f0) >> 4).into();
 
-                let vx: u16 = self.v[x].into();
-                let vy: u16 = self.v[y].into();
-
-                let byte = vx.wrapping_add(vy);
-                if byte > 255 {
-                    self.v[0xf] = 1;
-                } else {
-                    self.v[0xf] = 0;
-                }
-
-                self.v[x] = self.v[x].wrapping_add(self.v[y]);
-                None
+                // VF is set last, so it holds the flag even for ADD VF, Vy
+                let (sum, carry) = self.v[x].overflowing_add(self.v[y]);
+                self.v[x] = sum;
+                self.v[0xf] = carry as u8;
+                Ok(None)
             },
             n if (n & 0xf00f) == 0x8005 => { // SUB Vx, Vy
                 let x: usize = ((n & 0x0f00) >> 8).into();
                 let y: usize = ((n & 0x00f0) >> 4).into();
 
-                if self.v[x] > self.v[y] {
-                    self.v[0xf] = 1;
-                } else {
-                    self.v[0xf] = 0;
-                }
-
+                // No borrow when they are equal
+                let no_borrow = self.v[x] >= self.v[y];
                 self.v[x] = self.v[x].wrapping_sub(self.v[y]);
-                None
+                self.v[0xf] = no_borrow as u8;
+                Ok(None)
             },
             n if (n & 0xf00f) == 0x8006 => { // SHR Vx{, Vy}
                 let x: usize = ((n & 0x0f00) >> 8).into();
+                let y: usize = ((n & 0x00f0) >> 4).into();
+                if !self.quirks.shift {
+                    self.v[x] = self.v[y];
+                }
 
-                self.v[0xf] = self.v[x] & 0x01;
-
-                self.v[x] = self.v[x] >> 1; 
-                None
+                let shifted_out = self.v[x] & 0x01;
+                self.v[x] >>= 1;
+                self.v[0xf] = shifted_out;
+                Ok(None)
             },
             n if (n & 0xf00f) == 0x8007 => { // SUBN Vx, Vy
                 let x: usize = ((n & 0x0f00) >> 8).into();
                 let y: usize = ((n & 0x00f0) >> 4).into();
 
-                if self.v[y] > self.v[x] {
-                    self.v[0xf] = 1;
-                } else {
-                    self.v[0xf] = 0;
-                }
-
+                let no_borrow = self.v[y] >= self.v[x];
                 self.v[x] = self.v[y].wrapping_sub(self.v[x]);
-                None
+                self.v[0xf] = no_borrow as u8;
+                Ok(None)
             },
-            n if (n & 0xf00f) == 0x800E => { // SHL Vx{, Vy}
+            n if (n & 0xf00f) == 0x800e => { // SHL Vx{, Vy}
                 let x: usize = ((n & 0x0f00) >> 8).into();
+                let y: usize = ((n & 0x00f0) >> 4).into();
+                if !self.quirks.shift {
+                    self.v[x] = self.v[y];
+                }
 
-                self.v[0xf] = (self.v[x] & 0x80) >> 7;
-                self.v[x] = self.v[x] << 1; 
-                None
+                let shifted_out = (self.v[x] & 0x80) >> 7;
+                self.v[x] <<= 1;
+                self.v[0xf] = shifted_out;
+                Ok(None)
             },
             n if (n & 0xf00f) == 0x9000 => { // SNE Vx, Vy
                 let x: usize =  ((n & 0x0f00) >> 8).into();
@@ -263,487 +807,642 @@ impl Chip8 {
                 if self.v[x] != self.v[y] {
                     self.pc = self.pc.wrapping_add(2);
                 }
-                None
+                Ok(None)
             },
             n if (n & 0xf000) == 0xa000 => { // LD I, addr
-                let byte = (n & 0x0fff).into();
+                let byte = n & 0x0fff;
 
                 self.I = byte;
-                None
+                Ok(None)
             },
             n if (n & 0xf000) == 0xb000 => { // JP V0, addr
                 let addr = n & 0x0fff;
-                self.jump(addr + (self.v[0] as u16));
-                None
+                // BXNN jumps to XNN + VX with the jump quirk
+                let offset = if self.quirks.jump {
+                    self.v[((n & 0x0f00) >> 8) as usize]
+                } else {
+                    self.v[0]
+                };
+                self.jump(addr + (offset as u16));
+                Ok(None)
             },
             n if (n & 0xf000) == 0xc000 => { // RND Vx, byte
                 let x: usize =  ((n & 0x0f00) >> 8).into();
                 let kk: u8 = (n & 0x00ff) as u8;
 
-                let r: u8 = self.rng.gen_range(0..=255);
+                let r: u8 = self.rng.next() as u8;
                 
                 self.v[x] = r & kk;
-                None
+                Ok(None)
             },
             n if (n & 0xf000) == 0xd000 => { // DRW Vx, Vy, nibble
                 let x: usize = ((n & 0x0f00) >> 8).into();
                 let y: usize = ((n & 0x00f0) >> 4).into();
                 let n: usize = (n & 0x000f).into();
+                // The start position always wraps, the rest of the sprite only with the wrap quirk
+                let x = self.v[x] as usize % WIDTH;
+                let y = self.v[y] as usize % HEIGHT;
                 let mut collision = false;
-                for i in 0..n {
-                    let byte: u8 = self.memory[self.I as usize + i];
-                    for j in 0..8 {
-                        let bit: u32 = (byte.wrapping_shr(7 - j as u32) & 0x1).into();
-                        let pixel = self.screen[self.get_screen_pos((self.v[x] as usize) + j, (self.v[y] as usize) + i)];
-                        let new_pixel = (pixel & 0x01) ^ bit;
-                        if new_pixel == (pixel & 0x01) { 
-                            collision = true;
-                        }
-                        if new_pixel == 0x01 {
-                            self.screen[self.get_screen_pos((self.v[x] as usize) + j, (self.v[y] as usize) + i)] = 0x00FFFFFF;
-                        } else {                                                                         
-                            self.screen[self.get_screen_pos((self.v[x] as usize) + j, (self.v[y] as usize) + i)] = 0x00000000;
-                        }
+                let sprite = self.memory_at_i(pc, n)?.to_vec();
+                for (i, &byte) in sprite.iter().enumerate() {
+                    if self.quirks.wrap {
+                        collision |= self.screen.draw_row(x, y + i, byte);
+                    } else if y + i < HEIGHT {
+                        collision |= self.screen.clip_row(x, y + i, byte);
                     }
                 }
-                if collision {
-                    self.v[0xf] = 0x1;
-                }
-                Some(Action::DisplayScreen(&self.screen))
+                self.v[0xf] = collision as u8;
+                Ok(Some(Action::DisplayScreen(&self.screen)))
             },
             n if (n & 0xf0ff) == 0xe09e => { // SKP Vx
                 let x: usize = ((n & 0x0f00) >> 8).into();
-                match event {
-                    Some(Event::KeyPress(n)) if self.v[x] == n => {
-                        self.pc = self.pc.wrapping_add(2);
-                    },
-                    _ => (),
+                if self.is_key_down(self.v[x]) {
+                    self.pc = self.pc.wrapping_add(2);
                 }
-                None
+                Ok(None)
             },
             n if (n & 0xf0ff) == 0xe0a1 => { // SKNP Vx
                 let x: usize = ((n & 0x0f00) >> 8).into();
-                match event {
-                    Some(Event::KeyPress(n)) if self.v[x] == n => (),
-                    _ => self.pc = self.pc.wrapping_add(2),
+                if !self.is_key_down(self.v[x]) {
+                    self.pc = self.pc.wrapping_add(2);
                 }
-                None
+                Ok(None)
             },
             n if (n & 0xf0ff) == 0xf007 => { // LD Vx, DT
                 let x: usize =  ((n & 0x0f00) >> 8).into();
                 self.v[x] = self.DT;
-                None
+                Ok(None)
             },
-            n if (n & 0xf0ff) == 0xf00A => { // LD Vx, K
+            n if (n & 0xf0ff) == 0xf00a => { // LD Vx, K
                 let x: usize =  ((n & 0x0f00) >> 8).into();
-                match event {
-                    Some(Event::WaitingKeyPress(n)) => {
-                        self.v[x] = n;
-                        None
+                // Like the VIP, the key is only taken once it is released
+                match self.waiting_key {
+                    Some(key) if !self.is_key_down(key) => {
+                        self.v[x] = key;
+                        self.waiting_key = None;
+                        Ok(None)
+                    }
+                    waiting => {
+                        if waiting.is_none() {
+                            self.waiting_key = (0..16).find(|&key| self.is_key_down(key));
+                        }
+                        self.pc = self.pc.wrapping_sub(2);
+                        Ok(Some(Action::WaitForKeyPress))
                     }
-                    _ => Some(Action::WaitForKeyPress)
                 }
             },
             n if (n & 0xf0ff) == 0xf015 => { // LD DT, Vx 
                 let x: usize =  ((n & 0x0f00) >> 8).into();
                 self.DT = self.v[x];
-                None
+                Ok(None)
             },
             n if (n & 0xf0ff) == 0xf018 => { // LD ST, Vx 
                 let x: usize =  ((n & 0x0f00) >> 8).into();
                 self.ST = self.v[x];
-                None
+                Ok(None)
             },
             n if (n & 0xf0ff) == 0xf01e => { // ADD I, Vx
                 let x: usize =  ((n & 0x0f00) >> 8).into();
                 self.I = self.I.wrapping_add(self.v[x].into());
-                None
+                Ok(None)
             },
             n if (n & 0xf0ff) == 0xf029 => { // LD F, Vx
                 let x: usize =  ((n & 0x0f00) >> 8).into();
                 let i = self.get_font_location(self.v[x] as usize);
                 self.I = i as u16;
-                None
+                Ok(None)
             },
             n if (n & 0xf0ff) == 0xf033 => { // LD B, Vx
                 let x: usize =  ((n & 0x0f00) >> 8).into();
-                self.memory[self.I as usize]     = self.v[x] / 100;
-                self.memory[self.I as usize + 1] = (self.v[x] / 10) % 10;
-                self.memory[self.I as usize + 2] = (self.v[x] % 100) % 10;
-                None
+                let vx = self.v[x];
+                let digits = self.memory_at_i(pc, 3)?;
+                digits[0] = vx / 100;
+                digits[1] = (vx / 10) % 10;
+                digits[2] = (vx % 100) % 10;
+                Ok(None)
             },
             n if (n & 0xf0ff) == 0xf055 => { // LD [I], Vx
                 let x: usize =  ((n & 0x0f00) >> 8).into();
-                for i in 0..=x {
-                    self.memory[(self.I as usize) + i] = self.v[i];
-                }
-                None
+                let v = self.v;
+                self.memory_at_i(pc, x + 1)?.copy_from_slice(&v[..=x]);
+                self.increment_i(x);
+                Ok(None)
             },
             n if (n & 0xf0ff) == 0xf065 => { // LD Vx, [I]
                 let x: usize =  ((n & 0x0f00) >> 8).into();
-                for i in 0..=x {
-                    self.v[i] = self.memory[(self.I as usize) + i];
-                }
-                None
+                let values = self.memory_at_i(pc, x + 1)?.to_vec();
+                self.v[..=x].copy_from_slice(&values);
+                self.increment_i(x);
+                Ok(None)
             },
-            _ => panic!("Opcode not implemented: {:#05x}", op),
+            _ => Err(CpuError::UnknownOpcode { pc: pc as u16, opcode: op }),
         }
     }
 }
 
+/// Sets up a machine in any state without running a program to get there.
+///
+/// ```
+/// use chip8::emulator::Chip8;
+///
+/// // ADD V0, V1
+/// let mut chip8 = Chip8::builder().program(&[0x80, 0x14]).register(0, 0xff).register(1, 0x01).build();
+/// chip8.emulate_op().unwrap();
+/// assert_eq!(chip8.registers()[0xf], 1);
+/// ```
+///
+/// Everything not set starts out as on a machine from `Chip8::from_rom`,
+/// except that RND is seeded with 0.
+#[derive(Clone)]
+pub struct Chip8Builder {
+    chip8: Chip8,
+}
+
+impl Default for Chip8Builder {
+    fn default() -> Self {
+        Chip8Builder::new()
+    }
+}
+
+impl Chip8Builder {
+    pub fn new() -> Self {
+        let mut chip8 = Chip8::from_rom(&[]);
+        chip8.seed_rng(0);
+        Chip8Builder { chip8 }
+    }
+
+    /// The program, loaded at 0x200.
+    pub fn program(self, program: &[u8]) -> Self {
+        self.memory(0x200, program)
+    }
+
+    /// Writes `bytes` from `addr` on; bytes past the end of memory are dropped.
+    pub fn memory(mut self, addr: u16, bytes: &[u8]) -> Self {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            self.chip8.write_memory(addr.saturating_add(offset as u16), byte);
+        }
+        self
+    }
+
+    pub fn register(mut self, x: u8, value: u8) -> Self {
+        self.chip8.set_register(x, value);
+        self
+    }
+
+    pub fn registers(mut self, v: [u8; 16]) -> Self {
+        self.chip8.v = v;
+        self
+    }
+
+    pub fn index(mut self, i: u16) -> Self {
+        self.chip8.set_index(i);
+        self
+    }
+
+    pub fn pc(mut self, pc: u16) -> Self {
+        self.chip8.set_pc(pc);
+        self
+    }
+
+    /// The return addresses of the calls in progress, the outermost first.
+    ///
+    /// # Panics
+    /// If there are more than the stack holds.
+    pub fn stack(mut self, addrs: &[u16]) -> Self {
+        let chip8 = &mut self.chip8;
+        assert!(addrs.len() <= MAX_STACK_DEPTH, "At most {} return addresses fit on the stack", MAX_STACK_DEPTH);
+        chip8.stack = [0; MAX_STACK_DEPTH];
+        chip8.stack[..addrs.len()].copy_from_slice(addrs);
+        chip8.sp = addrs.len();
+        self
+    }
+
+    pub fn delay_timer(mut self, value: u8) -> Self {
+        self.chip8.set_delay_timer(value);
+        self
+    }
+
+    pub fn sound_timer(mut self, value: u8) -> Self {
+        self.chip8.set_sound_timer(value);
+        self
+    }
+
+    /// The keys held down, one bit per hex key.
+    pub fn keys(mut self, keys: u16) -> Self {
+        self.chip8.set_keys(keys);
+        self
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.chip8.seed_rng(seed);
+        self
+    }
+
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.chip8.set_quirks(quirks);
+        self
+    }
+
+    /// # Panics
+    /// If the stack is deeper than the quirks allow.
+    pub fn build(self) -> Chip8 {
+        let chip8 = self.chip8;
+        assert!(chip8.sp <= chip8.stack_depth(), "The stack is deeper than the quirks allow");
+        chip8
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn sys_addr() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x03;
-        memory[0x201] = 0x01;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0x03, 0x01]).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.pc, 0x301);
     }
 
     #[test]
     fn ret() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x00;
-        memory[0x201] = 0xee;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.sp = 3;
-        cpu.stack[3] = 0x0301;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0x00, 0xee]).stack(&[0x202, 0x2a2, 0x0301]).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.pc, 0x0301);
-        assert_eq!(cpu.sp, 2);
+        assert_eq!(cpu.stack(), &[0x202, 0x2a2]);
     }
 
     #[test]
     fn jp_addr() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x14;
-        memory[0x201] = 0x55;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0x14, 0x55]).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.pc, 0x0455);
     }
 
     #[test]
     fn call() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x24;
-        memory[0x201] = 0x55;
-        let mut cpu = Chip8::new();
-        let mut stack = [0; 16];
-        stack[1] = 0x202;
-        cpu.memory = memory;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0x24, 0x55]).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.pc, 0x0455);
-        assert_eq!(cpu.sp, 1);
-        assert_eq!(cpu.stack, stack);
+        assert_eq!(cpu.stack(), &[0x202]);
     }
 
     #[test]
     fn se() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x30;
-        memory[0x201] = 0x55;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x55;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0x30, 0x55]).register(0, 0x55).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.pc, 0x204);
     }
 
     #[test]
     fn sne_vx_byte() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x40;
-        memory[0x201] = 0x54;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x55;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0x40, 0x54]).register(0, 0x55).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.pc, 0x204);
     }
 
     #[test]
     fn se_vx_vy() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x50;
-        memory[0x201] = 0x10;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x55;
-        cpu.v[1] = 0x55;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder()
+            .program(&[0x50, 0x10])
+            .register(0, 0x55)
+            .register(1, 0x55)
+            .build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.pc, 0x204);
     }
 
     #[test]
     fn ld_vx_byte() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x60;
-        memory[0x201] = 0x10;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0x60, 0x10]).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.v[0], 0x10);
     }
 
     #[test]
     fn add_vx_byte() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x70;
-        memory[0x201] = 0x01;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x01;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0x70, 0x01]).register(0, 0x01).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.v[0], 0x02);
     }
 
     #[test]
     fn ld_vx_vy() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x80;
-        memory[0x201] = 0x10;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[1] = 0x01;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0x80, 0x10]).register(1, 0x01).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.v[0], 0x01);
     }
 
     #[test]
     fn or() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x80;
-        memory[0x201] = 0x11;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x02;
-        cpu.v[1] = 0x01;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder()
+            .program(&[0x80, 0x11])
+            .register(0, 0x02)
+            .register(1, 0x01)
+            .build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.v[0], 0x03);
     }
 
     #[test]
     fn and() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x80;
-        memory[0x201] = 0x12;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x03;
-        cpu.v[1] = 0x01;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder()
+            .program(&[0x80, 0x12])
+            .register(0, 0x03)
+            .register(1, 0x01)
+            .build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.v[0], 0x01);
     }
 
     #[test]
     fn xor() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x80;
-        memory[0x201] = 0x13;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x03;
-        cpu.v[1] = 0x01;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder()
+            .program(&[0x80, 0x13])
+            .register(0, 0x03)
+            .register(1, 0x01)
+            .build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.v[0], 0x02);
     }
 
     #[test]
     fn add_vx_vy() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x80;
-        memory[0x201] = 0x14;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0xff;
-        cpu.v[1] = 0x01;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder()
+            .program(&[0x80, 0x14])
+            .register(0, 0xff)
+            .register(1, 0x01)
+            .build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.v[0], 0x00);
         assert_eq!(cpu.v[0xf], 0x01);
     }
 
     #[test]
     fn sub_vx_vy() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x80;
-        memory[0x201] = 0x15;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0xff;
-        cpu.v[1] = 0x01;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder()
+            .program(&[0x80, 0x15])
+            .register(0, 0xff)
+            .register(1, 0x01)
+            .build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.v[0], 0xfe);
         assert_eq!(cpu.v[0xf], 0x01);
     }
 
     #[test]
     fn shr() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x80;
-        memory[0x201] = 0x16;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0xff;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0x80, 0x16]).register(0, 0xff).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.v[0], 0x7f);
         assert_eq!(cpu.v[0xf], 0x01);
     }
 
     #[test]
     fn subn() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x80;
-        memory[0x201] = 0x17;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x01;
-        cpu.v[1] = 0xff;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder()
+            .program(&[0x80, 0x17])
+            .register(0, 0x01)
+            .register(1, 0xff)
+            .build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.v[0], 0xfe);
         assert_eq!(cpu.v[0xf], 0x01);
     }
 
+    #[test]
+    fn sub_flags() {
+        // SUB V0, V1; SUBN V2, V3; SUB VF, V1: equal values don't borrow
+        let mut cpu = Chip8::builder()
+            .program(&[0x80, 0x15, 0x82, 0x37, 0x8f, 0x15])
+            .registers([0x05, 0x05, 0x07, 0x07, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])
+            .build();
+        cpu.emulate_op().unwrap();
+        assert_eq!((cpu.v[0], cpu.v[0xf]), (0x00, 0x01));
+        cpu.emulate_op().unwrap();
+        assert_eq!((cpu.v[2], cpu.v[0xf]), (0x00, 0x01));
+        // The flag, not the difference, ends up in VF
+        cpu.set_register(0xf, 0x03);
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.v[0xf], 0x00);
+    }
+
     #[test]
     fn shl() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x80;
-        memory[0x201] = 0x1e;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x7f;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0x80, 0x1e]).register(0, 0x7f).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.v[0], 0xfe);
         assert_eq!(cpu.v[0xf], 0x00);
     }
 
     #[test]
     fn sne_vx_vy() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0x90;
-        memory[0x201] = 0x10;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0xff;
-        cpu.v[1] = 0x01;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder()
+            .program(&[0x90, 0x10])
+            .register(0, 0xff)
+            .register(1, 0x01)
+            .build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.pc, 0x204);
     }
 
     #[test]
     fn ld_i_addr() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0xaf;
-        memory[0x201] = 0xff;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0xaf, 0xff]).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.I, 0xfff);
     }
 
     #[test]
     fn jp_v0_addr() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0xb3;
-        memory[0x201] = 0x00;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x1;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0xb3, 0x00]).register(0, 0x1).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.pc, 0x301);
     }
 
-    // #[test]
-    // fn skp() {
-        // let mut memory = [0x00; 0xfff];
-        // memory[0x200] = 0xb3;
-        // memory[0x201] = 0x00;
-        // let mut cpu = Chip8::new();
-        // cpu.memory = memory;
-        // cpu.v[0] = 0x1;
-        // cpu.emulate_op();
-        // assert_eq!(cpu.pc, 0x301);
-    // }
-
-    // #[test]
-    // fn sknp() {
-        // let mut memory = [0x00; 0xfff];
-        // memory[0x200] = 0xb3;
-        // memory[0x201] = 0x00;
-        // let mut cpu = Chip8::new();
-        // cpu.memory = memory;
-        // cpu.v[0] = 0x1;
-        // cpu.emulate_op();
-        // assert_eq!(cpu.pc, 0x301);
-    // }
-      
+    #[test]
+    fn drw() {
+        let mut cpu = Chip8::builder()
+            .program(&[0xd0, 0x12])
+            .memory(0x300, &[0xf0, 0x90])
+            .register(0, 0x3e)
+            .register(1, 0x1f)
+            .register(0xf, 0x1)
+            .index(0x300)
+            .build();
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.screen.rows()[0x1f], 0xc000_0000_0000_0003);
+        assert_eq!(cpu.screen.rows()[0], 0x4000_0000_0000_0002);
+        assert_eq!(cpu.v[0xf], 0x0);
+    }
+
+    #[test]
+    fn drw_collision() {
+        let mut cpu = Chip8::builder()
+            .program(&[0xd0, 0x01, 0xd0, 0x01])
+            .memory(0x300, &[0x81])
+            .index(0x300)
+            .build();
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.v[0xf], 0x0);
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.v[0xf], 0x1);
+        assert_eq!(cpu.screen, Screen::new());
+    }
+
+    #[test]
+    fn skp() {
+        let mut cpu = Chip8::builder()
+            .program(&[0xe0, 0x9e])
+            .register(0, 0x5)
+            .keys(1 << 0x5)
+            .build();
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn sknp() {
+        let mut cpu = Chip8::builder()
+            .program(&[0xe0, 0xa1])
+            .register(0, 0x5)
+            .keys(1 << 0x4)
+            .build();
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn display_wait() {
+        let mut cpu = Chip8::builder()
+            .program(&[
+                0x70, 0x01, // ADD V0, 1
+                0xd1, 0x10, // DRW V1, V1, 0
+                0x12, 0x00, // JP 0x200
+            ])
+            .build();
+        cpu.run_frame(9);
+        assert_eq!(cpu.v[0], 3);
+
+        cpu.set_register(0, 0);
+        cpu.set_pc(0x200);
+        cpu.set_quirks(Quirks { display_wait: true, ..Quirks::default() });
+        assert!(cpu.run_frame(9));
+        assert_eq!(cpu.v[0], 1);
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn vip_quirks() {
+        let mut cpu = Chip8::builder()
+            .program(&[
+                0x80, 0x16, // SHR V0, V1
+                0x83, 0x41, // OR V3, V4
+                0xf1, 0x55, // LD [I], V1
+                0xd0, 0x52, // DRW V0, V5, 2
+            ])
+            .memory(0x302, &[0xff, 0xff])
+            .quirks(Quirks::VIP)
+            .register(1, 0x3f)
+            .register(0xf, 0x1)
+            .index(0x300)
+            .register(5, 0x1f)
+            .build();
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.v[0], 0x1f);
+        assert_eq!(cpu.v[0xf], 0x1);
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.v[0xf], 0x0);
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.I, 0x302);
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.screen.rows()[0x1f], 0x1_fe00_0000);
+        assert_eq!(cpu.screen.rows()[0], 0);
+    }
+
+    #[test]
+    fn jump_quirk() {
+        let mut cpu = Chip8::builder()
+            .program(&[0xb3, 0x00])
+            .quirks(Quirks::SCHIP)
+            .register(0, 0x1)
+            .register(3, 0x2)
+            .build();
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.pc, 0x302);
+    }
+
+    #[test]
+    fn ld_vx_k() {
+        let mut cpu = Chip8::builder().program(&[0xf3, 0x0a]).build();
+        assert!(matches!(cpu.emulate_op(), Ok(Some(Action::WaitForKeyPress))));
+        cpu.set_key(0x7, true);
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.pc, 0x200);
+        cpu.set_key(0x7, false);
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.pc, 0x202);
+        assert_eq!(cpu.v[3], 0x7);
+    }
+
+    #[test]
+    fn timers_tick_once_per_frame_and_stop_at_zero() {
+        // JP 0x200
+        let mut cpu = Chip8::builder().program(&[0x12, 0x00]).delay_timer(2).sound_timer(1).build();
+        assert!(cpu.sound_active());
+        cpu.run_frame(10);
+        assert_eq!((cpu.DT, cpu.ST), (1, 0));
+        assert!(!cpu.sound_active());
+        cpu.run_frame(10);
+        cpu.run_frame(10);
+        assert_eq!((cpu.DT, cpu.ST), (0, 0));
+    }
+
+    #[test]
+    fn frame_ends_while_waiting_for_a_key() {
+        let mut cpu = Chip8::builder().program(&[0xf3, 0x0a]).delay_timer(5).build();
+        cpu.run_frame(10);
+        assert_eq!(cpu.pc, 0x200);
+        assert_eq!(cpu.DT, 4);
+        cpu.set_keys(1 << 0xa);
+        cpu.run_frame(10);
+        cpu.set_keys(0);
+        cpu.run_frame(1);
+        assert_eq!(cpu.v[3], 0xa);
+        assert_eq!(cpu.pc, 0x202);
+    }
+
     #[test]
     fn ld_dt_vx() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0xf0;
-        memory[0x201] = 0x15;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x5;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0xf0, 0x15]).register(0, 0x5).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.DT, 0x5);
     }
 
     #[test]
     fn ld_st_vx() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0xf0;
-        memory[0x201] = 0x18;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x5;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0xf0, 0x18]).register(0, 0x5).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.ST, 0x5);
     }
 
     #[test]
     fn add_i_vx() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0xf0;
-        memory[0x201] = 0x1e;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x5;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0xf0, 0x1e]).register(0, 0x5).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.I, 0x5);
     }
 
     #[test]
     fn ld_f_vx() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0xf0;
-        memory[0x201] = 0x29;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0xf;
-        cpu.I = 0x300;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0xf0, 0x29]).register(0, 0xf).index(0x300).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.I, 75);
     }
 
     #[test]
     fn ld_b_vx() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0xf0;
-        memory[0x201] = 0x33;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 123;
-        cpu.I = 0x300;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder().program(&[0xf0, 0x33]).register(0, 123).index(0x300).build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.memory[0x300], 1);
         assert_eq!(cpu.memory[0x301], 2);
         assert_eq!(cpu.memory[0x302], 3);
@@ -751,16 +1450,14 @@ mod tests {
 
     #[test]
     fn ld_mem_i_vx() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0xf3;
-        memory[0x201] = 0x55;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.v[0] = 0x01;
-        cpu.v[1] = 0x02;
-        cpu.v[2] = 0x03;
-        cpu.I = 0x300;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder()
+            .program(&[0xf3, 0x55])
+            .register(0, 0x01)
+            .register(1, 0x02)
+            .register(2, 0x03)
+            .index(0x300)
+            .build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.memory[0x300], 0x1);
         assert_eq!(cpu.memory[0x301], 0x2);
         assert_eq!(cpu.memory[0x302], 0x3);
@@ -768,18 +1465,127 @@ mod tests {
 
     #[test]
     fn ld_vx_mem_i() {
-        let mut memory = [0x00; 0xfff];
-        memory[0x200] = 0xf3;
-        memory[0x201] = 0x65;
-        let mut cpu = Chip8::new();
-        cpu.memory = memory;
-        cpu.memory[0x300] = 0x1;
-        cpu.memory[0x301] = 0x2;
-        cpu.memory[0x302] = 0x3;
-        cpu.I = 0x300;
-        cpu.emulate_op();
+        let mut cpu = Chip8::builder()
+            .program(&[0xf3, 0x65])
+            .memory(0x300, &[0x1, 0x2, 0x3])
+            .index(0x300)
+            .build();
+        cpu.emulate_op().unwrap();
         assert_eq!(cpu.v[0], 1);
         assert_eq!(cpu.v[1], 2);
         assert_eq!(cpu.v[2], 3);
     }
+
+    #[test]
+    fn save_and_load_state() {
+        // RND v0, 0xff; DRW v0, v1, 5; JP 0x200
+        let mut cpu = Chip8::from_rom(&[0xc0, 0xff, 0xd0, 0x15, 0x12, 0x00]);
+        cpu.seed_rng(3);
+        cpu.set_quirks(Quirks::VIP);
+        cpu.run_frame(7);
+        let state = cpu.save_state();
+
+        let mut other = Chip8::from_rom(&[]);
+        other.load_state(&state).unwrap();
+        assert_eq!(other.state_hash(), cpu.state_hash());
+        assert_eq!(other.quirks(), Quirks::VIP);
+        cpu.run_frame(7);
+        other.run_frame(7);
+        assert_eq!(other.state_hash(), cpu.state_hash());
+
+        let hash = other.state_hash();
+        assert_eq!(other.load_state(&state[..100]), Err(StateError::Truncated));
+        assert_eq!(other.load_state(b"nope"), Err(StateError::Format));
+        assert_eq!(other.state_hash(), hash);
+    }
+
+    #[test]
+    fn errors_stop_the_machine() {
+        let mut cpu = Chip8::from_rom(&[0x60, 0x01, 0xff, 0xff, 0x60, 0x02]);
+        assert!(!cpu.run_frame(10));
+        assert_eq!(cpu.error(), Some(&CpuError::UnknownOpcode { pc: 0x202, opcode: 0xffff }));
+        assert_eq!(cpu.v[0], 1);
+        cpu.run_frame(10);
+        assert_eq!(cpu.pc, 0x204);
+
+        let state = Chip8::from_rom(&[]).save_state();
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.error(), None);
+    }
+
+    #[test]
+    fn stack_errors() {
+        let mut cpu = Chip8::from_rom(&[0x00, 0xee]);
+        assert_eq!(cpu.emulate_op().unwrap_err(), CpuError::StackUnderflow { pc: 0x200 });
+
+        // CALL 0x200, forever
+        let mut cpu = Chip8::from_rom(&[0x22, 0x00]);
+        cpu.run_frame(100);
+        assert_eq!(cpu.error(), Some(&CpuError::StackOverflow { pc: 0x200, depth: 16 }));
+        assert_eq!(cpu.stack(), &[0x202; 16]);
+
+        let mut cpu = Chip8::builder().program(&[0x22, 0x00]).quirks(Quirks::VIP).build();
+        cpu.run_frame(100);
+        assert_eq!(cpu.error(), Some(&CpuError::StackOverflow { pc: 0x200, depth: 12 }));
+        assert_eq!(cpu.stack().len(), 12);
+    }
+
+    #[test]
+    fn backtrace() {
+        // CALL 0x204; CALL 0x208
+        let mut cpu = Chip8::from_rom(&[0x22, 0x04, 0, 0, 0x22, 0x08]);
+        cpu.run_frame(2);
+        assert_eq!(cpu.backtrace(), vec![
+            Call { from: 0x204, to: 0x208, return_to: 0x206 },
+            Call { from: 0x200, to: 0x204, return_to: 0x202 },
+        ]);
+        assert_eq!(cpu.backtrace()[0].to_string(), "204 called 208");
+    }
+
+    #[test]
+    fn memory_errors() {
+        // DRW V0, V0, 15; LD B, V0; LD [I], VF; LD VF, [I]
+        for op in [[0xd0, 0x0f], [0xf0, 0x33], [0xff, 0x55], [0xff, 0x65]] {
+            let mut cpu = Chip8::builder().program(&op).index(0xffe).build();
+            assert_eq!(cpu.emulate_op().unwrap_err(), CpuError::Memory { pc: 0x200, addr: 0x1000 });
+            assert_eq!(cpu.screen(), &Screen::new());
+        }
+        // The last byte of memory is still in it
+        let mut cpu = Chip8::builder().program(&[0xf0, 0x33, 0xf2, 0x65]).register(0, 123).index(0xffd).build();
+        cpu.run_frame(2);
+        assert_eq!(cpu.error(), None);
+        assert_eq!(cpu.memory()[0xfff], 3);
+        assert_eq!(cpu.v[2], 3);
+        // An instruction can't start there
+        let mut cpu = Chip8::builder().program(&[0x1f, 0xff]).build();
+        cpu.run_frame(2);
+        assert_eq!(cpu.error(), Some(&CpuError::Memory { pc: 0xfff, addr: 0x1000 }));
+        let mut cpu = Chip8::builder().program(&[0xd0, 0x01]).index(0xffff).build();
+        assert_eq!(cpu.emulate_op().unwrap_err(), CpuError::Memory { pc: 0x200, addr: 0xffff });
+
+        // JP V0, 0xfff runs off the end
+        let mut cpu = Chip8::builder().program(&[0xbf, 0xff]).register(0, 0xff).build();
+        cpu.run_frame(2);
+        assert_eq!(cpu.error(), Some(&CpuError::Memory { pc: 0x10fe, addr: 0x10fe }));
+
+        // Only the low digit of VX picks a font character
+        let mut cpu = Chip8::builder().program(&[0xf0, 0x29]).register(0, 0x1a).build();
+        cpu.emulate_op().unwrap();
+        assert_eq!(cpu.I, 50);
+    }
+
+    #[test]
+    fn random_programs_never_panic() {
+        let mut rng = Rng { state: 47 };
+        for _ in 0..500 {
+            let rom: Vec<u8> = (0..64).map(|_| rng.next() as u8).collect();
+            let mut cpu = Chip8::from_rom(&rom);
+            cpu.seed_rng(rng.next());
+            cpu.set_index(rng.next() as u16);
+            for _ in 0..20 {
+                cpu.set_keys(rng.next() as u16);
+                cpu.run_frame(50);
+            }
+        }
+    }
 }
@@ -0,0 +1,240 @@
+use crate::display::Screen;
+use crate::emulator::{Chip8, INSTRUCTIONS_PER_FRAME};
+use crate::quirks::Quirks;
+
+/// A byte of machine state the reward or the end of an episode is read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Register(u8),
+    Memory(u16),
+}
+
+impl Source {
+    pub fn read(self, chip8: &Chip8) -> u8 {
+        match self {
+            Source::Register(x) => chip8.registers()[(x & 0x0f) as usize],
+            Source::Memory(addr) => chip8.memory().get(addr as usize).copied().unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reward {
+    /// The change in the byte since the last step, e.g. a score. Negative
+    /// when the byte goes down.
+    Increase(Source),
+    /// The byte itself.
+    Value(Source),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Done {
+    /// The episode ends once the byte has this value, e.g. no lives left.
+    Equals(Source, u8),
+    /// The episode ends once the byte no longer has this value.
+    NotEquals(Source, u8),
+}
+
+impl Done {
+    fn check(self, chip8: &Chip8) -> bool {
+        match self {
+            Done::Equals(source, value) => source.read(chip8) == value,
+            Done::NotEquals(source, value) => source.read(chip8) != value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvConfig {
+    /// Frames run with the same action for every step.
+    pub frame_skip: u32,
+    pub instructions_per_frame: usize,
+    pub quirks: Quirks,
+    pub reward: Option<Reward>,
+    pub done: Option<Done>,
+    /// Ends episodes after this many frames.
+    pub max_frames: Option<u64>,
+    /// Seed for RND at every reset; a new random seed each time if unset.
+    pub seed: Option<u64>,
+}
+
+impl Default for EnvConfig {
+    fn default() -> Self {
+        EnvConfig {
+            frame_skip: 4,
+            instructions_per_frame: INSTRUCTIONS_PER_FRAME,
+            quirks: Quirks::default(),
+            reward: None,
+            done: None,
+            max_frames: None,
+            seed: None,
+        }
+    }
+}
+
+/// A gym-style environment for training agents on a rom. Actions are the
+/// keypad state to hold (one bit per hex key) and observations the
+/// 64×32 bit framebuffer. Nothing is drawn, so it runs as fast as the
+/// emulator does.
+pub struct Env {
+    rom: Vec<u8>,
+    config: EnvConfig,
+    chip8: Chip8,
+    // Reward source at the end of the last step
+    last: u8,
+    frames: u64,
+    done: bool,
+}
+
+impl Env {
+    pub fn new(rom: &[u8], config: EnvConfig) -> Self {
+        let mut env = Env {
+            rom: rom.to_vec(),
+            config,
+            chip8: Chip8::from_rom(rom),
+            last: 0,
+            frames: 0,
+            done: false,
+        };
+        env.reset();
+        env
+    }
+
+    /// Starts a new episode and returns the first observation.
+    pub fn reset(&mut self) -> Screen {
+        self.chip8 = Chip8::from_rom(&self.rom);
+        self.chip8.set_quirks(self.config.quirks);
+        if let Some(seed) = self.config.seed {
+            self.chip8.seed_rng(seed);
+        }
+        self.last = self.reward_source().map_or(0, |source| source.read(&self.chip8));
+        self.frames = 0;
+        self.done = false;
+        *self.chip8.screen()
+    }
+
+    /// Holds `action` for `frame_skip` frames, or until the episode ends.
+    /// Returns the observation, the reward collected and whether the episode
+    /// is over, which it also is once the rom crashes the machine. Stepping a
+    /// finished episode does nothing until `reset`.
+    pub fn step(&mut self, action: u16) -> (Screen, f32, bool) {
+        let mut reward = 0.0;
+        if self.done {
+            return (*self.chip8.screen(), reward, true);
+        }
+        self.chip8.set_keys(action);
+        for _ in 0..self.config.frame_skip.max(1) {
+            self.chip8.run_frame(self.config.instructions_per_frame);
+            self.frames += 1;
+            reward += self.collect();
+            self.done = self.chip8.error().is_some()
+                || self.config.done.is_some_and(|done| done.check(&self.chip8))
+                || self.config.max_frames.is_some_and(|max| self.frames >= max);
+            if self.done {
+                break;
+            }
+        }
+        (*self.chip8.screen(), reward, self.done)
+    }
+
+    fn reward_source(&self) -> Option<Source> {
+        match self.config.reward {
+            Some(Reward::Increase(source)) | Some(Reward::Value(source)) => Some(source),
+            None => None,
+        }
+    }
+
+    // Reward for the frame that just ran
+    fn collect(&mut self) -> f32 {
+        match self.config.reward {
+            Some(Reward::Increase(source)) => {
+                let value = source.read(&self.chip8);
+                let reward = value as f32 - self.last as f32;
+                self.last = value;
+                reward
+            }
+            Some(Reward::Value(source)) => source.read(&self.chip8) as f32,
+            None => 0.0,
+        }
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    pub fn chip8(&self) -> &Chip8 {
+        &self.chip8
+    }
+
+    pub fn config(&self) -> &EnvConfig {
+        &self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // V5 counts passes while key 1 is held; V6 turns 1 when it reaches 5
+    const ROM: [u8; 14] = [
+        0x61, 0x01, 0xe1, 0xa1, 0x75, 0x01, 0x35, 0x05, 0x12, 0x02, 0x66, 0x01,
+        0x12, 0x0c,
+    ];
+
+    fn config() -> EnvConfig {
+        EnvConfig {
+            frame_skip: 2,
+            instructions_per_frame: 4,
+            reward: Some(Reward::Increase(Source::Register(5))),
+            done: Some(Done::Equals(Source::Register(6), 1)),
+            seed: Some(1),
+            ..EnvConfig::default()
+        }
+    }
+
+    #[test]
+    fn episode() {
+        let mut env = Env::new(&ROM, config());
+        assert_eq!(env.step(0), (Screen::new(), 0.0, false));
+        let mut total = 0.0;
+        let mut steps = 0;
+        while !env.is_done() {
+            let (_, reward, _) = env.step(1 << 1);
+            total += reward;
+            steps += 1;
+            assert!(steps < 100);
+        }
+        assert_eq!(total, 5.0);
+        assert!(env.step(1 << 1).2);
+
+        env.reset();
+        assert!(!env.is_done());
+        assert_eq!(env.frames(), 0);
+        assert_eq!(env.chip8().registers()[5], 0);
+    }
+
+    #[test]
+    fn max_frames() {
+        let mut env = Env::new(&ROM, EnvConfig { max_frames: Some(5), ..config() });
+        assert!(!env.step(0).2);
+        assert!(!env.step(0).2);
+        assert!(env.step(0).2);
+        assert_eq!(env.frames(), 5);
+        assert_eq!(Source::Memory(0x200).read(env.chip8()), 0x61);
+    }
+
+    #[test]
+    fn crash_ends_episode() {
+        // LD V5, 3; RET with nothing to return to
+        let mut env = Env::new(&[0x65, 0x03, 0x00, 0xee], config());
+        let (_, reward, done) = env.step(0);
+        assert!(done);
+        assert_eq!(reward, 3.0);
+        assert_eq!(env.frames(), 1);
+        assert!(env.chip8().error().is_some());
+    }
+}
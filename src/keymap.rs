@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+// Physical key positions, named after the key found there on a US QWERTY
+// keyboard (the `code` names of browser keyboard events). One row per
+// keyboard row, in the same order as the characters in `Layout::rows`.
+const ROWS: [[&str; 10]; 4] = [
+    ["Digit1", "Digit2", "Digit3", "Digit4", "Digit5", "Digit6", "Digit7", "Digit8", "Digit9", "Digit0"],
+    ["KeyQ", "KeyW", "KeyE", "KeyR", "KeyT", "KeyY", "KeyU", "KeyI", "KeyO", "KeyP"],
+    ["KeyA", "KeyS", "KeyD", "KeyF", "KeyG", "KeyH", "KeyJ", "KeyK", "KeyL", "Semicolon"],
+    ["KeyZ", "KeyX", "KeyC", "KeyV", "KeyB", "KeyN", "KeyM", "Comma", "Period", "Slash"],
+];
+
+// Keys that sit in the same place on every layout
+const OTHER_CODES: [&str; 19] = [
+    "ArrowUp", "ArrowDown", "ArrowLeft", "ArrowRight", "Space", "Enter",
+    "Numpad0", "Numpad1", "Numpad2", "Numpad3", "Numpad4", "Numpad5", "Numpad6",
+    "Numpad7", "Numpad8", "Numpad9", "NumpadAdd", "NumpadSubtract", "NumpadEnter",
+];
+
+// The COSMAC VIP keypad laid over the left of the keyboard:
+//  1 2 3 C      1 2 3 4
+//  4 5 6 D  ->  Q W E R
+//  7 8 9 E      A S D F
+//  A 0 B F      Z X C V
+const DEFAULT: [&str; 16] = [
+    "KeyX", "Digit1", "Digit2", "Digit3",
+    "KeyQ", "KeyW", "KeyE", "KeyA",
+    "KeyS", "KeyD", "KeyZ", "KeyC",
+    "Digit4", "KeyR", "KeyF", "KeyV",
+];
+
+/// Keyboard layout the user types on. Frontends that only see characters
+/// use it to find the physical position of a key, so a keymap means the same
+/// keys on every layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    #[default]
+    Qwerty,
+    Qwertz,
+    Azerty,
+    Dvorak,
+}
+
+impl FromStr for Layout {
+    type Err = KeymapError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "qwerty" => Ok(Layout::Qwerty),
+            "qwertz" => Ok(Layout::Qwertz),
+            "azerty" => Ok(Layout::Azerty),
+            "dvorak" => Ok(Layout::Dvorak),
+            _ => Err(KeymapError::UnknownLayout(s.to_owned())),
+        }
+    }
+}
+
+impl Layout {
+    // Unshifted characters of each row in `ROWS`
+    fn rows(self) -> [&'static str; 4] {
+        match self {
+            Layout::Qwerty => ["1234567890", "qwertyuiop", "asdfghjkl;", "zxcvbnm,./"],
+            Layout::Qwertz => ["1234567890", "qwertzuiop", "asdfghjklö", "yxcvbnm,.-"],
+            Layout::Azerty => ["&é\"'(-è_çà", "azertyuiop", "qsdfghjklm", "wxcvbn,;:!"],
+            Layout::Dvorak => ["1234567890", "',.pyfgcrl", "aoeuidhtns", ";qjkxbmwvz"],
+        }
+    }
+
+    /// Physical position of the key that types `c` on this layout.
+    pub fn code(self, c: char) -> Option<&'static str> {
+        let c = c.to_lowercase().next()?;
+        self.rows()
+            .iter()
+            .zip(ROWS.iter())
+            .find_map(|(chars, codes)| {
+                chars.chars().position(|other| other == c).map(|i| codes[i])
+            })
+            // Shifted digits are still digits on AZERTY
+            .or_else(|| c.to_digit(10).map(|digit| ROWS[0][(digit as usize + 9) % 10]))
+    }
+}
+
+#[derive(Debug)]
+pub enum KeymapError {
+    Io(io::Error),
+    Toml(toml::de::Error),
+    UnknownHexKey(String),
+    UnknownCode(String),
+    UnknownLayout(String),
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeymapError::Io(e) => write!(f, "Could not read keymap: {}", e),
+            KeymapError::Toml(e) => write!(f, "Invalid keymap: {}", e),
+            KeymapError::UnknownHexKey(key) => write!(f, "Not a hex key: {}", key),
+            KeymapError::UnknownCode(code) => write!(f, "Unknown key position: {}", code),
+            KeymapError::UnknownLayout(layout) => write!(f, "Unknown keyboard layout: {}", layout),
+        }
+    }
+}
+
+impl std::error::Error for KeymapError {}
+
+impl From<io::Error> for KeymapError {
+    fn from(e: io::Error) -> Self {
+        KeymapError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for KeymapError {
+    fn from(e: toml::de::Error) -> Self {
+        KeymapError::Toml(e)
+    }
+}
+
+// layout = "azerty"
+//
+// [keymap]
+// c = ["Digit4", "ArrowUp"]
+//
+// [roms.PONG.keymap]
+// 1 = ["KeyW"]
+#[derive(Debug, Default, Deserialize)]
+struct KeymapFile {
+    layout: Option<Layout>,
+    #[serde(default)]
+    keymap: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    roms: HashMap<String, RomSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct RomSection {
+    #[serde(default)]
+    keymap: HashMap<String, Vec<String>>,
+}
+
+/// Maps physical key positions to the 16 hex keys. Several positions may
+/// map to the same hex key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    layout: Layout,
+    keys: [Vec<String>; 16],
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap {
+            layout: Layout::default(),
+            keys: DEFAULT.map(|code| vec![code.to_owned()]),
+        }
+    }
+}
+
+impl Keymap {
+    /// Reads a keymap file, applying the overrides for `rom` (the rom's file
+    /// name without extension) on top of the default mapping.
+    pub fn load(path: &Path, rom: Option<&str>) -> Result<Self, KeymapError> {
+        Keymap::from_toml(&fs::read_to_string(path)?, rom)
+    }
+
+    pub fn from_toml(text: &str, rom: Option<&str>) -> Result<Self, KeymapError> {
+        let file: KeymapFile = toml::from_str(text)?;
+        let mut keymap = Keymap::default();
+        if let Some(layout) = file.layout {
+            keymap.layout = layout;
+        }
+        keymap.apply(&file.keymap)?;
+        if let Some(section) = rom.and_then(|rom| file.roms.get(rom)) {
+            keymap.apply(&section.keymap)?;
+        }
+        Ok(keymap)
+    }
+
+    fn apply(&mut self, keymap: &HashMap<String, Vec<String>>) -> Result<(), KeymapError> {
+        for (key, codes) in keymap {
+            let hex = u8::from_str_radix(key.trim_start_matches("0x"), 16)
+                .ok()
+                .filter(|&hex| hex < 16)
+                .ok_or_else(|| KeymapError::UnknownHexKey(key.clone()))?;
+            if let Some(code) = codes.iter().find(|code| !is_code(code)) {
+                return Err(KeymapError::UnknownCode(code.clone()));
+            }
+            self.keys[hex as usize] = codes.clone();
+        }
+        Ok(())
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn set_layout(&mut self, layout: Layout) {
+        self.layout = layout;
+    }
+
+    /// Adds a physical key position for `hex`.
+    pub fn bind(&mut self, hex: u8, code: &str) {
+        self.keys[(hex & 0x0f) as usize].push(code.to_owned());
+    }
+
+    pub fn codes(&self, hex: u8) -> &[String] {
+        &self.keys[(hex & 0x0f) as usize]
+    }
+
+    /// Hex key bound to a physical key position.
+    pub fn hex_key(&self, code: &str) -> Option<u8> {
+        (0..16).find(|&hex| self.keys[hex as usize].iter().any(|other| other == code))
+    }
+
+    /// Hex key bound to the key that types `c` on the configured layout.
+    pub fn hex_key_for_char(&self, c: char) -> Option<u8> {
+        self.layout.code(c).and_then(|code| self.hex_key(code))
+    }
+}
+
+fn is_code(code: &str) -> bool {
+    ROWS.iter().flatten().chain(OTHER_CODES.iter()).any(|&other| other == code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_keymap() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.hex_key("Digit4"), Some(0xc));
+        assert_eq!(keymap.hex_key("KeyX"), Some(0x0));
+        assert_eq!(keymap.hex_key_for_char('V'), Some(0xf));
+        assert_eq!(keymap.hex_key_for_char('p'), None);
+    }
+
+    #[test]
+    fn layouts_use_positions() {
+        for &(layout, chars) in &[
+            (Layout::Qwerty, "1qaz"),
+            (Layout::Azerty, "&aqw"),
+            (Layout::Qwertz, "1qay"),
+            (Layout::Dvorak, "1'a;"),
+        ] {
+            let keymap = Keymap { layout, ..Keymap::default() };
+            let keys: Vec<Option<u8>> = chars.chars().map(|c| keymap.hex_key_for_char(c)).collect();
+            assert_eq!(keys, [Some(0x1), Some(0x4), Some(0x7), Some(0xa)], "{:?}", layout);
+        }
+    }
+
+    #[test]
+    fn rom_overrides() {
+        let text = r#"
+            layout = "azerty"
+
+            [keymap]
+            c = ["Digit4", "ArrowUp"]
+
+            [roms.PONG.keymap]
+            1 = ["KeyW", "ArrowLeft"]
+        "#;
+        let keymap = Keymap::from_toml(text, Some("PONG")).unwrap();
+        assert_eq!(keymap.layout(), Layout::Azerty);
+        assert_eq!(keymap.hex_key("ArrowUp"), Some(0xc));
+        assert_eq!(keymap.hex_key("Digit4"), Some(0xc));
+        assert_eq!(keymap.hex_key("ArrowLeft"), Some(0x1));
+        assert_eq!(keymap.hex_key("Digit1"), None);
+
+        let keymap = Keymap::from_toml(text, Some("TETRIS")).unwrap();
+        assert_eq!(keymap.hex_key("Digit1"), Some(0x1));
+    }
+
+    #[test]
+    fn invalid_keymaps() {
+        assert!(matches!(Keymap::from_toml("[keymap]\ng = [\"KeyQ\"]", None), Err(KeymapError::UnknownHexKey(_))));
+        assert!(matches!(Keymap::from_toml("[keymap]\n1 = [\"KeyÆ\"]", None), Err(KeymapError::UnknownCode(_))));
+        assert!(matches!(Keymap::from_toml("layout = \"bepo\"", None), Err(KeymapError::Toml(_))));
+    }
+}
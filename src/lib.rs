@@ -0,0 +1,20 @@
+pub mod analysis;
+pub mod batch;
+pub mod cheats;
+pub mod cli;
+pub mod config;
+pub mod control;
+pub mod coverage;
+pub mod database;
+pub mod disasm;
+pub mod display;
+pub mod emulator;
+pub mod env;
+pub mod keymap;
+pub mod movie;
+pub mod patch;
+pub mod phosphor;
+pub mod profile;
+pub mod quirks;
+pub mod sprites;
+pub mod trace;
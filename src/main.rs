@@ -1,72 +1,320 @@
-pub mod emulator;
 extern crate minifb;
 
-use minifb::{Key, Window, WindowOptions};
+use minifb::{Key, KeyRepeat, Scale, Window, WindowOptions};
 
-use crate::emulator::{Chip8, WIDTH, HEIGHT, Event, Action};
+use chip8::analysis::Analysis;
+use chip8::cheats::{Compare, SearchCommand, SearchControls};
+use chip8::cli::{Cli, Command, CoverageArgs, Options, ProfileArgs, RunArgs, TraceArgs};
+use chip8::control::{Controls, Speed};
+use chip8::display::OFF;
+use chip8::emulator::{Chip8, WIDTH, HEIGHT};
+use chip8::keymap::Keymap;
+use chip8::movie::{Movie, Session};
+use chip8::patch::{self, PatchedRom};
+use chip8::phosphor::Phosphor;
+use chip8::sprites::{SpriteRecorder, Sprites};
 
-use std::env;
+use clap::Parser;
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+const TITLE: &str = "Chip8 Interperter";
+const SEARCH_KEYS: [(Key, SearchCommand); 6] = [
+    (Key::F1, SearchCommand::Start),
+    (Key::F2, SearchCommand::Narrow(Compare::Equal)),
+    (Key::F3, SearchCommand::Narrow(Compare::Changed)),
+    (Key::F4, SearchCommand::Narrow(Compare::Increased)),
+    (Key::F5, SearchCommand::Narrow(Compare::Decreased)),
+    (Key::F6, SearchCommand::Freeze),
+];
 
 fn main() {
-    
-    let filename = env::args().nth(1).expect("Needs a file");
-    let mut chip8 = Chip8::new(&filename);
+    let cli = Cli::parse();
+    let result = match cli.command {
+        Command::Run(args) => run(&args),
+        Command::Config(options) => show_config(&options),
+        Command::Replay { movie, rom, patch, trace, profile, coverage } => {
+            replay(&movie, &rom, patch.as_deref(), &trace, &profile, &coverage)
+        }
+        Command::Analyze { rom, patch, dot } => analyze(&rom, patch.as_deref(), dot.as_deref()),
+        Command::Sprites { rom, frames, movie, png, options } => {
+            sprites(&rom, frames, movie.as_deref(), png.as_deref(), &options)
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn show_config(options: &Options) -> Result<(), Box<dyn Error>> {
+    match options.config_path() {
+        Some(path) if path.exists() => println!("# Config file: {}", path.display()),
+        Some(path) => println!("# Config file: {} (not found)", path.display()),
+        None => println!("# No config file location"),
+    }
+    let (settings, _) = options.load(None)?;
+    print!("{}", settings);
+    Ok(())
+}
+
+fn replay(
+    movie: &Path,
+    rom: &Path,
+    patch: Option<&Path>,
+    trace: &TraceArgs,
+    profile: &ProfileArgs,
+    coverage: &CoverageArgs,
+) -> Result<(), Box<dyn Error>> {
+    let movie = Movie::load(movie)?;
+    let rom = patch::load_rom(rom, patch)?;
+    let mut observers = (trace.open()?, (profile.profiler(), coverage.coverage()));
+    let chip8 = movie.play_observed(&rom, &mut observers);
+    let (trace, (profiler, covered)) = observers;
+    if let Some(trace) = trace {
+        trace.finish()?;
+    }
+    if let Some(profiler) = profiler {
+        profile.write(&profiler)?;
+    }
+    if let Some(covered) = covered {
+        coverage.write(&covered, &rom)?;
+    }
+    let chip8 = chip8?;
+    if let Some(e) = chip8.error() {
+        println!("stopped: {}", e);
+        for call in chip8.backtrace() {
+            println!("  {}", call);
+        }
+    }
+    println!("frames: {}", movie.frames());
+    println!("hash: {:016x}", chip8.state_hash());
+    print!("{}", chip8.screen());
+    Ok(())
+}
+
+fn analyze(rom: &Path, patch: Option<&Path>, dot: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let rom = patch::load_rom(rom, patch)?;
+    let analysis = Analysis::analyze(&rom);
+    print!("{}", analysis);
+    if let Some(path) = dot {
+        fs::write(path, analysis.to_dot()).map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+fn sprites(
+    path: &Path,
+    frames: Option<u32>,
+    movie: Option<&Path>,
+    png: Option<&Path>,
+    options: &Options,
+) -> Result<(), Box<dyn Error>> {
+    let PatchedRom { original, patched: rom } = options.read_rom(path)?;
+    let mut sprites = Sprites::find_static(&rom);
+    let mut recorder = SpriteRecorder::new();
+    if let Some(movie) = movie {
+        Movie::load(movie)?.play_observed(&rom, &mut recorder)?;
+    } else if let Some(frames) = frames {
+        let (settings, _) = options.load_rom(path, &original)?;
+        let mut chip8 = Chip8::from_rom(&rom);
+        settings.apply(&mut chip8);
+        for _ in 0..frames {
+            chip8.run_frame_observed(settings.instructions_per_frame(), &mut recorder);
+        }
+    }
+    sprites.merge(recorder.into_sprites());
+    match png {
+        Some(png) => {
+            let file = fs::File::create(png).map_err(|e| format!("Could not create {}: {}", png.display(), e))?;
+            sprites.write_png(std::io::BufWriter::new(file))?;
+            println!("{} sprites", sprites.len());
+        }
+        None => print!("{}", sprites.to_ascii()),
+    }
+    Ok(())
+}
+
+fn run(args: &RunArgs) -> Result<(), Box<dyn Error>> {
+    let PatchedRom { original, patched: rom } = args.options.read_rom(&args.rom)?;
+    let (settings, keymap) = args.options.load_rom(&args.rom, &original)?;
+    let (mut chip8, mut session) = args.movie.start(&rom, &settings)?;
+    session.set_cheats(args.options.cheats(&args.rom)?);
+    let mut observers = (args.trace.open()?, (args.profile.profiler(), args.coverage.coverage()));
+    let palette = settings.palette();
+    let mut phosphor = settings.phosphor.map(|mode| {
+        let mut phosphor = Phosphor::new(mode);
+        phosphor.set_palette(palette);
+        phosphor
+    });
+
+    let scale = match settings.scale() {
+        1 => Scale::X1,
+        2 => Scale::X2,
+        4 => Scale::X4,
+        8 => Scale::X8,
+        16 => Scale::X16,
+        32 => Scale::X32,
+        scale => return Err(format!("Unsupported scale: {} (expected 1, 2, 4, 8, 16 or 32)", scale).into()),
+    };
     let mut window = Window::new(
-        "Chip8 Interperter",
+        TITLE,
         WIDTH,
         HEIGHT,
-        WindowOptions::default()
-    ).unwrap();
+        WindowOptions { scale, ..WindowOptions::default() }
+    )?;
 
+    let mut buffer = [OFF; WIDTH * HEIGHT];
+    let mut controls = Controls::new();
+    let mut search = SearchControls::new();
+    let cheats_path = args.options.cheats_path(&args.rom);
+    // The window has no sound output, but the setting still applies to the controls
+    controls.set_mute(settings.mute());
+    let mut shown_title = TITLE.to_owned();
+    // The screen stays up once the program crashed
+    let mut stopped = false;
     window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        let key = window
+        // Keys the keymap binds stay CHIP-8 keys
+        let hotkey = |key, repeat| window.is_key_pressed(key, repeat) && to_valid_key(&keymap, key).is_none();
+        if hotkey(Key::P, KeyRepeat::No) {
+            controls.toggle_pause();
+        }
+        if hotkey(Key::N, KeyRepeat::Yes) {
+            controls.advance();
+        }
+        if hotkey(Key::Tab, KeyRepeat::No) {
+            controls.toggle_speed(settings.fast_forward());
+        }
+        if hotkey(Key::M, KeyRepeat::No) {
+            controls.toggle_speed(settings.slow_motion());
+        }
+        for &(key, command) in &SEARCH_KEYS {
+            if window.is_key_pressed(key, KeyRepeat::No) {
+                eprintln!("{}", search.run_and_save(command, &chip8, session.cheats_mut(), &cheats_path));
+            }
+        }
+        let status = title(&controls, &session);
+        if status != shown_title {
+            window.set_title(&status);
+            shown_title = status;
+        }
+
+        let keys = window
             .get_keys()
-            .unwrap_or_else(|| vec![])
-            .pop();
-        let key = to_valid_key(key).map(|k| Event::KeyPress(k));
-        match chip8.emulate_op(key) {
-            Some(Action::DisplayScreen(screen)) => {
-                window
-                    .update_with_buffer(&screen[..], WIDTH, HEIGHT)
-                    .unwrap();
-            },
-            Some(Action::WaitForKeyPress) => {
-                loop {
-                    let key = window.get_keys().unwrap_or_else(|| vec![]).pop();
-                    match to_valid_key(key) {
-                        Some(key) => {
-                            chip8.emulate_op(Some(Event::WaitingKeyPress(key)));
-                            break;
-                        }
-                        _ => window.update(),
-                    }
-                }
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|key| to_valid_key(&keymap, key))
+            .fold(0, |keys, key| keys | (1 << key));
+        let frames = controls.frames();
+        match session.run_observed(&mut chip8, frames, keys, &mut observers) {
+            Some(Ok(())) => eprintln!("Replay finished in the recorded state"),
+            Some(Err(e)) => eprintln!("{}", e),
+            None => (),
+        }
+        if let Some(e) = chip8.error().filter(|_| !stopped) {
+            eprintln!("{}", e);
+            for call in chip8.backtrace() {
+                eprintln!("  {}", call);
             }
-            _ => (),
+            stopped = true;
         }
-        chip8.decreament_timer();
+
+        let screen = match phosphor.as_mut() {
+            Some(phosphor) if frames > 0 => phosphor.apply(chip8.screen()),
+            Some(phosphor) => phosphor.output(),
+            None => {
+                chip8.screen().to_buffer(&mut buffer, &palette);
+                &buffer
+            }
+        };
+        window.update_with_buffer(&screen[..], WIDTH, HEIGHT)?;
+    }
+    session.finish(&chip8)?;
+    let (trace, (profiler, coverage)) = observers;
+    if let Some(trace) = trace {
+        trace.finish()?;
+    }
+    if let Some(profiler) = profiler {
+        args.profile.write(&profiler)?;
+    }
+    if let Some(coverage) = coverage {
+        args.coverage.write(&coverage, &rom)?;
     }
+    Ok(())
 }
 
-fn to_valid_key(key: Option<Key>) -> Option<u8> {
-    match key {
-        Some(Key::Key1) => Some(0x1),
-        Some(Key::Key2) => Some(0x2),
-        Some(Key::Key3) => Some(0x3),
-        Some(Key::Key4) => Some(0xc),
-        Some(Key::Q)    => Some(0x4),
-        Some(Key::W)    => Some(0x5),
-        Some(Key::E)    => Some(0x6),
-        Some(Key::R)    => Some(0xd),
-        Some(Key::A)    => Some(0x7),
-        Some(Key::S)    => Some(0x8),
-        Some(Key::D)    => Some(0x9),
-        Some(Key::F)    => Some(0xe),
-        Some(Key::Z)    => Some(0xa),
-        Some(Key::X)    => Some(0x0),
-        Some(Key::C)    => Some(0xb),
-        Some(Key::V)    => Some(0xf),
-        _               => None,
+fn title(controls: &Controls, session: &Session) -> String {
+    let title = if session.is_recording() {
+        format!("{} [recording]", TITLE)
+    } else if session.is_replaying() {
+        format!("{} [replay]", TITLE)
+    } else {
+        TITLE.to_owned()
+    };
+    match (controls.is_paused(), controls.speed()) {
+        (true, _) => format!("{} (paused)", title),
+        (false, Speed::Normal) => title,
+        (false, Speed::FastForward(frames)) => format!("{} (x{})", title, frames),
+        (false, Speed::SlowMotion(ticks)) => format!("{} (1/{})", title, ticks),
     }
 }
+
+fn to_valid_key(keymap: &Keymap, key: Key) -> Option<u8> {
+    // Keys that are in the same place on every layout
+    let code = match key {
+        Key::Up          => "ArrowUp",
+        Key::Down        => "ArrowDown",
+        Key::Left        => "ArrowLeft",
+        Key::Right       => "ArrowRight",
+        Key::Space       => "Space",
+        Key::Enter       => "Enter",
+        Key::NumPad0     => "Numpad0",
+        Key::NumPad1     => "Numpad1",
+        Key::NumPad2     => "Numpad2",
+        Key::NumPad3     => "Numpad3",
+        Key::NumPad4     => "Numpad4",
+        Key::NumPad5     => "Numpad5",
+        Key::NumPad6     => "Numpad6",
+        Key::NumPad7     => "Numpad7",
+        Key::NumPad8     => "Numpad8",
+        Key::NumPad9     => "Numpad9",
+        Key::NumPadPlus  => "NumpadAdd",
+        Key::NumPadMinus => "NumpadSubtract",
+        Key::NumPadEnter => "NumpadEnter",
+        // minifb names keys after the character they type, so those go
+        // through the keyboard layout to find their position
+        _ => return to_char(key).and_then(|c| keymap.hex_key_for_char(c)),
+    };
+    keymap.hex_key(code)
+}
+
+fn to_char(key: Key) -> Option<char> {
+    let c = match key {
+        Key::Key0 => '0',
+        Key::Key1 => '1',
+        Key::Key2 => '2',
+        Key::Key3 => '3',
+        Key::Key4 => '4',
+        Key::Key5 => '5',
+        Key::Key6 => '6',
+        Key::Key7 => '7',
+        Key::Key8 => '8',
+        Key::Key9 => '9',
+        Key::Semicolon  => ';',
+        Key::Comma      => ',',
+        Key::Period     => '.',
+        Key::Slash      => '/',
+        Key::Apostrophe => '\'',
+        Key::Minus      => '-',
+        // Key::A to Key::Z are numbered in order
+        key if (Key::A as u8..=Key::Z as u8).contains(&(key as u8)) => {
+            (b'a' + (key as u8 - Key::A as u8)) as char
+        }
+        _ => return None,
+    };
+    Some(c)
+}
@@ -0,0 +1,394 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cheats::Cheats;
+use crate::database::Database;
+use crate::emulator::{Chip8, Observer};
+use crate::quirks::{Quirks, Variant};
+
+// Bumped whenever a movie would stop replaying to its stored hash, e.g. with a new RNG.
+pub const MOVIE_VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Write(toml::ser::Error),
+    /// The movie was recorded with a different rom (SHA-1 of the rom it needs).
+    WrongRom(String),
+    /// Recorded by an emulator that runs roms differently from this one.
+    Version(u32),
+    /// The replay ended in another state than the recording.
+    Desync { expected: u64, actual: u64 },
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Io(e) => write!(f, "Could not access movie: {}", e),
+            MovieError::Parse(e) => write!(f, "Invalid movie: {}", e),
+            MovieError::Write(e) => write!(f, "Could not write movie: {}", e),
+            MovieError::WrongRom(sha1) => write!(f, "The movie was recorded with another rom (SHA-1 {})", sha1),
+            MovieError::Version(version) => {
+                write!(f, "The movie is version {}, this emulator only replays version {}", version, MOVIE_VERSION)
+            }
+            MovieError::Desync { expected, actual } => {
+                write!(f, "Replay ended in state {:016x} instead of {:016x}", actual, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+impl From<io::Error> for MovieError {
+    fn from(e: io::Error) -> Self {
+        MovieError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for MovieError {
+    fn from(e: toml::de::Error) -> Self {
+        MovieError::Parse(e)
+    }
+}
+
+impl From<toml::ser::Error> for MovieError {
+    fn from(e: toml::ser::Error) -> Self {
+        MovieError::Write(e)
+    }
+}
+
+/// A recorded session: everything needed to run a rom again exactly the same
+/// way, and the keypad state of every emulated frame.
+///
+/// ```toml
+/// version = 1
+/// rom = "c645b4600fd858a1ea4a910b838a4d11b50a6071"
+/// seed = 1234
+/// instructions-per-frame = 10
+/// variant = "chip8"
+/// hash = "3f0e5c2ab2d64c19"
+/// # [frames, keys] pairs: the keys held for that many frames in a row
+/// input = [[30, 0], [4, 0x20], [10, 0]]
+///
+/// [quirks]
+/// ...
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Movie {
+    pub version: u32,
+    /// SHA-1 of the rom.
+    pub rom: String,
+    pub seed: u64,
+    pub instructions_per_frame: usize,
+    pub variant: Variant,
+    /// State hash at the end of the recording.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(default)]
+    input: Vec<(u32, u16)>,
+    pub quirks: Quirks,
+}
+
+impl Movie {
+    pub fn new(rom: &[u8], seed: u64, instructions_per_frame: usize, variant: Variant, quirks: Quirks) -> Self {
+        Movie {
+            version: MOVIE_VERSION,
+            rom: Database::sha1(rom),
+            seed,
+            instructions_per_frame,
+            variant,
+            hash: None,
+            input: Vec::new(),
+            quirks,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, MovieError> {
+        Movie::from_toml(&fs::read_to_string(path)?)
+    }
+
+    // Movies of other versions would not replay the same, so they are refused
+    pub fn from_toml(text: &str) -> Result<Self, MovieError> {
+        let movie: Movie = toml::from_str(text)?;
+        if movie.version != MOVIE_VERSION {
+            return Err(MovieError::Version(movie.version));
+        }
+        Ok(movie)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), MovieError> {
+        fs::write(path, self.to_toml()?)?;
+        Ok(())
+    }
+
+    pub fn to_toml(&self) -> Result<String, MovieError> {
+        Ok(toml::to_string(self)?)
+    }
+
+    // Adds the keypad state of the next emulated frame
+    pub fn record(&mut self, keys: u16) {
+        match self.input.last_mut() {
+            Some((frames, last)) if *last == keys => *frames += 1,
+            _ => self.input.push((1, keys)),
+        }
+    }
+
+    /// Stores the state the recording ended in, to check replays against.
+    pub fn finish(&mut self, chip8: &Chip8) {
+        self.hash = Some(format!("{:016x}", chip8.state_hash()));
+    }
+
+    pub fn frames(&self) -> usize {
+        self.input.iter().map(|&(frames, _)| frames as usize).sum()
+    }
+
+    /// Keypad state of each frame in order.
+    pub fn keys(&self) -> impl Iterator<Item = u16> + '_ {
+        self.input.iter().flat_map(|&(frames, keys)| (0..frames).map(move |_| keys))
+    }
+
+    /// A machine set up the way the recording started.
+    pub fn start(&self, rom: &[u8]) -> Result<Chip8, MovieError> {
+        if Database::sha1(rom) != self.rom {
+            return Err(MovieError::WrongRom(self.rom.clone()));
+        }
+        let mut chip8 = Chip8::from_rom(rom);
+        chip8.set_variant(self.variant);
+        chip8.set_quirks(self.quirks);
+        chip8.seed_rng(self.seed);
+        Ok(chip8)
+    }
+
+    /// Runs the whole movie without a frontend and checks that it ends the
+    /// way the recording did.
+    pub fn play(&self, rom: &[u8]) -> Result<Chip8, MovieError> {
+        self.play_observed(rom, &mut ())
+    }
+
+    pub fn play_observed<O: Observer + ?Sized>(&self, rom: &[u8], observer: &mut O) -> Result<Chip8, MovieError> {
+        let mut chip8 = self.start(rom)?;
+        for keys in self.keys() {
+            chip8.set_keys(keys);
+            chip8.run_frame_observed(self.instructions_per_frame, observer);
+        }
+        self.check(&chip8)?;
+        Ok(chip8)
+    }
+
+    pub fn check(&self, chip8: &Chip8) -> Result<(), MovieError> {
+        let expected = match self.hash.as_deref().map(|hash| u64::from_str_radix(hash, 16)) {
+            Some(Ok(expected)) => expected,
+            // Nothing to check against
+            _ => return Ok(()),
+        };
+        let actual = chip8.state_hash();
+        if actual == expected {
+            Ok(())
+        } else {
+            Err(MovieError::Desync { expected, actual })
+        }
+    }
+}
+
+enum Mode {
+    Live,
+    Record(Movie, PathBuf),
+    Replay(Movie, Vec<u16>, usize),
+}
+
+/// Runs the emulated frames of a frontend, recording the keys to a movie or
+/// taking them from one. A replay goes back to live input when it ends.
+/// Cheats only apply to live play, so movies stay in sync.
+pub struct Session {
+    instructions: usize,
+    mode: Mode,
+    cheats: Cheats,
+}
+
+impl Session {
+    pub fn live(instructions: usize) -> Self {
+        Session { instructions, mode: Mode::Live, cheats: Cheats::new() }
+    }
+
+    // Saved to `path` by `finish`
+    pub fn record(movie: Movie, path: PathBuf) -> Self {
+        Session { instructions: movie.instructions_per_frame, mode: Mode::Record(movie, path), cheats: Cheats::new() }
+    }
+
+    pub fn replay(movie: Movie) -> Self {
+        let keys = movie.keys().collect();
+        Session { instructions: movie.instructions_per_frame, mode: Mode::Replay(movie, keys, 0), cheats: Cheats::new() }
+    }
+
+    /// Values frozen before every live frame.
+    pub fn set_cheats(&mut self, cheats: Cheats) {
+        self.cheats = cheats;
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut Cheats {
+        &mut self.cheats
+    }
+
+    pub fn is_recording(&self) -> bool {
+        matches!(self.mode, Mode::Record(..))
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        matches!(self.mode, Mode::Replay(..))
+    }
+
+    /// Runs `frames` frames with the live `keys`, unless a replay supplies
+    /// them. Returns whether the replay ended in the recorded state once it
+    /// runs out.
+    pub fn run(&mut self, chip8: &mut Chip8, frames: u32, keys: u16) -> Option<Result<(), MovieError>> {
+        self.run_observed(chip8, frames, keys, &mut ())
+    }
+
+    pub fn run_observed<O: Observer + ?Sized>(
+        &mut self,
+        chip8: &mut Chip8,
+        frames: u32,
+        keys: u16,
+        observer: &mut O,
+    ) -> Option<Result<(), MovieError>> {
+        let mut result = None;
+        for _ in 0..frames {
+            let keys = match &mut self.mode {
+                Mode::Live => {
+                    self.cheats.apply(chip8);
+                    keys
+                }
+                Mode::Record(movie, _) => {
+                    movie.record(keys);
+                    keys
+                }
+                Mode::Replay(movie, recorded, frame) => match recorded.get(*frame) {
+                    Some(&keys) => {
+                        *frame += 1;
+                        keys
+                    }
+                    None => {
+                        result = Some(movie.check(chip8));
+                        self.mode = Mode::Live;
+                        keys
+                    }
+                },
+            };
+            chip8.set_keys(keys);
+            chip8.run_frame_observed(self.instructions, observer);
+        }
+        // Check as soon as the last recorded frame has run
+        if let Mode::Replay(movie, recorded, frame) = &self.mode {
+            if *frame == recorded.len() {
+                result = Some(movie.check(chip8));
+                self.mode = Mode::Live;
+            }
+        }
+        result
+    }
+
+    /// Saves the recording, if there is one.
+    pub fn finish(self, chip8: &Chip8) -> Result<(), MovieError> {
+        match self.mode {
+            Mode::Record(mut movie, path) => {
+                movie.finish(chip8);
+                movie.save(&path)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cheats::{Cheat, Target};
+
+    // Draws dots at random columns, one row higher on every pass while 2 is held
+    const ROM: [u8; 21] = [
+        0xa2, 0x14, 0x60, 0x20, 0x61, 0x10, 0x62, 0x02, 0xe2, 0xa1, 0x71, 0xff,
+        0xc0, 0x3f, 0xd0, 0x11, 0x12, 0x08, 0x00, 0x00, 0x80,
+    ];
+
+    #[test]
+    fn record_and_play() {
+        let mut movie = Movie::new(&ROM, 42, 4, Variant::Chip8, Quirks::default());
+        let mut chip8 = movie.start(&ROM).unwrap();
+        for frame in 0..20u16 {
+            let keys = if frame % 5 == 0 { 1 << 2 } else { 0 };
+            movie.record(keys);
+            chip8.set_keys(keys);
+            chip8.run_frame(4);
+        }
+        movie.finish(&chip8);
+        assert_eq!(movie.frames(), 20);
+
+        let movie = Movie::from_toml(&movie.to_toml().unwrap()).unwrap();
+        let replay = movie.play(&ROM).unwrap();
+        assert_eq!(replay.state_hash(), chip8.state_hash());
+        assert_eq!(replay.screen(), chip8.screen());
+    }
+
+    #[test]
+    fn replay_session() {
+        let mut movie = Movie::new(&ROM, 7, 4, Variant::Chip8, Quirks::default());
+        for frame in 0..10 {
+            movie.record(if frame < 6 { 1 << 2 } else { 0 });
+        }
+        let expected = movie.play(&ROM).unwrap();
+        movie.finish(&expected);
+
+        let mut chip8 = movie.start(&ROM).unwrap();
+        let mut session = Session::replay(movie);
+        // Live keys are ignored until the movie runs out
+        assert!(session.run(&mut chip8, 4, 0).is_none());
+        assert!(matches!(session.run(&mut chip8, 6, 0), Some(Ok(()))));
+        assert!(!session.is_replaying());
+        assert_eq!(chip8.screen(), expected.screen());
+    }
+
+    #[test]
+    fn cheats_only_apply_live() {
+        let mut cheats = Cheats::new();
+        cheats.add(Cheat { target: Target::Register(5), value: 7, name: String::new() });
+        let mut movie = Movie::new(&ROM, 7, 4, Variant::Chip8, Quirks::default());
+        movie.record(0);
+        movie.record(0);
+
+        let mut chip8 = movie.start(&ROM).unwrap();
+        let mut session = Session::replay(movie);
+        session.set_cheats(cheats.clone());
+        session.run(&mut chip8, 1, 0);
+        assert_eq!(chip8.registers()[5], 0);
+
+        let mut session = Session::live(4);
+        session.set_cheats(cheats);
+        session.run(&mut chip8, 1, 0);
+        assert_eq!(chip8.registers()[5], 7);
+    }
+
+    #[test]
+    fn desync() {
+        let mut movie = Movie::new(&ROM, 42, 4, Variant::Chip8, Quirks::default());
+        movie.record(0);
+        movie.hash = Some(format!("{:016x}", 1));
+        assert!(matches!(movie.play(&ROM), Err(MovieError::Desync { .. })));
+        assert!(matches!(movie.play(&[0x12, 0x00]), Err(MovieError::WrongRom(_))));
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let movie = Movie::new(&ROM, 42, 4, Variant::Chip8, Quirks::default());
+        let text = movie.to_toml().unwrap();
+        assert_eq!(Movie::from_toml(&text).unwrap(), movie);
+        let newer = text.replace("version = 1\n", "version = 2\n");
+        assert!(matches!(Movie::from_toml(&newer), Err(MovieError::Version(2))));
+    }
+}
@@ -0,0 +1,342 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum PatchError {
+    Io(PathBuf, io::Error),
+    /// Neither an IPS nor a BPS patch.
+    Format,
+    /// The patch ends in the middle of a record.
+    Truncated,
+    /// A record points outside the rom or the output.
+    Invalid(&'static str),
+    /// The BPS patch was made for another rom.
+    WrongRom { expected: u32, actual: u32 },
+    /// The BPS checksum of the patch itself or of its output does not match.
+    Checksum(&'static str),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::Io(path, e) => write!(f, "Could not read {}: {}", path.display(), e),
+            PatchError::Format => write!(f, "Not an IPS or BPS patch"),
+            PatchError::Truncated => write!(f, "Patch is cut short"),
+            PatchError::Invalid(what) => write!(f, "Invalid patch: {}", what),
+            PatchError::WrongRom { expected, actual } => {
+                write!(f, "The patch is for another rom (CRC32 {:08x}, not {:08x})", expected, actual)
+            }
+            PatchError::Checksum(what) => write!(f, "Patch checksum mismatch: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// A rom as read from disk, and as its patch leaves it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchedRom {
+    pub original: Vec<u8>,
+    pub patched: Vec<u8>,
+}
+
+/// Reads `rom` and applies `patch` to it, or the `.ips` or `.bps` file next
+/// to the rom if there is one.
+pub fn load_rom(rom: &Path, patch: Option<&Path>) -> Result<Vec<u8>, PatchError> {
+    Ok(read_rom(rom, patch)?.patched)
+}
+
+/// Like `load_rom`, keeping the rom from before the patch too.
+pub fn read_rom(rom: &Path, patch: Option<&Path>) -> Result<PatchedRom, PatchError> {
+    let read = |path: &Path| fs::read(path).map_err(|e| PatchError::Io(path.to_owned(), e));
+    let original = read(rom)?;
+    let patch = match patch {
+        Some(patch) => Some(patch.to_owned()),
+        None => ["ips", "bps"].iter().map(|extension| rom.with_extension(extension)).find(|path| path.exists()),
+    };
+    let patched = match patch {
+        Some(patch) => apply(&read(&patch)?, &original)?,
+        None => original.clone(),
+    };
+    Ok(PatchedRom { original, patched })
+}
+
+/// Applies an IPS or BPS patch, told apart by their header.
+pub fn apply(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(patch, rom)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(patch, rom)
+    } else {
+        Err(PatchError::Format)
+    }
+}
+
+// Reads a patch front to back
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], PatchError> {
+        if self.data.len() < n {
+            return Err(PatchError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(n);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    // Big-endian, as IPS stores offsets and sizes
+    fn int(&mut self, n: usize) -> Result<usize, PatchError> {
+        Ok(self.take(n)?.iter().fold(0, |value, &byte| (value << 8) | byte as usize))
+    }
+
+    // BPS variable-length number
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let byte = self.take(1)?[0];
+            value = (byte as usize & 0x7f)
+                .checked_mul(shift)
+                .and_then(|part| value.checked_add(part))
+                .ok_or(PatchError::Invalid("number too large"))?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_mul(0x80).ok_or(PatchError::Invalid("number too large"))?;
+            value = value.checked_add(shift).ok_or(PatchError::Invalid("number too large"))?;
+        }
+    }
+}
+
+// Output grows up to the size a CHIP-8 rom can have, and no further
+const MAX_SIZE: usize = 0x1000;
+
+/// IPS: records of offset and bytes (or a run of one byte) to write.
+pub fn apply_ips(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut reader = Reader { data: patch.strip_prefix(b"PATCH").ok_or(PatchError::Format)? };
+    let mut output = rom.to_vec();
+    loop {
+        if reader.data.starts_with(b"EOF") {
+            reader.take(3)?;
+            break;
+        }
+        let offset = reader.int(3)?;
+        let (bytes, count) = match reader.int(2)? {
+            // A run of one byte
+            0 => {
+                let count = reader.int(2)?;
+                (reader.take(1)?, count)
+            }
+            size => (reader.take(size)?, 1),
+        };
+        let end = offset + bytes.len() * count;
+        if end > MAX_SIZE {
+            return Err(PatchError::Invalid("record past the end of memory"));
+        }
+        if output.len() < end {
+            output.resize(end, 0);
+        }
+        for chunk in output[offset..end].chunks_mut(bytes.len()) {
+            chunk.copy_from_slice(bytes);
+        }
+    }
+    // An optional size to cut the output down to
+    if reader.data.len() == 3 {
+        let size = reader.int(3)?;
+        output.truncate(size);
+    } else if !reader.data.is_empty() {
+        return Err(PatchError::Invalid("data after the end"));
+    }
+    Ok(output)
+}
+
+/// BPS: copies from the rom, the patch and the output so far, checked with
+/// CRC32s of the rom, the output and the patch.
+pub fn apply_bps(patch: &[u8], rom: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.len() < 4 + 12 {
+        return Err(PatchError::Truncated);
+    }
+    let (body, footer) = patch.split_at(patch.len() - 12);
+    let checksum = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    if crc32fast::hash(&patch[..patch.len() - 4]) != checksum(&footer[8..]) {
+        return Err(PatchError::Checksum("patch"));
+    }
+    let expected = checksum(&footer[..4]);
+    let actual = crc32fast::hash(rom);
+    if expected != actual {
+        return Err(PatchError::WrongRom { expected, actual });
+    }
+
+    let mut reader = Reader { data: body.strip_prefix(b"BPS1").ok_or(PatchError::Format)? };
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata = reader.number()?;
+    reader.take(metadata)?;
+    if source_size != rom.len() {
+        return Err(PatchError::Invalid("rom size"));
+    }
+    if target_size > MAX_SIZE {
+        return Err(PatchError::Invalid("output larger than memory"));
+    }
+
+    let mut output: Vec<u8> = Vec::with_capacity(target_size);
+    let (mut source_offset, mut target_offset) = (0usize, 0usize);
+    // Moves a copy offset by a signed amount
+    let seek = |offset: &mut usize, reader: &mut Reader| -> Result<(), PatchError> {
+        let number = reader.number()?;
+        let distance = number >> 1;
+        *offset = if number & 1 != 0 { offset.checked_sub(distance) } else { offset.checked_add(distance) }
+            .ok_or(PatchError::Invalid("copy offset"))?;
+        Ok(())
+    };
+    while !reader.data.is_empty() {
+        let action = reader.number()?;
+        let length = (action >> 2) + 1;
+        if output.len().checked_add(length).is_none_or(|end| end > target_size) {
+            return Err(PatchError::Invalid("output larger than declared"));
+        }
+        match action & 3 {
+            // SourceRead: the rom at the same position
+            0 => {
+                let start = output.len();
+                output.extend_from_slice(rom.get(start..start + length).ok_or(PatchError::Invalid("source read"))?);
+            }
+            // TargetRead: bytes from the patch
+            1 => output.extend_from_slice(reader.take(length)?),
+            // SourceCopy: the rom from anywhere
+            2 => {
+                seek(&mut source_offset, &mut reader)?;
+                let end = source_offset.checked_add(length).ok_or(PatchError::Invalid("source copy"))?;
+                let bytes = rom.get(source_offset..end).ok_or(PatchError::Invalid("source copy"))?;
+                output.extend_from_slice(bytes);
+                source_offset = end;
+            }
+            // TargetCopy: the output so far, byte by byte so runs can repeat
+            _ => {
+                seek(&mut target_offset, &mut reader)?;
+                for _ in 0..length {
+                    let byte = *output.get(target_offset).ok_or(PatchError::Invalid("target copy"))?;
+                    output.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if output.len() != target_size {
+        return Err(PatchError::Invalid("output smaller than declared"));
+    }
+    if crc32fast::hash(&output) != checksum(&footer[4..8]) {
+        return Err(PatchError::Checksum("patched rom"));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 8] = [0x60, 0x01, 0x61, 0x02, 0xd0, 0x15, 0x12, 0x06];
+
+    fn number(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte | 0x80);
+                return;
+            }
+            out.push(byte);
+            value -= 1;
+        }
+    }
+
+    // Keeps the first 4 bytes, writes two new ones, copies the first two
+    // bytes again and the rest of the rom from offset 6
+    fn bps(rom: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        number(rom.len(), &mut patch);
+        number(target.len(), &mut patch);
+        number(0, &mut patch);
+        number((4 - 1) << 2, &mut patch);
+        number(((2 - 1) << 2) | 1, &mut patch);
+        patch.extend_from_slice(&[0xaa, 0xbb]);
+        number(((2 - 1) << 2) | 3, &mut patch);
+        number(0, &mut patch);
+        number(((2 - 1) << 2) | 2, &mut patch);
+        number(6 << 1, &mut patch);
+        patch.extend_from_slice(&crc32fast::hash(rom).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let crc = crc32fast::hash(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn ips() {
+        let mut patch = b"PATCH".to_vec();
+        // 2 bytes at 0x000001, then a run of 3 0xee at 0x000008
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0x05, 0x06]);
+        patch.extend_from_slice(&[0, 0, 8, 0, 0, 0, 3, 0xee]);
+        patch.extend_from_slice(b"EOF");
+        let output = apply(&patch, &ROM).unwrap();
+        assert_eq!(output, [0x60, 0x05, 0x06, 0x02, 0xd0, 0x15, 0x12, 0x06, 0xee, 0xee, 0xee]);
+
+        // Cut down to 4 bytes
+        patch.extend_from_slice(&[0, 0, 4]);
+        assert_eq!(apply(&patch, &ROM).unwrap(), [0x60, 0x05, 0x06, 0x02]);
+        assert!(matches!(apply(&patch[..10], &ROM), Err(PatchError::Truncated)));
+    }
+
+    #[test]
+    fn bps_checksums() {
+        let target = [0x60, 0x01, 0x61, 0x02, 0xaa, 0xbb, 0x60, 0x01, 0x12, 0x06];
+        let patch = bps(&ROM, &target);
+        assert_eq!(apply(&patch, &ROM).unwrap(), target);
+
+        let mut other = ROM;
+        other[0] = 0x6f;
+        assert!(matches!(apply(&patch, &other), Err(PatchError::WrongRom { .. })));
+        let mut corrupt = patch.clone();
+        corrupt[8] ^= 1;
+        assert!(matches!(apply(&corrupt, &ROM), Err(PatchError::Checksum("patch"))));
+        assert!(matches!(apply(b"NOPE", &ROM), Err(PatchError::Format)));
+    }
+
+    #[test]
+    fn huge_numbers() {
+        // A BPS patch of nothing but `numbers` after the header, with valid checksums
+        let patch = |numbers: &[usize]| {
+            let mut patch = b"BPS1".to_vec();
+            for &value in [ROM.len(), 4, 0].iter().chain(numbers) {
+                number(value, &mut patch);
+            }
+            patch.extend_from_slice(&crc32fast::hash(&ROM).to_le_bytes());
+            patch.extend_from_slice(&[0; 4]);
+            let crc = crc32fast::hash(&patch);
+            patch.extend_from_slice(&crc.to_le_bytes());
+            patch
+        };
+        // A length near usize::MAX
+        assert!(matches!(apply(&patch(&[usize::MAX & !3]), &ROM), Err(PatchError::Invalid(_))));
+        // A SourceCopy seeking far past the rom
+        assert!(matches!(apply(&patch(&[2, usize::MAX - 1]), &ROM), Err(PatchError::Invalid(_))));
+    }
+
+    #[test]
+    fn next_to_the_rom() {
+        let dir = std::env::temp_dir().join(format!("chip8-patch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("game.ch8");
+        fs::write(&rom, ROM).unwrap();
+        assert_eq!(load_rom(&rom, None).unwrap(), ROM);
+        fs::write(dir.join("game.bps"), bps(&ROM, &[0x60, 0x01, 0x61, 0x02, 0xaa, 0xbb, 0x60, 0x01, 0x12, 0x06])).unwrap();
+        assert_eq!(load_rom(&rom, None).unwrap()[4..6], [0xaa, 0xbb]);
+        assert_eq!(read_rom(&rom, None).unwrap().original, ROM);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
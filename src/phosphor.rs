@@ -0,0 +1,140 @@
+use std::str::FromStr;
+
+use crate::display::{Palette, Screen};
+use crate::emulator::{WIDTH, HEIGHT};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PhosphorMode {
+    /// Blend every frame into the previous ones, losing `decay` (0.0 - 1.0)
+    /// of a pixel's brightness per frame once it goes dark.
+    Blend { decay: f32 },
+    /// Only show pixels lit in both the current frame and the one before it,
+    /// so a sprite drawn for a single frame never shows up.
+    Persist,
+    /// Show pixels lit in the current frame or the one before it, which
+    /// hides the single frame gap of a sprite being erased and redrawn.
+    Hold,
+}
+
+impl FromStr for PhosphorMode {
+    type Err = String;
+
+    // "blend", "blend:<decay>", "persist" or "hold"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some("persist"), None) => Ok(PhosphorMode::Persist),
+            (Some("hold"), None) => Ok(PhosphorMode::Hold),
+            (Some("blend"), None) => Ok(PhosphorMode::Blend { decay: 0.5 }),
+            (Some("blend"), Some(decay)) => match decay.parse::<f32>() {
+                Ok(decay) if (0.0..=1.0).contains(&decay) => Ok(PhosphorMode::Blend { decay }),
+                _ => Err(format!("Decay must be between 0 and 1: {}", decay)),
+            },
+            _ => Err(format!("Unknown phosphor mode: {}", s)),
+        }
+    }
+}
+
+/// Presentation filter that smooths XOR flicker. It only ever reads the
+/// emulator's screen, so the logical framebuffer stays untouched.
+pub struct Phosphor {
+    mode: PhosphorMode,
+    palette: Palette,
+    intensity: [f32; WIDTH * HEIGHT],
+    output: [u32; WIDTH * HEIGHT],
+}
+
+impl Phosphor {
+    pub fn new(mode: PhosphorMode) -> Self {
+        Phosphor {
+            mode,
+            palette: Palette::default(),
+            intensity: [0.0; WIDTH * HEIGHT],
+            output: [0; WIDTH * HEIGHT],
+        }
+    }
+
+    pub fn mode(&self) -> PhosphorMode {
+        self.mode
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    pub fn reset(&mut self) {
+        self.intensity = [0.0; WIDTH * HEIGHT];
+        self.output = [0; WIDTH * HEIGHT];
+    }
+
+    pub fn output(&self) -> &[u32; WIDTH * HEIGHT] {
+        &self.output
+    }
+
+    pub fn apply(&mut self, screen: &Screen) -> &[u32; WIDTH * HEIGHT] {
+        for i in 0..WIDTH * HEIGHT {
+            let lit = screen.pixel(i % WIDTH, i / WIDTH);
+            let was_lit = self.intensity[i] == 1.0;
+            self.intensity[i] = match self.mode {
+                PhosphorMode::Blend { decay } if !lit => self.intensity[i] * (1.0 - decay),
+                _ if lit => 1.0,
+                _ => 0.0,
+            };
+            self.output[i] = match self.mode {
+                PhosphorMode::Blend { .. } => self.palette.mix(self.intensity[i]),
+                PhosphorMode::Persist if lit && was_lit => self.palette.on,
+                PhosphorMode::Hold if lit || was_lit => self.palette.on,
+                _ => self.palette.off,
+            };
+        }
+        &self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::ON;
+
+    #[test]
+    fn blend_decays() {
+        let mut phosphor = Phosphor::new(PhosphorMode::Blend { decay: 0.5 });
+        let mut screen = Screen::new();
+        screen.draw_row(0, 0, 0x80);
+        assert_eq!(phosphor.apply(&screen)[0], ON);
+        screen.clear();
+        assert_eq!(phosphor.apply(&screen)[0], 0x00808080);
+        assert_eq!(phosphor.apply(&screen)[0], 0x00404040);
+    }
+
+    #[test]
+    fn persist_needs_two_frames() {
+        let mut phosphor = Phosphor::new(PhosphorMode::Persist);
+        let mut screen = Screen::new();
+        screen.draw_row(0, 0, 0x80);
+        assert_eq!(phosphor.apply(&screen)[0], 0);
+        assert_eq!(phosphor.apply(&screen)[0], ON);
+        screen.clear();
+        assert_eq!(phosphor.apply(&screen)[0], 0);
+    }
+
+    #[test]
+    fn hold_hides_single_frame_gap() {
+        let mut phosphor = Phosphor::new(PhosphorMode::Hold);
+        let mut screen = Screen::new();
+        screen.draw_row(1, 0, 0x80);
+        assert_eq!(phosphor.apply(&screen)[1], ON);
+        screen.clear();
+        assert_eq!(phosphor.apply(&screen)[1], ON);
+        assert_eq!(phosphor.apply(&screen)[1], 0);
+    }
+
+    #[test]
+    fn parse_mode() {
+        assert_eq!("persist".parse(), Ok(PhosphorMode::Persist));
+        assert_eq!("hold".parse(), Ok(PhosphorMode::Hold));
+        assert_eq!("blend:0.25".parse(), Ok(PhosphorMode::Blend { decay: 0.25 }));
+        assert!("blend:2".parse::<PhosphorMode>().is_err());
+        assert!("glow".parse::<PhosphorMode>().is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+
+use crate::disasm;
+use crate::emulator::{Chip8, Observer};
+
+// Rows of the hotspot table in the report
+const HOTSPOTS: usize = 20;
+// Frames and calls kept for the trace, about 24MB; later ones are only counted
+const MAX_SPANS: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Subroutine {
+    pub calls: u64,
+    /// Instructions run inside it, including the subroutines it calls.
+    pub instructions: u64,
+    /// Instructions run by the subroutine itself.
+    pub own_instructions: u64,
+}
+
+// A call in progress
+#[derive(Debug, Clone, Copy)]
+struct Call {
+    addr: u16,
+    // Instruction count when it was called
+    start: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SpanKind {
+    Frame(u32),
+    Call(u16),
+}
+
+// A finished frame or call, in instructions since the start
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    kind: SpanKind,
+    start: u64,
+    length: u64,
+}
+
+/// Counts what a run executes: each pc, each subroutine, and the
+/// instructions and draws of each frame.
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    opcodes: HashMap<u16, u16>,
+    counts: HashMap<u16, u64>,
+    subroutines: HashMap<u16, Subroutine>,
+    stack: Vec<Call>,
+    // Instructions and draws of each frame
+    frames: Vec<(u32, u32)>,
+    instructions: u64,
+    frame_start: u64,
+    draws: u32,
+    spans: Vec<Span>,
+    // Spans left out of the trace once it held `MAX_SPANS`
+    dropped: u64,
+    // Pending before the next `after`
+    current: Option<(u16, u16)>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            opcodes: HashMap::new(),
+            counts: HashMap::new(),
+            subroutines: HashMap::new(),
+            stack: Vec::new(),
+            frames: Vec::new(),
+            instructions: 0,
+            frame_start: 0,
+            draws: 0,
+            spans: Vec::new(),
+            dropped: 0,
+            current: None,
+        }
+    }
+
+    /// Instructions and `DRW`s of every finished frame.
+    pub fn frames(&self) -> &[(u32, u32)] {
+        &self.frames
+    }
+
+    /// How often the instruction at `pc` ran.
+    pub fn count(&self, pc: u16) -> u64 {
+        self.counts.get(&pc).copied().unwrap_or(0)
+    }
+
+    pub fn subroutine(&self, addr: u16) -> Option<&Subroutine> {
+        self.subroutines.get(&addr)
+    }
+
+    /// The most executed addresses first.
+    pub fn hotspots(&self) -> Vec<(u16, u64)> {
+        let mut hotspots: Vec<(u16, u64)> = self.counts.iter().map(|(&pc, &count)| (pc, count)).collect();
+        hotspots.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hotspots
+    }
+
+    fn push_span(&mut self, span: Span) {
+        if self.spans.len() < MAX_SPANS {
+            self.spans.push(span);
+        } else {
+            self.dropped += 1;
+        }
+    }
+
+    /// The frames and subroutine calls in the Chrome trace event format, for
+    /// chrome://tracing or Perfetto. One microsecond stands for one
+    /// instruction; frames are on one track and calls on another. Only the
+    /// first million or so are kept; `droppedSpans` counts the rest.
+    pub fn write_chrome_trace<W: Write>(&self, mut out: W) -> io::Result<()> {
+        // Calls still running end where the run did
+        let unfinished = self.stack.iter().map(|call| Span {
+            kind: SpanKind::Call(call.addr),
+            start: call.start,
+            length: self.instructions - call.start,
+        });
+        let events: Vec<serde_json::Value> = self.spans
+            .iter()
+            .copied()
+            .chain(unfinished)
+            .map(|span| {
+                let (name, cat, tid) = match span.kind {
+                    SpanKind::Frame(frame) => (format!("frame {}", frame), "frame", 1),
+                    SpanKind::Call(addr) => (format!("sub {:03x}", addr), "call", 2),
+                };
+                serde_json::json!({
+                    "name": name,
+                    "cat": cat,
+                    "ph": "X",
+                    "ts": span.start,
+                    "dur": span.length,
+                    "pid": 1,
+                    "tid": tid,
+                })
+            })
+            .collect();
+        let trace = serde_json::json!({
+            "traceEvents": events,
+            "otherData": { "droppedSpans": self.dropped },
+        });
+        serde_json::to_writer(&mut out, &trace)?;
+        out.flush()
+    }
+}
+
+impl Observer for Profiler {
+    fn before(&mut self, chip8: &Chip8) {
+        let (pc, op) = (chip8.pc() as u16, chip8.opcode());
+        *self.counts.entry(pc).or_insert(0) += 1;
+        self.opcodes.insert(pc, op);
+        if let Some(call) = self.stack.last() {
+            self.subroutines.entry(call.addr).or_default().own_instructions += 1;
+        }
+        for call in &self.stack {
+            self.subroutines.entry(call.addr).or_default().instructions += 1;
+        }
+        self.instructions += 1;
+        self.current = Some((pc, op));
+    }
+
+    fn after(&mut self, chip8: &Chip8) {
+        // Instructions that failed did nothing
+        if chip8.error().is_some() {
+            self.current = None;
+        }
+        match self.current.take() {
+            Some((_, op)) if op & 0xf000 == 0xd000 => self.draws += 1,
+            // Only once it really went there, which may be its own address
+            Some((_, op)) if op & 0xf000 == 0x2000 && chip8.pc() as u16 == op & 0x0fff => {
+                let addr = op & 0x0fff;
+                self.subroutines.entry(addr).or_default().calls += 1;
+                self.stack.push(Call { addr, start: self.instructions });
+            }
+            Some((_, 0x00ee)) => {
+                if let Some(call) = self.stack.pop() {
+                    self.push_span(Span {
+                        kind: SpanKind::Call(call.addr),
+                        start: call.start,
+                        length: self.instructions - call.start,
+                    });
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn end_frame(&mut self, _chip8: &Chip8) {
+        let instructions = (self.instructions - self.frame_start) as u32;
+        self.push_span(Span {
+            kind: SpanKind::Frame(self.frames.len() as u32),
+            start: self.frame_start,
+            length: instructions as u64,
+        });
+        self.frames.push((instructions, self.draws));
+        self.frame_start = self.instructions;
+        self.draws = 0;
+    }
+}
+
+// min, average and max
+fn spread(values: impl Iterator<Item = u32> + Clone) -> (u32, f64, u32) {
+    let count = values.clone().count().max(1);
+    let min = values.clone().min().unwrap_or(0);
+    let max = values.clone().max().unwrap_or(0);
+    let sum: u64 = values.map(u64::from).sum();
+    (min, sum as f64 / count as f64, max)
+}
+
+/// The hotspot report, most executed first.
+impl fmt::Display for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (min, avg, max) = spread(self.frames.iter().map(|&(instructions, _)| instructions));
+        writeln!(f, "{} instructions in {} frames", self.instructions, self.frames.len())?;
+        writeln!(f, "instructions per frame: min {}, avg {:.1}, max {}", min, avg, max)?;
+        let (min, avg, max) = spread(self.frames.iter().map(|&(_, draws)| draws));
+        writeln!(f, "draws per frame: min {}, avg {:.1}, max {}", min, avg, max)?;
+
+        writeln!(f, "\nhotspots:")?;
+        writeln!(f, "{:>10} {:>6}  pc   instruction", "count", "share")?;
+        for (pc, count) in self.hotspots().into_iter().take(HOTSPOTS) {
+            let share = 100.0 * count as f64 / self.instructions.max(1) as f64;
+            let instruction = disasm::disassemble(self.opcodes[&pc]);
+            writeln!(f, "{:>10} {:>5.1}%  {:03x}  {}", count, share, pc, instruction)?;
+        }
+
+        let mut subroutines: Vec<(&u16, &Subroutine)> = self.subroutines.iter().collect();
+        subroutines.sort_by(|a, b| b.1.instructions.cmp(&a.1.instructions).then(a.0.cmp(b.0)));
+        if !subroutines.is_empty() {
+            writeln!(f, "\nsubroutines:")?;
+            writeln!(f, "{:>10} {:>12} {:>12}  addr", "calls", "instructions", "own")?;
+        }
+        for (addr, subroutine) in subroutines {
+            let mut line = format!("{:>10} {:>12} {:>12}  {:03x}", subroutine.calls, subroutine.instructions,
+                subroutine.own_instructions, addr);
+            if subroutine.calls > 0 {
+                write!(line, " ({:.1} per call)", subroutine.instructions as f64 / subroutine.calls as f64).unwrap();
+            }
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 12] = [
+        0x22, 0x06, // 200: CALL 0x206
+        0xd0, 0x11, // 202: DRW V0, V1, 1
+        0x12, 0x00, // 204: JP 0x200
+        0x70, 0x01, // 206: ADD V0, 0x01
+        0x00, 0xee, // 208: RET
+        0x80, 0x00,
+    ];
+
+    #[test]
+    fn counts() {
+        let mut chip8 = Chip8::from_rom(&ROM);
+        let mut profiler = Profiler::new();
+        for _ in 0..4 {
+            chip8.run_frame_observed(10, &mut profiler);
+        }
+        assert_eq!(profiler.frames(), [(10, 2), (10, 2), (10, 2), (10, 2)]);
+        assert_eq!(profiler.count(0x200), 8);
+        assert_eq!(profiler.count(0x208), 8);
+        assert_eq!(profiler.hotspots()[0], (0x200, 8));
+        assert_eq!(profiler.subroutine(0x206), Some(&Subroutine { calls: 8, instructions: 16, own_instructions: 16 }));
+
+        let report = profiler.to_string();
+        assert!(report.starts_with("40 instructions in 4 frames\ninstructions per frame: min 10, avg 10.0, max 10\n"));
+        assert!(report.contains("         8  20.0%  200  CALL 0x206\n"));
+
+        let mut json = Vec::new();
+        profiler.write_chrome_trace(&mut json).unwrap();
+        let trace: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        assert_eq!(events.iter().filter(|event| event["cat"] == "frame").count(), 4);
+        let call = events.iter().find(|event| event["name"] == "sub 206").unwrap();
+        assert_eq!((call["ts"].as_u64(), call["dur"].as_u64()), (Some(1), Some(2)));
+        assert_eq!(trace["otherData"]["droppedSpans"], 0);
+    }
+
+    #[test]
+    fn spans_are_capped() {
+        let mut profiler = Profiler::new();
+        let chip8 = Chip8::from_rom(&[]);
+        for _ in 0..MAX_SPANS + 3 {
+            profiler.end_frame(&chip8);
+        }
+        assert_eq!(profiler.spans.len(), MAX_SPANS);
+        assert_eq!(profiler.dropped, 3);
+    }
+
+    #[test]
+    fn failed_instructions_are_not_counted() {
+        // CALL 0x200, until the stack overflows
+        let mut chip8 = Chip8::from_rom(&[0x22, 0x00]);
+        let mut profiler = Profiler::new();
+        chip8.run_frame_observed(20, &mut profiler);
+        assert!(chip8.error().is_some());
+        assert_eq!(profiler.subroutine(0x200).map(|subroutine| subroutine.calls), Some(16));
+
+        // DRW V0, V0, 15 past the end of memory
+        let mut chip8 = Chip8::builder().program(&[0xd0, 0x0f]).index(0xffe).build();
+        let mut profiler = Profiler::new();
+        chip8.run_frame_observed(1, &mut profiler);
+        assert_eq!(profiler.frames(), [(1, 0)]);
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+// What FX55 and FX65 do to I after storing or loading registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LoadStore {
+    /// I is left unchanged (CHIP-48 and later, cowgod's reference).
+    LeaveI,
+    /// I is incremented by X + 1 like on the COSMAC VIP.
+    Increment,
+    /// I is incremented by X, a bug in CHIP-48 1.0.
+    IncrementByX,
+}
+
+// Behaviour that differs between CHIP-8 interpreters. The defaults match
+// what this emulator has always done, which follows cowgod's reference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Quirks {
+    /// 8XY6 and 8XYE shift VX in place instead of shifting VY into VX.
+    pub shift: bool,
+    pub load_store: LoadStore,
+    /// Sprites wrap around the screen edges instead of being clipped.
+    pub wrap: bool,
+    /// BNNN jumps to XNN + VX instead of NNN + V0 (SCHIP).
+    pub jump: bool,
+    /// DXYN waits for the next vertical blank like on the COSMAC VIP, so a
+    /// draw ends the current frame and games get at most 60 draws a second.
+    pub display_wait: bool,
+    /// 8XY1, 8XY2 and 8XY3 reset VF to 0 like on the COSMAC VIP.
+    pub logic: bool,
+    /// How many calls can be nested: 12 on the COSMAC VIP, 16 from SCHIP on.
+    pub stack_depth: u8,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::COWGOD
+    }
+}
+
+impl Quirks {
+    pub const COWGOD: Quirks = Quirks {
+        shift: true,
+        load_store: LoadStore::LeaveI,
+        wrap: true,
+        jump: false,
+        display_wait: false,
+        logic: false,
+        stack_depth: 16,
+    };
+
+    pub const VIP: Quirks = Quirks {
+        shift: false,
+        load_store: LoadStore::Increment,
+        wrap: false,
+        jump: false,
+        display_wait: true,
+        logic: true,
+        stack_depth: 12,
+    };
+
+    pub const SCHIP: Quirks = Quirks {
+        shift: true,
+        load_store: LoadStore::LeaveI,
+        wrap: false,
+        jump: true,
+        display_wait: false,
+        logic: false,
+        stack_depth: 16,
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuirkPreset {
+    #[default]
+    Cowgod,
+    Vip,
+    Schip,
+}
+
+impl QuirkPreset {
+    pub fn quirks(self) -> Quirks {
+        match self {
+            QuirkPreset::Cowgod => Quirks::COWGOD,
+            QuirkPreset::Vip => Quirks::VIP,
+            QuirkPreset::Schip => Quirks::SCHIP,
+        }
+    }
+}
+
+impl FromStr for QuirkPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cowgod" => Ok(QuirkPreset::Cowgod),
+            "vip" => Ok(QuirkPreset::Vip),
+            "schip" => Ok(QuirkPreset::Schip),
+            _ => Err(format!("Unknown quirk preset: {} (expected cowgod, vip or schip)", s)),
+        }
+    }
+}
+
+impl fmt::Display for QuirkPreset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuirkPreset::Cowgod => write!(f, "cowgod"),
+            QuirkPreset::Vip => write!(f, "vip"),
+            QuirkPreset::Schip => write!(f, "schip"),
+        }
+    }
+}
+
+// The machine being emulated. Only the CHIP-8 instruction set is implemented;
+// the variant picks the default quirks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    #[default]
+    Chip8,
+    Schip,
+}
+
+impl Variant {
+    pub fn preset(self) -> QuirkPreset {
+        match self {
+            Variant::Chip8 => QuirkPreset::Cowgod,
+            Variant::Schip => QuirkPreset::Schip,
+        }
+    }
+}
+
+impl FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chip8" => Ok(Variant::Chip8),
+            "schip" => Ok(Variant::Schip),
+            _ => Err(format!("Unknown variant: {} (expected chip8 or schip)", s)),
+        }
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Variant::Chip8 => write!(f, "chip8"),
+            Variant::Schip => write!(f, "schip"),
+        }
+    }
+}
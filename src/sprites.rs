@@ -0,0 +1,300 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write as _;
+use std::io::{self, Write};
+
+use crate::analysis::{Analysis, EdgeKind};
+use crate::emulator::{Chip8, Observer, FONT_SET};
+
+/// Sprite data found in a rom: 8 pixels wide, one byte per row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sprite {
+    pub addr: u16,
+    pub rows: Vec<u8>,
+    /// Found by following `LD I, addr` to a `DRW` without running the rom.
+    pub found_statically: bool,
+    /// How often a run drew it.
+    pub draws: u64,
+}
+
+/// Sprites by address and height.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Sprites {
+    sprites: BTreeMap<(u16, u8), Sprite>,
+}
+
+// More values of `I` than this at one point are given up on as unknown
+const MAX_INDEXES: usize = 8;
+
+// The values `I` can have at some point of the rom, as far as static analysis can tell
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Index {
+    Known(BTreeSet<u16>),
+    Unknown,
+}
+
+impl Index {
+    fn join(&self, other: &Index) -> Index {
+        match (self, other) {
+            (Index::Known(a), Index::Known(b)) => {
+                let joined: BTreeSet<u16> = a.union(b).copied().collect();
+                if joined.len() > MAX_INDEXES { Index::Unknown } else { Index::Known(joined) }
+            }
+            _ => Index::Unknown,
+        }
+    }
+}
+
+// Follows I through the instructions of a block, calling `draw` with the
+// address and height of every sprite drawn from a known I. Returns I at the end.
+fn run_block(instructions: &[(u16, u16)], mut index: Index, mut draw: impl FnMut(u16, u8)) -> Index {
+    for &(_, op) in instructions {
+        match op & 0xf000 {
+            0xa000 => index = Index::Known(BTreeSet::from([op & 0x0fff])),
+            0xd000 => {
+                if let (Index::Known(addrs), n @ 1..=15) = (&index, (op & 0x000f) as u8) {
+                    for &addr in addrs {
+                        draw(addr, n);
+                    }
+                }
+            }
+            // ADD I, LD F, LD [I] and LD Vx, [I] move I, depending on registers or quirks
+            0xf000 if matches!(op & 0x00ff, 0x1e | 0x29 | 0x55 | 0x65) => index = Index::Unknown,
+            _ => (),
+        }
+    }
+    index
+}
+
+impl Sprites {
+    /// The sprites drawn with `I` set by an `LD I, addr` on every path to the
+    /// `DRW`, without running the rom. A `DRW` reached from several loads
+    /// gives a sprite for each, up to a handful.
+    pub fn find_static(rom: &[u8]) -> Self {
+        let analysis = Analysis::analyze(rom);
+        let memory = Chip8::from_rom(rom).memory().to_vec();
+        let blocks: HashMap<u16, _> = analysis.blocks().iter().map(|block| (block.start(), block)).collect();
+        // `I` when entering each block; I starts out as 0
+        let mut entry: HashMap<u16, Index> = HashMap::new();
+        let mut work = Vec::new();
+        if let Some(start) = analysis.blocks().first() {
+            entry.insert(start.start(), Index::Known(BTreeSet::from([0])));
+            work.push(start.start());
+        }
+        while let Some(start) = work.pop() {
+            let block = match blocks.get(&start) {
+                Some(block) => block,
+                None => continue,
+            };
+            let index = run_block(&block.instructions, entry[&start].clone(), |_, _| ());
+            for edge in &block.edges {
+                // Calls come back with whatever the subroutine did to I
+                let after = match edge.kind {
+                    EdgeKind::Next if block.edges.iter().any(|edge| edge.kind == EdgeKind::Call) => Index::Unknown,
+                    _ => index.clone(),
+                };
+                let joined = entry.get(&edge.to).map_or(after.clone(), |other| other.join(&after));
+                if entry.get(&edge.to) != Some(&joined) {
+                    entry.insert(edge.to, joined);
+                    work.push(edge.to);
+                }
+            }
+        }
+        // Only once I is known on every path into each block
+        let mut sprites = Sprites::default();
+        for block in analysis.blocks() {
+            if let Some(index) = entry.get(&block.start()) {
+                run_block(&block.instructions, index.clone(), |addr, height| {
+                    sprites.add(&memory, addr, height).found_statically = true;
+                });
+            }
+        }
+        sprites
+    }
+
+    fn add(&mut self, memory: &[u8], addr: u16, height: u8) -> &mut Sprite {
+        self.sprites.entry((addr, height)).or_insert_with(|| Sprite {
+            addr,
+            rows: (0..height as usize).map(|row| memory.get(addr as usize + row).copied().unwrap_or(0)).collect(),
+            found_statically: false,
+            draws: 0,
+        })
+    }
+
+    /// Adds the sprites of `other`, keeping what both know about each.
+    pub fn merge(&mut self, other: Sprites) {
+        for (key, sprite) in other.sprites {
+            match self.sprites.get_mut(&key) {
+                Some(known) => {
+                    known.found_statically |= sprite.found_statically;
+                    known.draws += sprite.draws;
+                }
+                None => {
+                    self.sprites.insert(key, sprite);
+                }
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Sprite> {
+        self.sprites.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sprites.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sprites.is_empty()
+    }
+
+    /// Every sprite as ASCII art under its address.
+    pub fn to_ascii(&self) -> String {
+        let mut text = String::new();
+        for sprite in self.iter() {
+            let found = match (sprite.found_statically, sprite.draws) {
+                (true, 0) => "static".to_owned(),
+                (false, draws) => format!("drawn {} times", draws),
+                (true, draws) => format!("static, drawn {} times", draws),
+            };
+            writeln!(text, "{:03x} ({} rows, {})", sprite.addr, sprite.rows.len(), found).unwrap();
+            for row in &sprite.rows {
+                let pixels: String = (0..8).map(|bit| if row & (0x80 >> bit) != 0 { '#' } else { '.' }).collect();
+                writeln!(text, "{}", pixels).unwrap();
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Writes all sprites as one PNG, in a grid of cells with the address of
+    /// each sprite above it in the CHIP-8 font.
+    pub fn write_png<W: Write>(&self, out: W) -> io::Result<()> {
+        const COLUMNS: usize = 8;
+        // Three hex digits of 4x5 pixels, then the tallest sprite
+        const CELL_WIDTH: usize = 16;
+        const CELL_HEIGHT: usize = 24;
+        let rows = self.len().div_ceil(COLUMNS).max(1);
+        let (width, height) = (COLUMNS * CELL_WIDTH, rows * CELL_HEIGHT);
+        let mut pixels = vec![0u8; width * height];
+        let mut set = |x: usize, y: usize| pixels[y * width + x] = 0xff;
+
+        for (i, sprite) in self.iter().enumerate() {
+            let (left, top) = ((i % COLUMNS) * CELL_WIDTH + 1, (i / COLUMNS) * CELL_HEIGHT + 1);
+            for (digit, shift) in [8, 4, 0].iter().enumerate() {
+                let glyph = &FONT_SET[((sprite.addr >> shift) & 0xf) as usize * 5..][..5];
+                for (y, row) in glyph.iter().enumerate() {
+                    for x in (0..4).filter(|x| row & (0x80 >> x) != 0) {
+                        set(left + digit * 5 + x, top + y);
+                    }
+                }
+            }
+            for (y, row) in sprite.rows.iter().enumerate() {
+                for x in (0..8).filter(|x| row & (0x80 >> x) != 0) {
+                    set(left + x, top + 7 + y);
+                }
+            }
+        }
+
+        let mut encoder = png::Encoder::new(out, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// Records the sprite behind every `DRW` a run executes, from the `I` and
+/// height it actually uses.
+#[derive(Debug, Default)]
+pub struct SpriteRecorder {
+    sprites: Sprites,
+}
+
+impl SpriteRecorder {
+    pub fn new() -> Self {
+        SpriteRecorder::default()
+    }
+
+    pub fn into_sprites(self) -> Sprites {
+        self.sprites
+    }
+}
+
+impl Observer for SpriteRecorder {
+    fn before(&mut self, chip8: &Chip8) {
+        let op = chip8.opcode();
+        if op & 0xf000 == 0xd000 && op & 0x000f != 0 {
+            // The data as first drawn, for roms that change their sprites
+            self.sprites.add(chip8.memory(), chip8.index(), (op & 0x000f) as u8).draws += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 20] = [
+        0xa2, 0x10, // 200: LD I, 0x210
+        0x30, 0x00, // 202: SE V0, 0x00
+        0xa2, 0x12, // 204: LD I, 0x212
+        0xd0, 0x12, // 206: DRW V0, V1, 2 (either sprite)
+        0xa2, 0x12, // 208: LD I, 0x212
+        0xd0, 0x12, // 20a: DRW V0, V1, 2
+        0x70, 0x01, // 20c: ADD V0, 0x01
+        0x12, 0x02, // 20e: JP 0x202
+        0x81, 0x42, // 210: sprite
+        0xff, 0x18, // 212: sprite
+    ];
+
+    #[test]
+    fn static_and_dynamic() {
+        let mut sprites = Sprites::find_static(&ROM);
+        let found: Vec<(u16, &[u8])> = sprites.iter().map(|sprite| (sprite.addr, &sprite.rows[..])).collect();
+        assert_eq!(found, [(0x210, &[0x81, 0x42][..]), (0x212, &[0xff, 0x18][..])]);
+
+        let mut chip8 = Chip8::from_rom(&ROM);
+        let mut recorder = SpriteRecorder::new();
+        chip8.run_frame_observed(14, &mut recorder);
+        sprites.merge(recorder.into_sprites());
+        let found: Vec<(u16, bool, u64)> =
+            sprites.iter().map(|sprite| (sprite.addr, sprite.found_statically, sprite.draws)).collect();
+        assert_eq!(found, [(0x210, true, 1), (0x212, true, 3)]);
+
+        let ascii = sprites.to_ascii();
+        assert!(ascii.starts_with("210 (2 rows, static, drawn 1 times)\n#......#\n.#....#.\n"));
+        assert!(ascii.contains("212 (2 rows, static, drawn 3 times)\n########\n...##...\n"));
+    }
+
+    #[test]
+    fn png_sheet() {
+        let mut png = Vec::new();
+        Sprites::find_static(&ROM).write_png(&mut png).unwrap();
+        let decoder = png::Decoder::new(&png[..]);
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (128, 24));
+        // The top rows of the sprites below their addresses
+        assert_eq!(&pixels[8 * 128 + 1..][..8], &[0xff, 0, 0, 0, 0, 0, 0, 0xff]);
+        assert_eq!(&pixels[8 * 128 + 17..][..8], &[0xff; 8]);
+    }
+
+    #[test]
+    fn too_many_indexes() {
+        // LD I, 0x300, then each SE Vn, 1 either skips or takes LD I, 0x301 + n
+        let rom = |loads: u8| {
+            let mut rom = vec![0xa3, 0x00];
+            for n in 0..loads {
+                rom.extend_from_slice(&[0x30 | n, 0x01, 0xa3, n + 1]);
+            }
+            rom.extend_from_slice(&[0xd0, 0x11]);
+            rom
+        };
+        let found: Vec<u16> = Sprites::find_static(&rom(7)).iter().map(|sprite| sprite.addr).collect();
+        assert_eq!(found, (0x300..0x308).collect::<Vec<_>>());
+        assert!(Sprites::find_static(&rom(8)).is_empty());
+    }
+}
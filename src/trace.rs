@@ -0,0 +1,231 @@
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::disasm;
+use crate::emulator::{Chip8, Observer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceFormat {
+    /// One aligned line per instruction.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "json" => Ok(TraceFormat::Json),
+            _ => Err(format!("Unknown trace format: {} (expected text or json)", s)),
+        }
+    }
+}
+
+/// Which instructions get logged. Unset filters let everything through.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub pc: Option<RangeInclusive<u16>>,
+    /// Instruction names, e.g. `DRW` or `CALL`.
+    pub kinds: Vec<String>,
+    pub frames: Option<RangeInclusive<u64>>,
+}
+
+impl TraceFilter {
+    fn matches(&self, frame: u64, pc: u16, op: u16) -> bool {
+        self.frames.as_ref().is_none_or(|frames| frames.contains(&frame))
+            && self.pc.as_ref().is_none_or(|range| range.contains(&pc))
+            && (self.kinds.is_empty() || self.kinds.iter().any(|kind| kind.eq_ignore_ascii_case(disasm::kind(op))))
+    }
+}
+
+// `200-2ff`, or a single address
+pub fn parse_pc_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    parse_range(s, |n| u16::from_str_radix(n.trim_start_matches("0x"), 16).ok())
+        .ok_or_else(|| format!("Invalid address range: {} (expected hex like 200-2ff)", s))
+}
+
+// `100-200`, or a single frame
+pub fn parse_frame_range(s: &str) -> Result<RangeInclusive<u64>, String> {
+    parse_range(s, |n| n.parse().ok()).ok_or_else(|| format!("Invalid frame range: {} (expected like 100-200)", s))
+}
+
+fn parse_range<T: Copy>(s: &str, parse: impl Fn(&str) -> Option<T>) -> Option<RangeInclusive<T>> {
+    match s.split_once('-') {
+        Some((start, end)) => Some(parse(start.trim())?..=parse(end.trim())?),
+        None => parse(s.trim()).map(|n| n..=n),
+    }
+}
+
+// What an instruction can change, besides pc
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    v: [u8; 16],
+    i: u16,
+    dt: u8,
+    st: u8,
+}
+
+impl Registers {
+    fn of(chip8: &Chip8) -> Self {
+        Registers { v: *chip8.registers(), i: chip8.index(), dt: chip8.delay_timer(), st: chip8.sound_timer() }
+    }
+
+    // Names and new values of what differs in `after`
+    fn changes(&self, after: &Registers) -> Vec<(String, u16)> {
+        let mut changes: Vec<(String, u16)> = (0..16)
+            .filter(|&x| self.v[x] != after.v[x])
+            .map(|x| (format!("V{:X}", x), after.v[x] as u16))
+            .collect();
+        if self.i != after.i {
+            changes.push(("I".to_owned(), after.i));
+        }
+        if self.dt != after.dt {
+            changes.push(("DT".to_owned(), after.dt as u16));
+        }
+        if self.st != after.st {
+            changes.push(("ST".to_owned(), after.st as u16));
+        }
+        changes
+    }
+}
+
+/// Logs every executed instruction that passes the filter: frame number,
+/// pc, opcode, mnemonic and the registers, `I` and timers it changed.
+///
+/// ```text
+///      12 0204 6a02 LD VA, 0x02          VA=02
+/// ```
+pub struct Trace<W: Write> {
+    out: W,
+    format: TraceFormat,
+    filter: TraceFilter,
+    frame: u64,
+    // Set before an instruction that gets logged
+    pending: Option<(u16, u16, Registers)>,
+    error: Option<io::Error>,
+}
+
+impl Trace<BufWriter<File>> {
+    pub fn create(path: &Path, format: TraceFormat, filter: TraceFilter) -> io::Result<Self> {
+        Ok(Trace::new(BufWriter::new(File::create(path)?), format, filter))
+    }
+}
+
+impl<W: Write> Trace<W> {
+    pub fn new(out: W, format: TraceFormat, filter: TraceFilter) -> Self {
+        Trace { out, format, filter, frame: 0, pending: None, error: None }
+    }
+
+    /// Flushes the log, and returns the first error writing it if there was one.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn line(&self, pc: u16, op: u16, changes: &[(String, u16)]) -> String {
+        let mnemonic = disasm::disassemble(op);
+        match self.format {
+            TraceFormat::Text => {
+                let mut line = format!("{:>7} {:04x} {:04x} {:<20}", self.frame, pc, op, mnemonic);
+                for (name, value) in changes {
+                    match name.as_str() {
+                        "I" => write!(line, " I={:03x}", value),
+                        _ => write!(line, " {}={:02x}", name, value),
+                    }
+                    .unwrap();
+                }
+                line.trim_end().to_owned()
+            }
+            TraceFormat::Json => {
+                let changes: serde_json::Map<String, serde_json::Value> =
+                    changes.iter().map(|(name, value)| (name.clone(), (*value).into())).collect();
+                serde_json::json!({
+                    "frame": self.frame,
+                    "pc": pc,
+                    "opcode": op,
+                    "mnemonic": mnemonic,
+                    "changes": changes,
+                })
+                .to_string()
+            }
+        }
+    }
+}
+
+impl<W: Write> Observer for Trace<W> {
+    fn before(&mut self, chip8: &Chip8) {
+        let (pc, op) = (chip8.pc() as u16, chip8.opcode());
+        if self.error.is_none() && self.filter.matches(self.frame, pc, op) {
+            self.pending = Some((pc, op, Registers::of(chip8)));
+        }
+    }
+
+    fn after(&mut self, chip8: &Chip8) {
+        if let Some((pc, op, before)) = self.pending.take() {
+            let line = self.line(pc, op, &before.changes(&Registers::of(chip8)));
+            if let Err(e) = writeln!(self.out, "{}", line) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn end_frame(&mut self, _chip8: &Chip8) {
+        self.frame += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LD VA, 2; LD I, 0x20a; LD DT, VA; JP 0x206 (waits forever); data
+    const ROM: [u8; 10] = [0x6a, 0x02, 0xa2, 0x0a, 0xfa, 0x15, 0x12, 0x06, 0x80, 0x00];
+
+    fn trace(format: TraceFormat, filter: TraceFilter) -> String {
+        let mut chip8 = Chip8::from_rom(&ROM);
+        let mut trace = Trace::new(Vec::new(), format, filter);
+        for _ in 0..3 {
+            chip8.run_frame_observed(3, &mut trace);
+        }
+        String::from_utf8(trace.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn text() {
+        let log = trace(TraceFormat::Text, TraceFilter::default());
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[0], "      0 0200 6a02 LD VA, 0x02          VA=02");
+        assert_eq!(lines[1], "      0 0202 a20a LD I, 0x20a          I=20a");
+        assert_eq!(lines[2], "      0 0204 fa15 LD DT, VA            DT=02");
+        assert_eq!(lines[3], "      1 0206 1206 JP 0x206");
+    }
+
+    #[test]
+    fn filters() {
+        let filter = TraceFilter { pc: Some(0x202..=0x206), kinds: vec!["ld".to_owned()], frames: None };
+        let log = trace(TraceFormat::Json, filter);
+        let lines: Vec<serde_json::Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["pc"], 0x202);
+        assert_eq!(lines[1]["mnemonic"], "LD DT, VA");
+        assert_eq!(lines[1]["changes"]["DT"], 2);
+
+        let filter = TraceFilter { frames: Some(2..=2), ..TraceFilter::default() };
+        assert!(trace(TraceFormat::Text, filter).lines().all(|line| line.starts_with("      2 ")));
+        assert_eq!(parse_pc_range("200-2FF"), Ok(0x200..=0x2ff));
+        assert_eq!(parse_frame_range("60"), Ok(60..=60));
+        assert!(parse_pc_range("200-").is_err());
+    }
+}
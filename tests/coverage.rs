@@ -0,0 +1,36 @@
+use std::fs;
+use std::path::Path;
+
+use chip8::analysis::Analysis;
+use chip8::coverage::Coverage;
+use chip8::movie::Movie;
+
+const MOVIES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/movies");
+
+// Replays `<name>.movie` on `<name>.ch8` and records what it covered
+fn cover(name: &str) -> (Vec<u8>, Coverage) {
+    let dir = Path::new(MOVIES);
+    let rom = fs::read(dir.join(name).with_extension("ch8")).unwrap();
+    let movie = Movie::load(&dir.join(name).with_extension("movie")).unwrap();
+    let mut coverage = Coverage::new();
+    movie.play_observed(&rom, &mut coverage).unwrap();
+    (rom, coverage)
+}
+
+#[test]
+fn doodle_reaches_every_routine() {
+    let (rom, coverage) = cover("doodle");
+    let analysis = Analysis::analyze(&rom);
+    assert!(!analysis.subroutines().is_empty());
+    for &addr in analysis.subroutines() {
+        assert!(coverage.executed(addr), "Subroutine {:03x} was never called", addr);
+    }
+    // The dot sprite is drawn, and the rom never writes to itself
+    assert!(coverage.read(0x240));
+    assert!((0x200..0x241).all(|addr| !coverage.written(addr)));
+    // Every direction and the stamp were used
+    let listing = coverage.listing(&rom);
+    for line in ["X-- 218: 71ff", "X-- 21e: 7101", "X-- 224: 70ff", "X-- 22a: 7001", "X-- 230: 2238"] {
+        assert!(listing.contains(line), "{} missing from\n{}", line, listing);
+    }
+}
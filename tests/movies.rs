@@ -0,0 +1,34 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use chip8::movie::Movie;
+
+const MOVIES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/movies");
+
+// Replays `<name>.movie` on `<name>.ch8` and compares the final screen with
+// `<name>.golden`. UPDATE_GOLDEN=1 records the end state again instead.
+fn check(name: &str) {
+    let dir = Path::new(MOVIES);
+    let rom = fs::read(dir.join(name).with_extension("ch8")).unwrap();
+    let movie_path = dir.join(name).with_extension("movie");
+    let golden_path = dir.join(name).with_extension("golden");
+    let mut movie = Movie::load(&movie_path).unwrap();
+
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        movie.hash = None;
+        let chip8 = movie.play(&rom).unwrap();
+        movie.finish(&chip8);
+        movie.save(&movie_path).unwrap();
+        fs::write(&golden_path, chip8.screen().to_string()).unwrap();
+    } else {
+        let chip8 = movie.play(&rom).unwrap_or_else(|e| panic!("{}: {}", name, e));
+        let golden = fs::read_to_string(&golden_path).unwrap();
+        assert_eq!(chip8.screen().to_string(), golden, "{} does not match {}", name, golden_path.display());
+    }
+}
+
+#[test]
+fn doodle() {
+    check("doodle");
+}
@@ -0,0 +1,366 @@
+//! A second CHIP-8 interpreter, written as plainly as possible from the
+//! specification, that `emulate_op` is compared against on random machines
+//! running random instructions.
+
+use proptest::prelude::*;
+
+use chip8::emulator::{Action, Chip8, CpuError, FONT_SET, HEIGHT, WIDTH};
+use chip8::quirks::{LoadStore, Quirks};
+
+// 4KB, from 0x000 to 0xfff
+const MEMORY: usize = 0x1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Next,
+    Draw,
+    WaitForKey,
+}
+
+#[derive(Debug, Clone)]
+struct Machine {
+    v: [u8; 16],
+    i: u16,
+    pc: usize,
+    // Return addresses, the outermost first
+    stack: Vec<u16>,
+    memory: Vec<u8>,
+    screen: [[bool; WIDTH]; HEIGHT],
+    delay_timer: u8,
+    sound_timer: u8,
+    keys: u16,
+    waiting_key: Option<u8>,
+    quirks: Quirks,
+    rng: u64,
+}
+
+impl Machine {
+    fn pressed(&self, key: u8) -> bool {
+        self.keys & (1 << (key & 0xf)) != 0
+    }
+
+    // SplitMix64, which `seed_rng` promises
+    fn random(&mut self) -> u8 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        (z ^ (z >> 31)) as u8
+    }
+
+    // Every address from `start` on for `len` bytes must be in memory
+    fn check(&self, pc: usize, start: usize, len: usize) -> Result<(), CpuError> {
+        for addr in start..start + len {
+            if addr >= MEMORY {
+                return Err(CpuError::Memory { pc: pc as u16, addr });
+            }
+        }
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<Outcome, CpuError> {
+        let pc = self.pc;
+        self.check(pc, pc, 2)?;
+        let op = (self.memory[pc] as u16) << 8 | self.memory[pc + 1] as u16;
+        self.pc += 2;
+
+        let x = ((op >> 8) & 0xf) as usize;
+        let y = ((op >> 4) & 0xf) as usize;
+        let n = (op & 0xf) as usize;
+        let kk = (op & 0xff) as u8;
+        let nnn = op & 0xfff;
+        let unknown = CpuError::UnknownOpcode { pc: pc as u16, opcode: op };
+        match op >> 12 {
+            0x0 if op == 0x00e0 => {
+                self.screen = [[false; WIDTH]; HEIGHT];
+                return Ok(Outcome::Draw);
+            }
+            0x0 if op == 0x00ee => {
+                self.pc = self.stack.pop().ok_or(CpuError::StackUnderflow { pc: pc as u16 })? as usize;
+            }
+            // SYS jumps like JP
+            0x0 | 0x1 => self.pc = nnn as usize,
+            0x2 => {
+                let depth = self.quirks.stack_depth;
+                if self.stack.len() == depth as usize {
+                    return Err(CpuError::StackOverflow { pc: pc as u16, depth });
+                }
+                self.stack.push(self.pc as u16);
+                self.pc = nnn as usize;
+            }
+            0x3 => {
+                if self.v[x] == kk {
+                    self.pc += 2;
+                }
+            }
+            0x4 => {
+                if self.v[x] != kk {
+                    self.pc += 2;
+                }
+            }
+            0x5 if n == 0 => {
+                if self.v[x] == self.v[y] {
+                    self.pc += 2;
+                }
+            }
+            0x6 => self.v[x] = kk,
+            0x7 => self.v[x] = self.v[x].wrapping_add(kk),
+            0x8 => {
+                let (vx, vy) = (self.v[x], self.v[y]);
+                // The value shifted, VY unless the quirk shifts VX in place
+                let shifted = if self.quirks.shift { vx } else { vy };
+                let (result, flag) = match n {
+                    0x0 => (vy, None),
+                    0x1 => (vx | vy, if self.quirks.logic { Some(0) } else { None }),
+                    0x2 => (vx & vy, if self.quirks.logic { Some(0) } else { None }),
+                    0x3 => (vx ^ vy, if self.quirks.logic { Some(0) } else { None }),
+                    0x4 => ((vx as u16 + vy as u16) as u8, Some((vx as u16 + vy as u16 > 0xff) as u8)),
+                    0x5 => (vx.wrapping_sub(vy), Some((vx >= vy) as u8)),
+                    0x6 => (shifted >> 1, Some(shifted & 1)),
+                    0x7 => (vy.wrapping_sub(vx), Some((vy >= vx) as u8)),
+                    0xe => (shifted << 1, Some(shifted >> 7)),
+                    _ => return Err(unknown),
+                };
+                self.v[x] = result;
+                if let Some(flag) = flag {
+                    self.v[0xf] = flag;
+                }
+            }
+            0x9 if n == 0 => {
+                if self.v[x] != self.v[y] {
+                    self.pc += 2;
+                }
+            }
+            0xa => self.i = nnn,
+            0xb => {
+                let offset = if self.quirks.jump { self.v[x] } else { self.v[0] };
+                self.pc = nnn as usize + offset as usize;
+            }
+            0xc => self.v[x] = self.random() & kk,
+            0xd => {
+                self.check(pc, self.i as usize, n)?;
+                let (left, top) = (self.v[x] as usize % WIDTH, self.v[y] as usize % HEIGHT);
+                let mut collision = false;
+                for row in 0..n {
+                    let byte = self.memory[self.i as usize + row];
+                    for column in 0..8 {
+                        if byte & (0x80 >> column) == 0 {
+                            continue;
+                        }
+                        let (mut px, mut py) = (left + column, top + row);
+                        if self.quirks.wrap {
+                            px %= WIDTH;
+                            py %= HEIGHT;
+                        } else if px >= WIDTH || py >= HEIGHT {
+                            continue;
+                        }
+                        collision |= self.screen[py][px];
+                        self.screen[py][px] = !self.screen[py][px];
+                    }
+                }
+                self.v[0xf] = collision as u8;
+                return Ok(Outcome::Draw);
+            }
+            0xe if kk == 0x9e => {
+                if self.pressed(self.v[x]) {
+                    self.pc += 2;
+                }
+            }
+            0xe if kk == 0xa1 => {
+                if !self.pressed(self.v[x]) {
+                    self.pc += 2;
+                }
+            }
+            0xf => match kk {
+                0x07 => self.v[x] = self.delay_timer,
+                // The first key held down is taken once it is released
+                0x0a => match self.waiting_key {
+                    Some(key) if !self.pressed(key) => {
+                        self.v[x] = key;
+                        self.waiting_key = None;
+                    }
+                    _ => {
+                        if self.waiting_key.is_none() {
+                            self.waiting_key = (0..16).find(|&key| self.pressed(key));
+                        }
+                        self.pc = pc;
+                        return Ok(Outcome::WaitForKey);
+                    }
+                },
+                0x15 => self.delay_timer = self.v[x],
+                0x18 => self.sound_timer = self.v[x],
+                0x1e => self.i = self.i.wrapping_add(self.v[x] as u16),
+                0x29 => self.i = (self.v[x] & 0xf) as u16 * 5,
+                0x33 => {
+                    self.check(pc, self.i as usize, 3)?;
+                    let i = self.i as usize;
+                    self.memory[i] = self.v[x] / 100;
+                    self.memory[i + 1] = self.v[x] / 10 % 10;
+                    self.memory[i + 2] = self.v[x] % 10;
+                }
+                0x55 | 0x65 => {
+                    self.check(pc, self.i as usize, x + 1)?;
+                    for r in 0..=x {
+                        let addr = self.i as usize + r;
+                        if kk == 0x55 {
+                            self.memory[addr] = self.v[r];
+                        } else {
+                            self.v[r] = self.memory[addr];
+                        }
+                    }
+                    self.i = match self.quirks.load_store {
+                        LoadStore::LeaveI => self.i,
+                        LoadStore::Increment => self.i.wrapping_add(x as u16 + 1),
+                        LoadStore::IncrementByX => self.i.wrapping_add(x as u16),
+                    };
+                }
+                _ => return Err(unknown),
+            },
+            _ => return Err(unknown),
+        }
+        Ok(Outcome::Next)
+    }
+}
+
+// Fixed bits and random bits of each opcode family
+const FAMILIES: [(u16, u16); 35] = [
+    (0x00e0, 0x0000), (0x00ee, 0x0000), (0x0000, 0x0fff), (0x1000, 0x0fff), (0x2000, 0x0fff),
+    (0x3000, 0x0fff), (0x4000, 0x0fff), (0x5000, 0x0ff0), (0x6000, 0x0fff), (0x7000, 0x0fff),
+    (0x8000, 0x0ff0), (0x8001, 0x0ff0), (0x8002, 0x0ff0), (0x8003, 0x0ff0), (0x8004, 0x0ff0),
+    (0x8005, 0x0ff0), (0x8006, 0x0ff0), (0x8007, 0x0ff0), (0x800e, 0x0ff0), (0x9000, 0x0ff0),
+    (0xa000, 0x0fff), (0xb000, 0x0fff), (0xc000, 0x0fff), (0xd000, 0x0fff), (0xe09e, 0x0f00),
+    (0xe0a1, 0x0f00), (0xf007, 0x0f00), (0xf00a, 0x0f00), (0xf015, 0x0f00), (0xf018, 0x0f00),
+    (0xf01e, 0x0f00), (0xf029, 0x0f00), (0xf033, 0x0f00), (0xf055, 0x0f00), (0xf065, 0x0f00),
+];
+
+fn opcode() -> impl Strategy<Value = u16> {
+    prop_oneof![
+        4 => (0..FAMILIES.len(), any::<u16>()).prop_map(|(family, bits)| {
+            let (fixed, random) = FAMILIES[family];
+            fixed | (bits & random)
+        }),
+        // Anything, mostly opcodes no family has
+        1 => any::<u16>(),
+    ]
+}
+
+fn quirks() -> impl Strategy<Value = Quirks> {
+    (any::<[bool; 5]>(), 0..3u8, 1..=16u8).prop_map(|([shift, wrap, jump, display_wait, logic], load_store, stack_depth)| Quirks {
+        shift,
+        load_store: match load_store {
+            0 => LoadStore::LeaveI,
+            1 => LoadStore::Increment,
+            _ => LoadStore::IncrementByX,
+        },
+        wrap,
+        jump,
+        display_wait,
+        logic,
+        stack_depth,
+    })
+}
+
+fn machine() -> impl Strategy<Value = Machine> {
+    (
+        any::<[u8; 16]>(),
+        // I mostly in memory, sometimes anywhere
+        prop_oneof![3 => 0..MEMORY as u16, 1 => any::<u16>()],
+        0..MEMORY,
+        prop::collection::vec(0..MEMORY as u16, 0..=16),
+        any::<u64>(),
+        any::<(u8, u8)>(),
+        quirks(),
+        any::<u64>(),
+    )
+        .prop_map(|(v, i, pc, mut stack, fill, (delay_timer, sound_timer), quirks, rng)| {
+            stack.truncate(quirks.stack_depth.into());
+            let mut filler = Machine { rng: fill, ..blank() };
+            let mut bytes: Vec<u8> = (0..MEMORY).map(|_| filler.random()).collect();
+            bytes[..FONT_SET.len()].copy_from_slice(&FONT_SET);
+            Machine { v, i, pc, stack, memory: bytes, delay_timer, sound_timer, quirks, rng, ..blank() }
+        })
+}
+
+fn blank() -> Machine {
+    Machine {
+        v: [0; 16],
+        i: 0,
+        pc: 0x200,
+        stack: Vec::new(),
+        memory: vec![0; MEMORY],
+        screen: [[false; WIDTH]; HEIGHT],
+        delay_timer: 0,
+        sound_timer: 0,
+        keys: 0,
+        waiting_key: None,
+        quirks: Quirks::default(),
+        rng: 0,
+    }
+}
+
+fn emulator(machine: &Machine) -> Chip8 {
+    Chip8::builder()
+        .memory(0, &machine.memory)
+        .registers(machine.v)
+        .index(machine.i)
+        .pc(machine.pc as u16)
+        .stack(&machine.stack)
+        .delay_timer(machine.delay_timer)
+        .sound_timer(machine.sound_timer)
+        .keys(machine.keys)
+        .quirks(machine.quirks)
+        .seed(machine.rng)
+        .build()
+}
+
+fn assert_same(chip8: &Chip8, machine: &Machine, step: usize) -> Result<(), TestCaseError> {
+    prop_assert_eq!(chip8.registers(), &machine.v, "registers after step {}", step);
+    prop_assert_eq!(chip8.index(), machine.i, "I after step {}", step);
+    prop_assert_eq!(chip8.pc(), machine.pc, "pc after step {}", step);
+    prop_assert_eq!(chip8.stack(), &machine.stack[..], "stack after step {}", step);
+    prop_assert_eq!((chip8.delay_timer(), chip8.sound_timer()), (machine.delay_timer, machine.sound_timer));
+    prop_assert!(chip8.memory() == &machine.memory[..], "memory after step {}", step);
+    for (y, row) in machine.screen.iter().enumerate() {
+        for (x, &lit) in row.iter().enumerate() {
+            prop_assert_eq!(chip8.screen().pixel(x, y), lit, "pixel ({}, {}) after step {}", x, y, step);
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(2048))]
+
+    // Each instruction is written at pc before it runs, so they follow each
+    // other wherever jumps, calls and skips go
+    #[test]
+    fn matches_reference(
+        start in machine(),
+        program in prop::collection::vec((opcode(), any::<u16>()), 1..12),
+    ) {
+        let mut machine = start;
+        let mut chip8 = emulator(&machine);
+        for (step, &(op, keys)) in program.iter().enumerate() {
+            if machine.pc + 1 < MEMORY {
+                for (addr, byte) in [(machine.pc, (op >> 8) as u8), (machine.pc + 1, op as u8)] {
+                    machine.memory[addr] = byte;
+                    chip8.write_memory(addr as u16, byte);
+                }
+            }
+            machine.keys = keys;
+            chip8.set_keys(keys);
+
+            let expected = machine.step();
+            let actual = chip8.emulate_op().map(|action| match action {
+                None => Outcome::Next,
+                Some(Action::DisplayScreen(_)) => Outcome::Draw,
+                Some(Action::WaitForKeyPress) => Outcome::WaitForKey,
+            });
+            prop_assert_eq!(&actual, &expected, "{:04x} at step {}", op, step);
+            assert_same(&chip8, &machine, step)?;
+            if expected.is_err() {
+                break;
+            }
+        }
+    }
+}
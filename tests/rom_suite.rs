@@ -0,0 +1,91 @@
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use chip8::emulator::{Chip8, INSTRUCTIONS_PER_FRAME};
+
+const ROMS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/roms");
+
+fn dump(chip8: &Chip8, frames: usize) -> String {
+    let mut out = String::new();
+    writeln!(out, "frames: {}", frames).unwrap();
+    writeln!(out, "pc: {:#05x}", chip8.pc()).unwrap();
+    writeln!(out, "i: {:#05x}", chip8.index()).unwrap();
+    let v: Vec<String> = chip8.registers().iter().map(|r| format!("{:02x}", r)).collect();
+    writeln!(out, "v: {}", v.join(" ")).unwrap();
+    writeln!(out, "dt: {}", chip8.delay_timer()).unwrap();
+    writeln!(out, "st: {}", chip8.sound_timer()).unwrap();
+    writeln!(out, "screen:").unwrap();
+    write!(out, "{}", chip8.screen()).unwrap();
+    out
+}
+
+fn frames(golden: &str) -> usize {
+    golden
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("frames: "))
+        .and_then(|frames| frames.parse().ok())
+        .expect("Golden file must start with \"frames: <n>\"")
+}
+
+fn check(name: &str) {
+    let dir = Path::new(ROMS);
+    let rom = fs::read(dir.join(name).with_extension("ch8")).unwrap();
+    let golden_path = dir.join(name).with_extension("golden");
+    let golden = fs::read_to_string(&golden_path).unwrap();
+    let frames = frames(&golden);
+
+    let mut chip8 = Chip8::from_rom(&rom);
+    for _ in 0..frames {
+        chip8.run_frame(INSTRUCTIONS_PER_FRAME);
+    }
+    let actual = dump(&chip8, frames);
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::write(&golden_path, &actual).unwrap();
+    } else {
+        assert_eq!(actual, golden, "{} does not match {}", name, golden_path.display());
+    }
+}
+
+#[test]
+fn arith() {
+    check("arith");
+}
+
+#[test]
+fn subroutines() {
+    check("subroutines");
+}
+
+#[test]
+fn timers() {
+    check("timers");
+}
+
+#[test]
+fn draw() {
+    check("draw");
+}
+
+#[test]
+fn collision() {
+    check("collision");
+}
+
+#[test]
+fn every_rom_has_a_test() {
+    let mut roms: Vec<String> = fs::read_dir(ROMS)
+        .unwrap()
+        .filter_map(|entry| {
+            let path = entry.unwrap().path();
+            match path.extension() {
+                Some(ext) if ext == "ch8" => Some(path.file_stem()?.to_str()?.to_owned()),
+                _ => None,
+            }
+        })
+        .collect();
+    roms.sort();
+    assert_eq!(roms, ["arith", "collision", "draw", "subroutines", "timers"]);
+}